@@ -0,0 +1,163 @@
+//! Compares `MemoryDB` with a database which clones its column families on each snapshot.
+
+#[macro_use]
+extern crate criterion;
+extern crate cryptocurrency_kit;
+
+use std::collections::btree_map::{BTreeMap, Range};
+use std::collections::{Bound::*, HashMap};
+use std::iter::Peekable;
+use std::sync::RwLock;
+
+use criterion::{BenchmarkId, Criterion, black_box};
+
+use cryptocurrency_kit::storage::Result;
+use cryptocurrency_kit::storage::db::{Change, Database, Iter, Iterator, Patch, Snapshot};
+use cryptocurrency_kit::storage::memorydb::MemoryDB;
+
+const NAME: &str = "bench";
+const SIZES: [u32; 3] = [1_000, 10_000, 100_000];
+const PATCH_SIZE: u32 = 100;
+
+type Families = HashMap<String, BTreeMap<Vec<u8>, Vec<u8>>>;
+
+/// Database whose snapshots are full copies of its content.
+#[derive(Default)]
+struct CloningDB {
+    map: RwLock<Families>,
+}
+
+struct CloningSnapshot {
+    map: Families,
+}
+
+struct CloningIter<'a> {
+    iter: Option<Peekable<Range<'a, Vec<u8>, Vec<u8>>>>,
+}
+
+impl Database for CloningDB {
+    fn snapshot(&self) -> Box<dyn Snapshot> {
+        Box::new(CloningSnapshot {
+            map: self.map.read().unwrap().clone(),
+        })
+    }
+
+    fn merge(&self, patch: Patch) -> Result<()> {
+        let mut map = self.map.write().unwrap();
+        for (name, changes) in patch {
+            let family = map.entry(name).or_default();
+            for (key, change) in changes {
+                match change {
+                    Change::Put(value) => {
+                        family.insert(key, value);
+                    }
+                    Change::Delete => {
+                        family.remove(&key);
+                    }
+                    Change::DeletePrefix => family.retain(|k, _| !k.starts_with(&key)),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn merge_sync(&self, patch: Patch) -> Result<()> {
+        self.merge(patch)
+    }
+}
+
+impl Snapshot for CloningSnapshot {
+    fn get(&self, name: &str, key: &[u8]) -> Option<Vec<u8>> {
+        self.map
+            .get(name)
+            .and_then(|family| family.get(key).cloned())
+    }
+
+    fn iter<'a>(&'a self, name: &str, from: &[u8]) -> Iter<'a> {
+        let iter = self.map.get(name).map(|family| {
+            family
+                .range::<[u8], _>((Included(from), Unbounded))
+                .peekable()
+        });
+        Box::new(CloningIter { iter })
+    }
+}
+
+impl<'a> Iterator for CloningIter<'a> {
+    fn next(&mut self) -> Option<(&[u8], &[u8])> {
+        self.iter
+            .as_mut()
+            .and_then(|iter| iter.next())
+            .map(|(k, v)| (k.as_slice(), v.as_slice()))
+    }
+
+    fn peek(&mut self) -> Option<(&[u8], &[u8])> {
+        self.iter
+            .as_mut()
+            .and_then(|iter| iter.peek())
+            .map(|&(k, v)| (k.as_slice(), v.as_slice()))
+    }
+}
+
+fn fill<T: Database>(db: &T, size: u32) {
+    let mut fork = db.fork();
+    for i in 0..size {
+        fork.put(NAME, i.to_be_bytes().to_vec(), vec![0; 64]);
+    }
+    db.merge(fork.into_patch()).unwrap();
+}
+
+fn patch<T: Database>(db: &T, round: u32) -> Patch {
+    let mut fork = db.fork();
+    for i in 0..PATCH_SIZE {
+        let key = (i * 7 + round).to_be_bytes().to_vec();
+        fork.put(NAME, key, round.to_be_bytes().to_vec());
+    }
+    fork.into_patch()
+}
+
+fn bench_snapshot(c: &mut Criterion) {
+    let mut group = c.benchmark_group("snapshot");
+    for &size in SIZES.iter() {
+        let memory = MemoryDB::new();
+        fill(&memory, size);
+        group.bench_with_input(BenchmarkId::new("memorydb", size), &size, |b, _| {
+            b.iter(|| black_box(memory.snapshot()))
+        });
+        let cloning = CloningDB::default();
+        fill(&cloning, size);
+        group.bench_with_input(BenchmarkId::new("cloning", size), &size, |b, _| {
+            b.iter(|| black_box(cloning.snapshot()))
+        });
+    }
+    group.finish();
+}
+
+// Each iteration takes a snapshot to build the patch and merges it, as a block would.
+fn bench_merge(c: &mut Criterion) {
+    let mut group = c.benchmark_group("fork_and_merge");
+    for &size in SIZES.iter() {
+        let memory = MemoryDB::new();
+        fill(&memory, size);
+        let mut round = 0;
+        group.bench_with_input(BenchmarkId::new("memorydb", size), &size, |b, _| {
+            b.iter(|| {
+                round += 1;
+                memory.merge(patch(&memory, round)).unwrap()
+            })
+        });
+        let cloning = CloningDB::default();
+        fill(&cloning, size);
+        let mut round = 0;
+        group.bench_with_input(BenchmarkId::new("cloning", size), &size, |b, _| {
+            b.iter(|| {
+                round += 1;
+                cloning.merge(patch(&cloning, round)).unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_snapshot, bench_merge);
+criterion_main!(benches);
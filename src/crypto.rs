@@ -125,7 +125,7 @@ pub fn hash<T: AsRef<[u8]>>(data: T) -> Hash {
     Hash::new(digest.as_ref())
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct HashStream(Sha3_256);
 
 impl HashStream {
@@ -37,6 +37,15 @@ extern crate rust_decimal;
 extern crate rustc_hex;
 extern crate tiny_keccak;
 extern crate core;
+extern crate flate2;
+extern crate lru;
+extern crate im;
+extern crate arc_swap;
+extern crate crc32fast;
+#[cfg(feature = "rocksdb")]
+extern crate rocksdb;
+#[cfg(feature = "sqlite")]
+extern crate rusqlite;
 
 #[cfg(test)]
 mod tests {
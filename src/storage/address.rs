@@ -0,0 +1,252 @@
+//! Hierarchical addressing of indexes within column families.
+//!
+//! An [`IndexAddress`] consists of a column family name and an optional byte prefix. Names
+//! are dot-separated paths (e.g. `wallet.accounts`), so modules can namespace their column
+//! families by deriving child names from a common root. The prefix allows keeping a whole
+//! family of indexes (e.g. one map per `Address`) in a single column family; all keys of
+//! an addressed index are stored as `prefix ++ key`.
+//!
+//! Prefixes of indexes sharing a column family should have the same length, otherwise keys
+//! of different indexes may collide.
+//!
+//! [`IndexAddress`]: struct.IndexAddress.html
+
+use std::fmt;
+
+use super::db::{Fork, Iter, Iterator, Snapshot};
+use super::keys::StorageKey;
+use super::typed::key_bytes;
+use super::{Error, Result};
+
+/// Separator between components of a hierarchical name.
+pub const NAME_SEPARATOR: char = '.';
+
+/// Returns `true` if the name consists of non-empty components of ASCII alphanumeric
+/// characters, `_` and `-`, separated by dots.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.split(NAME_SEPARATOR).all(|component| {
+            !component.is_empty()
+                && component
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        })
+}
+
+/// Address of an index: a column family name and an optional key prefix.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct IndexAddress {
+    name: String,
+    bytes: Option<Vec<u8>>,
+}
+
+struct PrefixIter<'a> {
+    inner: Iter<'a>,
+    prefix: Vec<u8>,
+}
+
+impl IndexAddress {
+    /// Creates an address of the whole column family with the given name.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `InvalidName` error if the name does not follow the naming rules.
+    pub fn new<S: Into<String>>(name: S) -> Result<Self> {
+        let name = name.into();
+        if !is_valid_name(&name) {
+            return Err(Error::InvalidName { name });
+        }
+        Ok(Self { name, bytes: None })
+    }
+
+    /// Creates an address of the whole column family with the given name.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the name does not follow the naming rules.
+    pub fn with_root<S: Into<String>>(name: S) -> Self {
+        Self::new(name).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Returns the column family name.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the key prefix, if any.
+    pub fn bytes(&self) -> Option<&[u8]> {
+        self.bytes.as_deref()
+    }
+
+    /// Returns the address of a child column family, named `name.suffix`.
+    ///
+    /// The prefix of this address, if any, is kept.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `InvalidName` error if the resulting name does not follow the naming rules.
+    pub fn append_name(&self, suffix: &str) -> Result<Self> {
+        let name = format!("{}{}{}", self.name, NAME_SEPARATOR, suffix);
+        if !is_valid_name(&name) {
+            return Err(Error::InvalidName { name });
+        }
+        Ok(Self {
+            name,
+            bytes: self.bytes.clone(),
+        })
+    }
+
+    /// Returns the address of a child index within the same column family, whose prefix is
+    /// the prefix of this address followed by the serialized `key`.
+    pub fn append_bytes<K: StorageKey + ?Sized>(&self, key: &K) -> Self {
+        let mut bytes = self.bytes.clone().unwrap_or_default();
+        bytes.extend_from_slice(&key_bytes(key));
+        Self {
+            name: self.name.clone(),
+            bytes: Some(bytes),
+        }
+    }
+
+    /// Returns the full key in the column family for the given key of the index.
+    pub fn keyed(&self, key: &[u8]) -> Vec<u8> {
+        match self.bytes {
+            Some(ref prefix) => {
+                let mut full = Vec::with_capacity(prefix.len() + key.len());
+                full.extend_from_slice(prefix);
+                full.extend_from_slice(key);
+                full
+            }
+            None => key.to_vec(),
+        }
+    }
+
+    /// Returns a value of the index corresponding to the key.
+    pub fn get(&self, view: &dyn Snapshot, key: &[u8]) -> Option<Vec<u8>> {
+        view.get(&self.name, &self.keyed(key))
+    }
+
+    /// Returns `true` if the index contains a value for the key.
+    pub fn contains(&self, view: &dyn Snapshot, key: &[u8]) -> bool {
+        view.contains(&self.name, &self.keyed(key))
+    }
+
+    /// Returns an iterator over the entries of the index in ascending order starting from
+    /// the specified key.
+    ///
+    /// The iterator does not leave the prefix of the address; its keys are returned without
+    /// the prefix.
+    pub fn iter<'a>(&self, view: &'a dyn Snapshot, from: &[u8]) -> Iter<'a> {
+        let inner = view.iter(&self.name, &self.keyed(from));
+        match self.bytes {
+            Some(ref prefix) => Box::new(PrefixIter {
+                inner,
+                prefix: prefix.clone(),
+            }),
+            None => inner,
+        }
+    }
+
+    /// Inserts a key-value pair into the index.
+    pub fn put(&self, fork: &mut Fork, key: &[u8], value: Vec<u8>) {
+        fork.put(&self.name, self.keyed(key), value);
+    }
+
+    /// Removes the key from the index.
+    pub fn remove(&self, fork: &mut Fork, key: &[u8]) {
+        fork.remove(&self.name, self.keyed(key));
+    }
+
+    /// Removes all entries of the index.
+    pub fn clear(&self, fork: &mut Fork) {
+        fork.remove_by_prefix(&self.name, self.bytes.as_ref());
+    }
+}
+
+impl fmt::Display for IndexAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.bytes {
+            Some(ref bytes) => write!(f, "{}[0x{}]", self.name, crate::common::to_hex(bytes)),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+impl<'a> Iterator for PrefixIter<'a> {
+    fn next(&mut self) -> Option<(&[u8], &[u8])> {
+        let len = self.prefix.len();
+        match self.inner.next() {
+            Some((k, v)) if k.starts_with(&self.prefix) => Some((&k[len..], v)),
+            _ => None,
+        }
+    }
+
+    fn peek(&mut self) -> Option<(&[u8], &[u8])> {
+        let len = self.prefix.len();
+        match self.inner.peek() {
+            Some((k, v)) if k.starts_with(&self.prefix) => Some((&k[len..], v)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::db::Database;
+    use crate::storage::test_utils::TestDB;
+
+    #[test]
+    fn naming_rules() {
+        assert!(is_valid_name("accounts"));
+        assert!(is_valid_name("wallet.accounts_v2"));
+        assert!(is_valid_name("a-b.c"));
+        assert!(!is_valid_name(""));
+        assert!(!is_valid_name(".accounts"));
+        assert!(!is_valid_name("wallet..accounts"));
+        assert!(!is_valid_name("wallet/accounts"));
+        assert!(!is_valid_name("счета"));
+
+        let root = IndexAddress::with_root("wallet");
+        assert_eq!(
+            root.append_name("accounts").unwrap().name(),
+            "wallet.accounts"
+        );
+        match root.append_name("bad name") {
+            Err(Error::InvalidName { ref name }) => assert_eq!(name, "wallet.bad name"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(IndexAddress::new("").is_err());
+    }
+
+    #[test]
+    fn prefixed_indexes() {
+        let db = TestDB::new();
+        let balances = IndexAddress::with_root("balances");
+        let alice = balances.append_bytes(&1_u16);
+        let bob = balances.append_bytes(&2_u16);
+        assert_eq!(alice.bytes(), Some(&[0_u8, 1][..]));
+        assert_eq!(alice.to_string(), "balances[0x0001]");
+
+        let mut fork = db.fork();
+        alice.put(&mut fork, &[1], vec![10]);
+        alice.put(&mut fork, &[2], vec![20]);
+        bob.put(&mut fork, &[1], vec![30]);
+        db.merge(fork.into_patch()).unwrap();
+
+        let snapshot = db.snapshot();
+        assert_eq!(alice.get(&*snapshot, &[1]), Some(vec![10]));
+        assert_eq!(bob.get(&*snapshot, &[1]), Some(vec![30]));
+        assert!(!bob.contains(&*snapshot, &[2]));
+
+        let mut iter = alice.iter(&*snapshot, &[]);
+        assert_eq!(iter.peek(), Some((&[1_u8][..], &[10_u8][..])));
+        assert_eq!(iter.next(), Some((&[1_u8][..], &[10_u8][..])));
+        assert_eq!(iter.next(), Some((&[2_u8][..], &[20_u8][..])));
+        assert_eq!(iter.next(), None);
+
+        let mut fork = db.fork();
+        alice.clear(&mut fork);
+        assert_eq!(alice.iter(&fork, &[]).next(), None);
+        assert_eq!(bob.get(&fork, &[1]), Some(vec![30]));
+    }
+}
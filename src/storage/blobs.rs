@@ -0,0 +1,221 @@
+//! Content-addressed storage of deduplicated blobs.
+//!
+//! A [`BlobStore`] keeps each distinct blob once under its `crypto::hash`, together with
+//! the number of references to it. Putting a blob which is already stored only increments
+//! its reference count, and releasing a blob decrements it. A blob whose count drops to
+//! zero is not removed right away: it is marked as garbage and stays readable until a
+//! [`collect_garbage`] pass removes it, unless it is referenced again before that.
+//!
+//! All the records of a store live in a single column family, distinguished by a one-byte
+//! tag before the hash: blobs, reference counts (big-endian `u64`) and garbage marks. A
+//! garbage collection pass thus touches only the blobs which are actually unreferenced.
+//!
+//! [`BlobStore`]: struct.BlobStore.html
+//! [`collect_garbage`]: struct.BlobStore.html#method.collect_garbage
+
+use byteorder::{BigEndian, ByteOrder};
+
+use crate::crypto::{self, HASH_SIZE, Hash};
+
+use super::db::{Fork, Snapshot};
+use super::metadata::{IndexKind, IndexMetadata, ensure_metadata};
+use super::{Error, Result};
+
+pub(crate) const BLOB_TAG: u8 = 0;
+pub(crate) const REFS_TAG: u8 = 1;
+pub(crate) const GARBAGE_TAG: u8 = 2;
+
+/// Store of blobs addressed by their hashes, kept in the column family with the given name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlobStore {
+    name: String,
+}
+
+fn record_key(tag: u8, hash: &Hash) -> Vec<u8> {
+    let mut key = Vec::with_capacity(1 + HASH_SIZE);
+    key.push(tag);
+    key.extend_from_slice(hash.as_ref());
+    key
+}
+
+impl BlobStore {
+    /// Creates a store kept in the column family with the given `name`.
+    pub fn new<S: Into<String>>(name: S) -> Self {
+        Self { name: name.into() }
+    }
+
+    /// Returns the name of the column family of the store.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the blob with the given hash, or `None` if it is not stored.
+    pub fn get(&self, view: &dyn Snapshot, hash: &Hash) -> Option<Vec<u8>> {
+        view.get(&self.name, &record_key(BLOB_TAG, hash))
+    }
+
+    /// Returns `true` if the blob with the given hash is stored.
+    pub fn contains(&self, view: &dyn Snapshot, hash: &Hash) -> bool {
+        view.contains(&self.name, &record_key(BLOB_TAG, hash))
+    }
+
+    /// Returns the number of references to the blob with the given hash.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `Corrupted` error if the stored count is malformed.
+    pub fn refcount(&self, view: &dyn Snapshot, hash: &Hash) -> Result<u64> {
+        let key = record_key(REFS_TAG, hash);
+        match view.get(&self.name, &key) {
+            Some(ref value) if value.len() == 8 => Ok(BigEndian::read_u64(value)),
+            Some(_) => Err(Error::Corrupted {
+                name: self.name.clone(),
+                key,
+                reason: "malformed reference count".to_string(),
+            }),
+            None => Ok(0),
+        }
+    }
+
+    fn set_refcount(&self, fork: &mut Fork, hash: &Hash, count: u64) {
+        if count == 0 {
+            fork.remove(&self.name, record_key(REFS_TAG, hash));
+            fork.put(&self.name, record_key(GARBAGE_TAG, hash), Vec::new());
+        } else {
+            let mut value = vec![0; 8];
+            BigEndian::write_u64(&mut value, count);
+            fork.put(&self.name, record_key(REFS_TAG, hash), value);
+        }
+    }
+
+    /// Stores the blob, or adds a reference to it if it is already stored, and returns
+    /// its hash.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `IndexTypeMismatch` error if the column family holds another kind of
+    /// index, or a `Corrupted` error if the stored count is malformed.
+    pub fn put(&self, fork: &mut Fork, blob: &[u8]) -> Result<Hash> {
+        ensure_metadata(
+            fork,
+            &self.name,
+            &IndexMetadata::new::<Hash, Vec<u8>>(IndexKind::Blobs),
+        )?;
+        let hash = crypto::hash(blob);
+        let count = self.refcount(fork, &hash)?;
+        if count == 0 {
+            fork.remove(&self.name, record_key(GARBAGE_TAG, &hash));
+            if !self.contains(fork, &hash) {
+                fork.put(&self.name, record_key(BLOB_TAG, &hash), blob.to_vec());
+            }
+        }
+        self.set_refcount(fork, &hash, count + 1);
+        Ok(hash)
+    }
+
+    /// Removes a reference to the blob with the given hash and returns the number of
+    /// remaining references. A blob without references is removed by the next garbage
+    /// collection pass.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `NotFound` error if the blob is not referenced, or a `Corrupted` error
+    /// if the stored count is malformed.
+    pub fn release(&self, fork: &mut Fork, hash: &Hash) -> Result<u64> {
+        let count = self.refcount(fork, hash)?;
+        if count == 0 {
+            return Err(Error::NotFound {
+                name: self.name.clone(),
+                key: record_key(BLOB_TAG, hash),
+            });
+        }
+        self.set_refcount(fork, hash, count - 1);
+        Ok(count - 1)
+    }
+
+    /// Removes all unreferenced blobs and returns their number.
+    pub fn collect_garbage(&self, fork: &mut Fork) -> usize {
+        let mut garbage = Vec::new();
+        {
+            let mut iter = fork.iter(&self.name, &[GARBAGE_TAG]);
+            while let Some((key, _)) = iter.next() {
+                if key[0] != GARBAGE_TAG {
+                    break;
+                }
+                garbage.push(Hash::new(&key[1..]));
+            }
+        }
+        for hash in &garbage {
+            fork.remove(&self.name, record_key(BLOB_TAG, hash));
+        }
+        fork.remove_by_prefix(&self.name, Some(&vec![GARBAGE_TAG]));
+        garbage.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::db::Database;
+    use crate::storage::test_utils::TestDB;
+
+    #[test]
+    fn reference_counting() {
+        let db = TestDB::new();
+        let store = BlobStore::new("code");
+        let mut fork = db.fork();
+        let hash = store.put(&mut fork, b"contract").unwrap();
+        assert_eq!(hash, crypto::hash(b"contract"));
+        assert_eq!(store.put(&mut fork, b"contract").unwrap(), hash);
+        let other = store.put(&mut fork, b"other").unwrap();
+        assert_eq!(store.refcount(&fork, &hash).unwrap(), 2);
+        db.merge(fork.into_patch()).unwrap();
+
+        let mut fork = db.fork();
+        assert_eq!(store.get(&fork, &hash), Some(b"contract".to_vec()));
+        assert_eq!(store.release(&mut fork, &hash).unwrap(), 1);
+        assert_eq!(store.release(&mut fork, &other).unwrap(), 0);
+        assert!(store.release(&mut fork, &other).is_err());
+        // Unreferenced blobs are kept until collected.
+        assert!(store.contains(&fork, &other));
+        assert_eq!(store.collect_garbage(&mut fork), 1);
+        assert!(!store.contains(&fork, &other));
+        assert!(store.contains(&fork, &hash));
+        assert_eq!(store.collect_garbage(&mut fork), 0);
+        db.merge(fork.into_patch()).unwrap();
+
+        let snapshot = db.snapshot();
+        assert_eq!(store.refcount(&*snapshot, &hash).unwrap(), 1);
+        assert_eq!(store.refcount(&*snapshot, &other).unwrap(), 0);
+        assert_eq!(store.get(&*snapshot, &other), None);
+    }
+
+    #[test]
+    fn resurrected_blob_survives_collection() {
+        let db = TestDB::new();
+        let store = BlobStore::new("payloads");
+        let mut fork = db.fork();
+        let hash = store.put(&mut fork, b"payload").unwrap();
+        store.release(&mut fork, &hash).unwrap();
+        store.put(&mut fork, b"payload").unwrap();
+        assert_eq!(store.collect_garbage(&mut fork), 0);
+        assert_eq!(store.get(&fork, &hash), Some(b"payload".to_vec()));
+        assert_eq!(store.refcount(&fork, &hash).unwrap(), 1);
+    }
+
+    #[test]
+    fn kind_mismatch() {
+        let db = TestDB::new();
+        let mut fork = db.fork();
+        ensure_metadata(
+            &mut fork,
+            "code",
+            &IndexMetadata::new::<u64, u64>(IndexKind::List),
+        )
+        .unwrap();
+        match BlobStore::new("code").put(&mut fork, b"contract") {
+            Err(Error::IndexTypeMismatch { ref name, .. }) => assert_eq!(name, "code"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,435 @@
+//! A `Database` wrapper answering definite negatives of key lookups with Bloom filters.
+//!
+//! Each selected column family gets an in-memory Bloom filter over its keys. The filters
+//! are rebuilt from the underlying database when the wrapper is created and updated with
+//! the keys put by every merged patch, so `Snapshot::contains` and `Snapshot::get` for an
+//! absent key are usually answered without touching the underlying store.
+//!
+//! A Bloom filter cannot forget keys, so removed keys only make the filter less selective.
+//! When the number of keys added to a filter exceeds its capacity, the filter is rebuilt
+//! from the database with twice the capacity, dropping removed keys as well. Keys which
+//! the filter already may contain are not counted.
+//!
+//! Merges are serialized, but snapshots are never blocked by a merge or a rebuild: filters
+//! are updated out of the shared map, and snapshots taken in the meantime read the affected
+//! column families from the underlying database.
+//!
+//! Snapshots keep the filters they were taken with, so a filter rebuilt after a removal
+//! never hides a key from an older snapshot.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::f64::consts::LN_2;
+use std::hash::Hasher;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use super::Result;
+use super::db::{Change, Database, Iter, Patch, Snapshot};
+
+/// Options of the Bloom filters.
+#[derive(Debug, Clone)]
+pub struct BloomOptions {
+    /// Names of the column families to keep filters for.
+    pub names: HashSet<String>,
+    /// Target rate of false positives in `0.0..1.0`. Default value is `0.01`.
+    pub false_positive_rate: f64,
+    /// Minimal number of keys a filter is sized for. Default value is `1024`.
+    pub capacity: usize,
+}
+
+impl BloomOptions {
+    /// Creates options keeping filters for the given column families.
+    pub fn with_names<I, S>(names: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self {
+            names: names.into_iter().map(Into::into).collect(),
+            false_positive_rate: 0.01,
+            capacity: 1024,
+        }
+    }
+}
+
+/// Counters of lookups checked against the filters.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BloomStats {
+    /// Number of lookups checked against a filter.
+    pub checks: u64,
+    /// Number of lookups answered by a filter without reading the underlying database.
+    pub negatives: u64,
+    /// Number of lookups passed by a filter for keys which turned out to be absent.
+    pub false_positives: u64,
+}
+
+impl BloomStats {
+    /// Returns the share of lookups of absent keys which were not filtered out, or `0` if
+    /// there were no such lookups.
+    pub fn false_positive_ratio(&self) -> f64 {
+        let absent = self.negatives + self.false_positives;
+        if absent == 0 {
+            0.0
+        } else {
+            self.false_positives as f64 / absent as f64
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    checks: AtomicU64,
+    negatives: AtomicU64,
+    false_positives: AtomicU64,
+}
+
+#[derive(Debug, Clone)]
+struct BloomFilter {
+    bits: Vec<u64>,
+    hashes: u32,
+    capacity: usize,
+    len: usize,
+}
+
+impl BloomFilter {
+    fn new(capacity: usize, false_positive_rate: f64) -> Self {
+        let capacity = capacity.max(1);
+        let bits = (-(capacity as f64) * false_positive_rate.ln() / (LN_2 * LN_2)).ceil();
+        let words = (bits as usize).max(64).div_ceil(64);
+        let hashes = ((words * 64) as f64 / capacity as f64 * LN_2).round();
+        Self {
+            bits: vec![0; words],
+            hashes: (hashes as u32).max(1),
+            capacity,
+            len: 0,
+        }
+    }
+
+    // Positions of the key bits, derived from two hashes by double hashing.
+    fn positions(&self, key: &[u8]) -> impl Iterator<Item = usize> + use<> {
+        let hash = |seed: u8| {
+            let mut hasher = DefaultHasher::new();
+            hasher.write_u8(seed);
+            hasher.write(key);
+            hasher.finish()
+        };
+        let (first, second) = (hash(0), hash(1) | 1);
+        let size = (self.bits.len() * 64) as u64;
+        (0..u64::from(self.hashes))
+            .map(move |i| (first.wrapping_add(i.wrapping_mul(second)) % size) as usize)
+    }
+
+    fn insert(&mut self, key: &[u8]) {
+        let mut added = false;
+        for position in self.positions(key) {
+            let word = &mut self.bits[position / 64];
+            added |= *word & (1 << (position % 64)) == 0;
+            *word |= 1 << (position % 64);
+        }
+        if added {
+            self.len += 1;
+        }
+    }
+
+    fn may_contain(&self, key: &[u8]) -> bool {
+        self.positions(key)
+            .all(|position| self.bits[position / 64] & (1 << (position % 64)) != 0)
+    }
+}
+
+/// Database wrapper keeping Bloom filters over the keys of selected column families.
+pub struct BloomDB<T: Database> {
+    inner: T,
+    options: BloomOptions,
+    filters: Mutex<HashMap<String, Arc<BloomFilter>>>,
+    // Serializes merges, so that a rebuilt filter misses no keys of a concurrent merge.
+    merge_lock: Mutex<()>,
+    counters: Arc<Counters>,
+}
+
+struct BloomSnapshot {
+    snapshot: Box<dyn Snapshot>,
+    filters: HashMap<String, Arc<BloomFilter>>,
+    counters: Arc<Counters>,
+}
+
+impl<T: Database> BloomDB<T> {
+    /// Wraps the given database, building the filters from its current content.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the false positive rate is not in `0.0..1.0`.
+    pub fn new(inner: T, options: BloomOptions) -> Self {
+        assert!(
+            options.false_positive_rate > 0.0 && options.false_positive_rate < 1.0,
+            "false positive rate must be in 0.0..1.0"
+        );
+        let snapshot = inner.snapshot();
+        let filters = options
+            .names
+            .iter()
+            .map(|name| {
+                let filter = build_filter(&*snapshot, name, &options, options.capacity);
+                (name.clone(), Arc::new(filter))
+            })
+            .collect();
+        Self {
+            inner,
+            options,
+            filters: Mutex::new(filters),
+            merge_lock: Mutex::new(()),
+            counters: Arc::default(),
+        }
+    }
+
+    /// Returns a reference to the underlying database.
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    /// Returns the options of the filters.
+    pub fn options(&self) -> &BloomOptions {
+        &self.options
+    }
+
+    /// Returns the counters accumulated so far.
+    pub fn stats(&self) -> BloomStats {
+        BloomStats {
+            checks: self.counters.checks.load(Ordering::Relaxed),
+            negatives: self.counters.negatives.load(Ordering::Relaxed),
+            false_positives: self.counters.false_positives.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Resets the counters.
+    pub fn reset_stats(&self) {
+        self.counters.checks.store(0, Ordering::Relaxed);
+        self.counters.negatives.store(0, Ordering::Relaxed);
+        self.counters.false_positives.store(0, Ordering::Relaxed);
+    }
+
+    fn merge_with<F>(&self, patch: Patch, merge: F) -> Result<()>
+    where
+        F: FnOnce(&T, Patch) -> Result<()>,
+    {
+        let _merge = self.merge_lock.lock().unwrap();
+        // The filters are taken out of the map while being updated, so they are not copied
+        // unless held by snapshots.
+        let mut updated = {
+            let mut filters = self.filters.lock().unwrap();
+            patch
+                .iter()
+                .filter_map(|(name, _)| filters.remove_entry(name))
+                .collect::<Vec<_>>()
+        };
+        // Keys are added before merging, so no snapshot sees a key missing from its filter.
+        // Extra keys left by a failed merge only cause false positives.
+        let mut overflown = Vec::new();
+        for (name, filter) in &mut updated {
+            let filter = Arc::make_mut(filter);
+            for (key, change) in patch.changes(name).into_iter().flat_map(|c| c.iter()) {
+                if let Change::Put(..) = *change {
+                    filter.insert(key);
+                }
+            }
+            if filter.len > filter.capacity {
+                overflown.push((name.clone(), filter.capacity * 2));
+            }
+        }
+        self.filters.lock().unwrap().extend(updated);
+        merge(&self.inner, patch)?;
+
+        if !overflown.is_empty() {
+            let snapshot = self.inner.snapshot();
+            let rebuilt = overflown
+                .into_iter()
+                .map(|(name, capacity)| {
+                    let filter = build_filter(&*snapshot, &name, &self.options, capacity);
+                    (name, Arc::new(filter))
+                })
+                .collect::<Vec<_>>();
+            self.filters.lock().unwrap().extend(rebuilt);
+        }
+        Ok(())
+    }
+}
+
+fn build_filter(
+    snapshot: &dyn Snapshot,
+    name: &str,
+    options: &BloomOptions,
+    capacity: usize,
+) -> BloomFilter {
+    let mut keys = Vec::new();
+    let mut iter = snapshot.iter(name, &[]);
+    while let Some((key, _)) = iter.next() {
+        keys.push(key.to_vec());
+    }
+    let capacity = capacity.max(keys.len() * 2);
+    let mut filter = BloomFilter::new(capacity, options.false_positive_rate);
+    for key in &keys {
+        filter.insert(key);
+    }
+    filter
+}
+
+impl<T: Database> Database for BloomDB<T> {
+    fn snapshot(&self) -> Box<dyn Snapshot> {
+        let filters = self.filters.lock().unwrap();
+        Box::new(BloomSnapshot {
+            snapshot: self.inner.snapshot(),
+            filters: filters.clone(),
+            counters: Arc::clone(&self.counters),
+        })
+    }
+
+    fn merge(&self, patch: Patch) -> Result<()> {
+        self.merge_with(patch, |db, patch| db.merge(patch))
+    }
+
+    fn merge_sync(&self, patch: Patch) -> Result<()> {
+        self.merge_with(patch, |db, patch| db.merge_sync(patch))
+    }
+}
+
+impl BloomSnapshot {
+    // Returns `false` if the key is definitely absent, and `None` if there is no filter.
+    fn check(&self, name: &str, key: &[u8]) -> Option<bool> {
+        let filter = self.filters.get(name)?;
+        self.counters.checks.fetch_add(1, Ordering::Relaxed);
+        let passed = filter.may_contain(key);
+        if !passed {
+            self.counters.negatives.fetch_add(1, Ordering::Relaxed);
+        }
+        Some(passed)
+    }
+
+    fn record_absent(&self) {
+        self.counters
+            .false_positives
+            .fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Snapshot for BloomSnapshot {
+    fn get(&self, name: &str, key: &[u8]) -> Option<Vec<u8>> {
+        match self.check(name, key) {
+            Some(false) => None,
+            Some(true) => {
+                let value = self.snapshot.get(name, key);
+                if value.is_none() {
+                    self.record_absent();
+                }
+                value
+            }
+            None => self.snapshot.get(name, key),
+        }
+    }
+
+    fn contains(&self, name: &str, key: &[u8]) -> bool {
+        match self.check(name, key) {
+            Some(false) => false,
+            Some(true) => {
+                let contains = self.snapshot.contains(name, key);
+                if !contains {
+                    self.record_absent();
+                }
+                contains
+            }
+            None => self.snapshot.contains(name, key),
+        }
+    }
+
+    fn iter<'a>(&'a self, name: &str, from: &[u8]) -> Iter<'a> {
+        self.snapshot.iter(name, from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::test_utils::TestDB;
+
+    #[test]
+    fn false_positive_rate() {
+        let mut filter = BloomFilter::new(1000, 0.01);
+        for i in 0..1000_u32 {
+            filter.insert(&i.to_be_bytes());
+        }
+        assert!((0..1000_u32).all(|i| filter.may_contain(&i.to_be_bytes())));
+        let false_positives = (1000..11_000_u32)
+            .filter(|i| filter.may_contain(&i.to_be_bytes()))
+            .count();
+        assert!(false_positives < 300, "{} false positives", false_positives);
+    }
+
+    #[test]
+    fn definite_negatives() {
+        let base = TestDB::new();
+        let mut fork = base.fork();
+        fork.put("txs", vec![1], vec![1]);
+        base.merge(fork.into_patch()).unwrap();
+
+        let db = BloomDB::new(base, BloomOptions::with_names(vec!["txs"]));
+        let mut fork = db.fork();
+        fork.put("txs", vec![2], vec![2]);
+        fork.put("blocks", vec![1], vec![1]);
+        db.merge(fork.into_patch()).unwrap();
+
+        let snapshot = db.snapshot();
+        assert!(snapshot.contains("txs", &[1]));
+        assert_eq!(snapshot.get("txs", &[2]), Some(vec![2]));
+        assert!(snapshot.contains("blocks", &[1]));
+        for i in 3..103_u8 {
+            assert!(!snapshot.contains("txs", &[i]));
+        }
+        let stats = db.stats();
+        assert_eq!(stats.checks, 102);
+        assert_eq!(stats.negatives + stats.false_positives, 100);
+        assert!(stats.negatives > 90);
+        assert!(stats.false_positive_ratio() < 0.1);
+
+        db.reset_stats();
+        assert_eq!(db.stats(), BloomStats::default());
+    }
+
+    #[test]
+    fn rebuild_keeps_old_snapshots() {
+        let mut options = BloomOptions::with_names(vec!["txs"]);
+        options.capacity = 4;
+        let db = BloomDB::new(TestDB::new(), options);
+        let mut fork = db.fork();
+        fork.put("txs", vec![0], vec![0]);
+        db.merge(fork.into_patch()).unwrap();
+        let old = db.snapshot();
+
+        // Removing the key and overflowing the filter rebuilds it without the key.
+        let mut fork = db.fork();
+        fork.remove("txs", vec![0]);
+        for i in 1..8_u8 {
+            fork.put("txs", vec![i], vec![i]);
+        }
+        db.merge(fork.into_patch()).unwrap();
+
+        assert!(old.contains("txs", &[0]));
+        let new = db.snapshot();
+        assert!(!new.contains("txs", &[0]));
+        assert!((1..8_u8).all(|i| new.contains("txs", &[i])));
+    }
+
+    #[test]
+    fn repeated_puts_are_counted_once() {
+        let mut options = BloomOptions::with_names(vec!["txs"]);
+        options.capacity = 4;
+        let db = BloomDB::new(TestDB::new(), options);
+        for i in 0..8_u8 {
+            let mut fork = db.fork();
+            fork.put("txs", vec![1], vec![i]);
+            db.merge(fork.into_patch()).unwrap();
+        }
+        let filters = db.filters.lock().unwrap();
+        assert_eq!(filters["txs"].len, 1);
+        assert_eq!(filters["txs"].capacity, 4);
+    }
+}
@@ -0,0 +1,476 @@
+//! Bulk loading of a fresh `FileDB` from key-value streams.
+//!
+//! A [`BulkLoader`] writes column families directly into the data file of an empty
+//! [`FileDB`] directory, bypassing forks and patches entirely. Column families are loaded
+//! one at a time in ascending order of names. [`load_sorted`] expects the entries in
+//! strictly ascending order of keys and streams them straight to the file; [`load`] accepts
+//! entries in any order and sorts them externally, spilling sorted chunks to temporary
+//! files once they exceed [`BulkLoadOptions::sort_buffer_size`].
+//!
+//! [`finish`] publishes the data file atomically and returns an integrity hash over the
+//! loaded data. The same hash can be computed over any storage view with [`content_hash`],
+//! e.g. to check the database opened after loading against the source of the data.
+//!
+//! [`BulkLoader`]: struct.BulkLoader.html
+//! [`FileDB`]: ../filedb/struct.FileDB.html
+//! [`load_sorted`]: struct.BulkLoader.html#method.load_sorted
+//! [`load`]: struct.BulkLoader.html#method.load
+//! [`BulkLoadOptions::sort_buffer_size`]: struct.BulkLoadOptions.html#structfield.sort_buffer_size
+//! [`finish`]: struct.BulkLoader.html#method.finish
+//! [`content_hash`]: fn.content_hash.html
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+
+use byteorder::{BigEndian, WriteBytesExt};
+
+use crate::crypto::{Hash, HashStream};
+
+use super::db::Snapshot;
+use super::filedb::{DATA_MAGIC, TMP_SUFFIX, data_path, sync_dir};
+use super::wal::{read_bytes, write_bytes};
+use super::{Error, Result};
+
+/// Options of the `BulkLoader`.
+#[derive(Debug, Clone)]
+pub struct BulkLoadOptions {
+    /// Size of the entries in bytes sorted in memory by `BulkLoader::load` before they are
+    /// written to a temporary file. Default value is 64 MiB.
+    pub sort_buffer_size: usize,
+}
+
+impl Default for BulkLoadOptions {
+    fn default() -> Self {
+        Self {
+            sort_buffer_size: 64 << 20,
+        }
+    }
+}
+
+/// Writer of the data file of a fresh `FileDB`.
+pub struct BulkLoader {
+    dir: PathBuf,
+    options: BulkLoadOptions,
+    path: PathBuf,
+    writer: BufWriter<File>,
+    offset: u64,
+    // Offsets of the entry counts to fill in, with the counts.
+    counts: Vec<(u64, u64)>,
+    last_name: Option<String>,
+    hasher: HashStream,
+    chunks: usize,
+    // Whether a load has failed, leaving a partial column family in the data file.
+    failed: bool,
+}
+
+/// Sorted chunk of entries spilled to a temporary file.
+struct Chunk {
+    path: PathBuf,
+    reader: BufReader<File>,
+    remaining: u64,
+}
+
+fn update_hash(hasher: HashStream, bytes: &[u8]) -> HashStream {
+    hasher
+        .update(&(bytes.len() as u32).to_be_bytes())
+        .update(bytes)
+}
+
+/// Returns the integrity hash over the entries of the given column families, as returned
+/// by `BulkLoader::finish` after loading the same data.
+pub fn content_hash(view: &dyn Snapshot, names: &[&str]) -> Hash {
+    let mut names = names.to_vec();
+    names.sort();
+    let mut hasher = HashStream::new();
+    for name in names {
+        hasher = update_hash(hasher, name.as_bytes());
+        let mut iter = view.iter(name, &[]);
+        while let Some((key, value)) = iter.next() {
+            hasher = update_hash(update_hash(hasher, key), value);
+        }
+    }
+    hasher.hash()
+}
+
+impl BulkLoader {
+    /// Starts loading a database into the given directory, creating the directory if it does
+    /// not exist.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `Other` error if the directory is not empty, or an `Io` error if the data
+    /// file cannot be created.
+    pub fn new<P: AsRef<Path>>(dir: P, options: BulkLoadOptions) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)
+            .map_err(|e| Error::io(format!("creating {}", dir.display()), e))?;
+        // Only an empty directory is accepted, and nothing in it is touched otherwise.
+        let occupied = fs::read_dir(&dir)
+            .map_err(|e| Error::io(format!("listing {}", dir.display()), e))?
+            .next()
+            .is_some();
+        if occupied {
+            return Err(Error::new(format!("{} is not empty", dir.display())));
+        }
+
+        let path = data_path(&dir, 0).with_extension(&TMP_SUFFIX[1..]);
+        let context = || format!("creating {}", path.display());
+        // The file is read back by `finish` to compute the checksum.
+        let mut writer = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .map(BufWriter::new)
+            .map_err(|e| Error::io(context(), e))?;
+        // The number of column families is filled in by `finish`.
+        writer
+            .write_all(DATA_MAGIC)
+            .and_then(|()| writer.write_u32::<BigEndian>(0))
+            .map_err(|e| Error::io(context(), e))?;
+        Ok(Self {
+            dir,
+            options,
+            path,
+            writer,
+            offset: DATA_MAGIC.len() as u64 + 4,
+            counts: Vec::new(),
+            last_name: None,
+            hasher: HashStream::new(),
+            chunks: 0,
+            failed: false,
+        })
+    }
+
+    fn ensure_usable(&self) -> Result<()> {
+        if self.failed {
+            return Err(Error::new("bulk loader cannot be used after a failed load"));
+        }
+        Ok(())
+    }
+
+    fn record<T>(&mut self, result: Result<T>) -> Result<T> {
+        self.failed = result.is_err();
+        result
+    }
+
+    fn check_name(&mut self, name: &str) -> Result<()> {
+        if self.last_name.as_deref().is_some_and(|last| last >= name) {
+            return Err(Error::new(format!(
+                "column family `{}` is not loaded in ascending order of names",
+                name
+            )));
+        }
+        self.last_name = Some(name.to_string());
+        Ok(())
+    }
+
+    fn write_family<I>(&mut self, name: &str, entries: I) -> Result<u64>
+    where
+        I: IntoIterator<Item = io::Result<(Vec<u8>, Vec<u8>)>>,
+    {
+        let context = || format!("writing {}", self.path.display());
+        write_bytes(&mut self.writer, name.as_bytes())
+            .and_then(|()| self.writer.write_u64::<BigEndian>(0))
+            .map_err(|e| Error::io(context(), e))?;
+        self.offset += 4 + name.len() as u64;
+        let count_offset = self.offset;
+        self.offset += 8;
+        let mut hasher = update_hash(self.hasher.clone(), name.as_bytes());
+
+        let mut count = 0;
+        let mut last_key: Option<Vec<u8>> = None;
+        for entry in entries {
+            let (key, value) = entry.map_err(|e| Error::io(context(), e))?;
+            if last_key.as_ref().is_some_and(|last| *last >= key) {
+                return Err(Error::new(format!(
+                    "keys of `{}` are not in strictly ascending order",
+                    name
+                )));
+            }
+            write_bytes(&mut self.writer, &key)
+                .and_then(|()| write_bytes(&mut self.writer, &value))
+                .map_err(|e| Error::io(context(), e))?;
+            self.offset += 8 + key.len() as u64 + value.len() as u64;
+            hasher = update_hash(update_hash(hasher, &key), &value);
+            last_key = Some(key);
+            count += 1;
+        }
+        self.hasher = hasher;
+        self.counts.push((count_offset, count));
+        Ok(count)
+    }
+
+    /// Loads the column family with the given `name` from entries sorted in strictly
+    /// ascending order of keys, and returns the number of loaded entries.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `Other` error if the column families are not loaded in ascending order of
+    /// names or the keys are not sorted, or an `Io` error if the data file cannot be written.
+    /// After an error, all further calls to the loader fail.
+    pub fn load_sorted<I>(&mut self, name: &str, entries: I) -> Result<u64>
+    where
+        I: IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+    {
+        self.ensure_usable()?;
+        let result = self
+            .check_name(name)
+            .and_then(|()| self.write_family(name, entries.into_iter().map(Ok)));
+        self.record(result)
+    }
+
+    /// Loads the column family with the given `name` from entries in any order, and returns
+    /// the number of loaded entries.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `Other` error if the column families are not loaded in ascending order of
+    /// names or a key is repeated, or an `Io` error if a file cannot be written.
+    /// After an error, all further calls to the loader fail.
+    pub fn load<I>(&mut self, name: &str, entries: I) -> Result<u64>
+    where
+        I: IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+    {
+        self.ensure_usable()?;
+        let result = self
+            .check_name(name)
+            .and_then(|()| self.sort_family(name, entries));
+        self.record(result)
+    }
+
+    fn sort_family<I>(&mut self, name: &str, entries: I) -> Result<u64>
+    where
+        I: IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+    {
+        let mut chunks = Vec::new();
+        let mut buffer = Vec::new();
+        let mut buffered = 0;
+        for (key, value) in entries {
+            buffered += key.len() + value.len();
+            buffer.push((key, value));
+            if buffered >= self.options.sort_buffer_size {
+                chunks.push(self.write_chunk(&mut buffer)?);
+                buffered = 0;
+            }
+        }
+        if chunks.is_empty() {
+            buffer.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+            return self.write_family(name, buffer.into_iter().map(Ok));
+        }
+        if !buffer.is_empty() {
+            chunks.push(self.write_chunk(&mut buffer)?);
+        }
+        self.write_family(name, merge_chunks(chunks))
+    }
+
+    fn write_chunk(&mut self, buffer: &mut Vec<(Vec<u8>, Vec<u8>)>) -> Result<Chunk> {
+        buffer.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        let path = self.dir.join(format!("sort-{}{}", self.chunks, TMP_SUFFIX));
+        self.chunks += 1;
+        let write = || -> io::Result<Chunk> {
+            let mut writer = BufWriter::new(File::create(&path)?);
+            for (key, value) in buffer.iter() {
+                write_bytes(&mut writer, key)?;
+                write_bytes(&mut writer, value)?;
+            }
+            writer.flush()?;
+            Ok(Chunk {
+                reader: BufReader::new(File::open(&path)?),
+                path: path.clone(),
+                remaining: buffer.len() as u64,
+            })
+        };
+        let chunk = write().map_err(|e| Error::io(format!("writing {}", path.display()), e))?;
+        buffer.clear();
+        Ok(chunk)
+    }
+
+    /// Publishes the data file and returns the integrity hash over the loaded data.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `Other` error if a load has failed, or an `Io` error if the data file
+    /// cannot be written.
+    pub fn finish(mut self) -> Result<Hash> {
+        self.ensure_usable()?;
+        let path = data_path(&self.dir, 0);
+        let context = || format!("writing {}", path.display());
+        let families = self.counts.len() as u32;
+        let mut write = || -> io::Result<()> {
+            self.writer.flush()?;
+            let file = self.writer.get_mut();
+            file.seek(SeekFrom::Start(DATA_MAGIC.len() as u64))?;
+            file.write_u32::<BigEndian>(families)?;
+            for &(offset, count) in &self.counts {
+                file.seek(SeekFrom::Start(offset))?;
+                file.write_u64::<BigEndian>(count)?;
+            }
+
+            // The checksum covers the whole content, including the counts filled in above.
+            file.seek(SeekFrom::Start(0))?;
+            let mut hasher = crc32fast::Hasher::new();
+            let mut reader = BufReader::new(&mut *file).take(self.offset);
+            let mut buf = vec![0; 64 << 10];
+            loop {
+                let read = reader.read(&mut buf)?;
+                if read == 0 {
+                    break;
+                }
+                hasher.update(&buf[..read]);
+            }
+            file.seek(SeekFrom::End(0))?;
+            file.write_u32::<BigEndian>(hasher.finalize())?;
+            file.sync_all()?;
+            fs::rename(&self.path, &path)
+        };
+        write().map_err(|e| Error::io(context(), e))?;
+        sync_dir(&self.dir)?;
+        Ok(mem::take(&mut self.hasher).hash())
+    }
+}
+
+fn read_entry(chunk: &mut Chunk) -> io::Result<Option<(Vec<u8>, Vec<u8>)>> {
+    if chunk.remaining == 0 {
+        return Ok(None);
+    }
+    chunk.remaining -= 1;
+    let key = read_bytes(&mut chunk.reader)?;
+    Ok(Some((key, read_bytes(&mut chunk.reader)?)))
+}
+
+/// Returns the entries of the sorted chunks in ascending order of keys.
+fn merge_chunks(mut chunks: Vec<Chunk>) -> impl Iterator<Item = io::Result<(Vec<u8>, Vec<u8>)>> {
+    let mut heap = BinaryHeap::new();
+    let mut error = None;
+    for (index, chunk) in chunks.iter_mut().enumerate() {
+        match read_entry(chunk) {
+            Ok(Some((key, value))) => heap.push(Reverse((key, index, value))),
+            Ok(None) => {}
+            Err(e) => error = Some(e),
+        }
+    }
+    let mut error = error.map(Err);
+    std::iter::from_fn(move || {
+        if let Some(error) = error.take() {
+            return Some(error);
+        }
+        let Reverse((key, index, value)) = heap.pop()?;
+        match read_entry(&mut chunks[index]) {
+            Ok(Some((next_key, next_value))) => heap.push(Reverse((next_key, index, next_value))),
+            Ok(None) => {}
+            Err(e) => error = Some(Err(e)),
+        }
+        Some(Ok((key, value)))
+    })
+}
+
+impl Drop for Chunk {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+impl Drop for BulkLoader {
+    fn drop(&mut self) {
+        // The data file is renamed by `finish`, so only an unfinished file is removed.
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::db::Database;
+    use crate::storage::filedb::{FileDB, FileDBOptions};
+    use crate::storage::test_utils::TempDir;
+
+    fn entries(count: u32) -> Vec<(Vec<u8>, Vec<u8>)> {
+        (0..count)
+            .map(|i| (i.to_be_bytes().to_vec(), (i * 2).to_be_bytes().to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn load_and_open() {
+        let dir = TempDir::new("bulk");
+        let options = BulkLoadOptions {
+            sort_buffer_size: 256,
+        };
+        let mut loader = BulkLoader::new(dir.path(), options).unwrap();
+        assert_eq!(loader.load_sorted("accounts", entries(100)).unwrap(), 100);
+        let mut shuffled = entries(500);
+        shuffled.reverse();
+        shuffled.swap(3, 250);
+        assert_eq!(loader.load("blocks", shuffled).unwrap(), 500);
+        assert_eq!(loader.load_sorted("empty", Vec::new()).unwrap(), 0);
+        let hash = loader.finish().unwrap();
+
+        let db = FileDB::open(dir.path(), FileDBOptions::default()).unwrap();
+        let snapshot = db.snapshot();
+        assert_eq!(
+            snapshot.get("accounts", &5_u32.to_be_bytes()),
+            Some(10_u32.to_be_bytes().to_vec())
+        );
+        let mut iter = snapshot.iter("blocks", &[]);
+        for (key, value) in entries(500) {
+            assert_eq!(iter.next(), Some((key.as_slice(), value.as_slice())));
+        }
+        assert_eq!(iter.next(), None);
+        assert_eq!(
+            content_hash(&*snapshot, &["blocks", "empty", "accounts"]),
+            hash
+        );
+        assert_ne!(content_hash(&*snapshot, &["blocks"]), hash);
+
+        // The data file is left along with the log created by opening the database.
+        let mut names = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(
+            names,
+            vec!["data-00000000000000000000", "wal-00000000000000000000"]
+        );
+        assert!(BulkLoader::new(dir.path(), BulkLoadOptions::default()).is_err());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn invalid_order() {
+        let dir = TempDir::new("bulk_order");
+        let mut loader = BulkLoader::new(dir.path(), BulkLoadOptions::default()).unwrap();
+        loader.load_sorted("b", entries(3)).unwrap();
+        assert!(loader.load_sorted("a", entries(3)).is_err());
+
+        // The unfinished data file is removed when the loader is dropped.
+        drop(loader);
+        let mut loader = BulkLoader::new(dir.path(), BulkLoadOptions::default()).unwrap();
+        let mut unsorted = entries(3);
+        unsorted.swap(0, 1);
+        assert!(loader.load_sorted("a", unsorted).is_err());
+        // The partially loaded column family makes the loader unusable.
+        let failed = "bulk loader cannot be used after a failed load";
+        match loader.load_sorted("b", entries(3)) {
+            Err(e) => assert_eq!(e.to_string(), failed),
+            Ok(_) => panic!("loader is used after a failed load"),
+        }
+        match loader.load("c", entries(3)) {
+            Err(e) => assert_eq!(e.to_string(), failed),
+            Ok(_) => panic!("loader is used after a failed load"),
+        }
+        assert!(loader.finish().is_err());
+
+        let mut loader = BulkLoader::new(dir.path(), BulkLoadOptions::default()).unwrap();
+        assert!(
+            loader
+                .load("b", vec![(vec![1], vec![1]), (vec![1], vec![2])])
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,380 @@
+//! A `Database` wrapper with a read-through cache in front of `Snapshot::get`.
+//!
+//! Each column family gets its own bounded LRU of raw values (including the fact that a key
+//! is absent). The cache always describes the latest state of the database: merging a patch
+//! evicts exactly the keys touched by the patch and bumps the cache version, so snapshots
+//! taken before the merge stop using the cache and keep their read isolation. Snapshots
+//! taken while a merge is in progress do not use the cache at all, since they may read
+//! the database either before or after the merge.
+
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use lru::LruCache;
+
+use super::db::{Change, Database, Iter, Patch, Snapshot};
+use super::Result;
+
+/// Hit and miss counters of the cache.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Number of reads answered from the cache.
+    pub hits: u64,
+    /// Number of reads passed to the underlying database.
+    pub misses: u64,
+}
+
+impl CacheStats {
+    /// Returns the share of reads answered from the cache, or `0` if there were no reads.
+    pub fn hit_ratio(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            0.0
+        } else {
+            self.hits as f64 / total as f64
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+struct Cache {
+    version: u64,
+    // Number of merges in progress.
+    merging: usize,
+    capacity: NonZeroUsize,
+    families: HashMap<String, LruCache<Vec<u8>, Option<Vec<u8>>>>,
+}
+
+impl Cache {
+    fn get(&mut self, version: Option<u64>, name: &str, key: &[u8]) -> Option<Option<Vec<u8>>> {
+        if version != Some(self.version) {
+            return None;
+        }
+        self.families
+            .get_mut(name)
+            .and_then(|family| family.get(key))
+            .cloned()
+    }
+
+    fn put(&mut self, version: Option<u64>, name: &str, key: &[u8], value: Option<Vec<u8>>) {
+        if version != Some(self.version) {
+            return;
+        }
+        let capacity = self.capacity;
+        self.families
+            .entry(name.to_string())
+            .or_insert_with(|| LruCache::new(capacity))
+            .put(key.to_vec(), value);
+    }
+
+    fn invalidate(&mut self, touched: &[Touched]) {
+        for (name, keys) in touched {
+            if let Some(family) = self.families.get_mut(name) {
+                for (key, is_prefix) in keys {
+                    if *is_prefix {
+                        let keys = family
+                            .iter()
+                            .map(|(k, _)| k)
+                            .filter(|k| k.starts_with(key))
+                            .cloned()
+                            .collect::<Vec<_>>();
+                        for k in keys {
+                            family.pop(&k);
+                        }
+                    } else {
+                        family.pop(key);
+                    }
+                }
+            }
+        }
+        self.version += 1;
+    }
+}
+
+/// Keys of a column family touched by a patch, with flags of prefix removals.
+type Touched = (String, Vec<(Vec<u8>, bool)>);
+
+fn touched(patch: &Patch) -> Vec<Touched> {
+    patch
+        .iter()
+        .map(|(name, changes)| {
+            let keys = changes
+                .iter()
+                .map(|(key, change)| (key.clone(), matches!(*change, Change::DeletePrefix)))
+                .collect();
+            (name.clone(), keys)
+        })
+        .collect()
+}
+
+/// Database wrapper which caches values read through its snapshots.
+pub struct CachedDB<T: Database> {
+    inner: T,
+    cache: Arc<Mutex<Cache>>,
+    counters: Arc<Counters>,
+}
+
+struct CachedSnapshot {
+    snapshot: Box<dyn Snapshot>,
+    // Version of the cache, or `None` if the snapshot was taken during a merge.
+    version: Option<u64>,
+    cache: Arc<Mutex<Cache>>,
+    counters: Arc<Counters>,
+}
+
+impl<T: Database> CachedDB<T> {
+    /// Wraps the given database, keeping at most `capacity` entries per column family.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is zero.
+    pub fn new(inner: T, capacity: usize) -> Self {
+        let capacity = NonZeroUsize::new(capacity).expect("cache capacity must be positive");
+        Self {
+            inner,
+            cache: Arc::new(Mutex::new(Cache {
+                version: 0,
+                merging: 0,
+                capacity,
+                families: HashMap::new(),
+            })),
+            counters: Arc::default(),
+        }
+    }
+
+    /// Returns a reference to the underlying database.
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    /// Returns the hit and miss counters accumulated so far.
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.counters.hits.load(Ordering::Relaxed),
+            misses: self.counters.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Resets the hit and miss counters.
+    pub fn reset_stats(&self) {
+        self.counters.hits.store(0, Ordering::Relaxed);
+        self.counters.misses.store(0, Ordering::Relaxed);
+    }
+
+    /// Drops all cached entries.
+    pub fn clear(&self) {
+        self.cache.lock().unwrap().families.clear();
+    }
+
+    fn merge_with<F>(&self, patch: Patch, merge: F) -> Result<()>
+    where
+        F: FnOnce(&T, Patch) -> Result<()>,
+    {
+        // The cache is not locked while the patch is merged. The keys of the patch are
+        // evicted and the version is bumped beforehand, so older snapshots stop using
+        // the cache, and snapshots taken until the merge finishes bypass it. Invalidation
+        // is harmless even if merging fails, so it is done unconditionally.
+        {
+            let mut cache = self.cache.lock().unwrap();
+            cache.invalidate(&touched(&patch));
+            cache.merging += 1;
+        }
+        let result = merge(&self.inner, patch);
+        self.cache.lock().unwrap().merging -= 1;
+        result
+    }
+}
+
+impl<T: Database> Database for CachedDB<T> {
+    fn snapshot(&self) -> Box<dyn Snapshot> {
+        let cache = self.cache.lock().unwrap();
+        Box::new(CachedSnapshot {
+            snapshot: self.inner.snapshot(),
+            version: if cache.merging == 0 {
+                Some(cache.version)
+            } else {
+                None
+            },
+            cache: Arc::clone(&self.cache),
+            counters: Arc::clone(&self.counters),
+        })
+    }
+
+    fn merge(&self, patch: Patch) -> Result<()> {
+        self.merge_with(patch, |db, patch| db.merge(patch))
+    }
+
+    fn merge_sync(&self, patch: Patch) -> Result<()> {
+        self.merge_with(patch, |db, patch| db.merge_sync(patch))
+    }
+}
+
+impl CachedSnapshot {
+    fn cached(&self, name: &str, key: &[u8]) -> Option<Option<Vec<u8>>> {
+        let cached = self.cache.lock().unwrap().get(self.version, name, key);
+        let counter = if cached.is_some() {
+            &self.counters.hits
+        } else {
+            &self.counters.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        cached
+    }
+}
+
+impl Snapshot for CachedSnapshot {
+    fn get(&self, name: &str, key: &[u8]) -> Option<Vec<u8>> {
+        if let Some(value) = self.cached(name, key) {
+            return value;
+        }
+        let value = self.snapshot.get(name, key);
+        self.cache
+            .lock()
+            .unwrap()
+            .put(self.version, name, key, value.clone());
+        value
+    }
+
+    fn contains(&self, name: &str, key: &[u8]) -> bool {
+        match self.cached(name, key) {
+            Some(value) => value.is_some(),
+            None => self.snapshot.contains(name, key),
+        }
+    }
+
+    fn multi_get(&self, name: &str, keys: &[&[u8]]) -> Vec<Option<Vec<u8>>> {
+        let mut values = Vec::with_capacity(keys.len());
+        let mut missed = Vec::new();
+        for (i, key) in keys.iter().enumerate() {
+            match self.cached(name, key) {
+                Some(value) => values.push(value),
+                None => {
+                    values.push(None);
+                    missed.push(i);
+                }
+            }
+        }
+        if !missed.is_empty() {
+            let missed_keys = missed.iter().map(|&i| keys[i]).collect::<Vec<_>>();
+            let stored = self.snapshot.multi_get(name, &missed_keys);
+            let mut cache = self.cache.lock().unwrap();
+            for (i, value) in missed.into_iter().zip(stored) {
+                cache.put(self.version, name, keys[i], value.clone());
+                values[i] = value;
+            }
+        }
+        values
+    }
+
+    fn iter<'a>(&'a self, name: &str, from: &[u8]) -> Iter<'a> {
+        self.snapshot.iter(name, from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::test_utils::TestDB;
+
+    #[test]
+    fn hits_and_misses() {
+        let db = CachedDB::new(TestDB::new(), 2);
+        let mut fork = db.fork();
+        fork.put("accounts", vec![1], vec![1]);
+        fork.put("accounts", vec![2], vec![2]);
+        fork.put("accounts", vec![3], vec![3]);
+        db.merge(fork.into_patch()).unwrap();
+
+        let snapshot = db.snapshot();
+        assert_eq!(snapshot.get("accounts", &[1]), Some(vec![1]));
+        assert_eq!(snapshot.get("accounts", &[1]), Some(vec![1]));
+        assert!(!snapshot.contains("accounts", &[4]));
+        assert_eq!(snapshot.get("accounts", &[4]), None);
+        assert!(!snapshot.contains("accounts", &[4]));
+        assert_eq!(db.stats(), CacheStats { hits: 2, misses: 3 });
+
+        // `[1]` is evicted by the bounded LRU.
+        assert_eq!(snapshot.get("accounts", &[2]), Some(vec![2]));
+        assert_eq!(snapshot.get("accounts", &[1]), Some(vec![1]));
+        assert_eq!(db.stats(), CacheStats { hits: 2, misses: 5 });
+
+        db.reset_stats();
+        assert_eq!(db.stats(), CacheStats::default());
+    }
+
+    #[test]
+    fn multi_get_fills_cache() {
+        let db = CachedDB::new(TestDB::new(), 16);
+        let mut fork = db.fork();
+        fork.put("accounts", vec![1], vec![1]);
+        fork.put("accounts", vec![2], vec![2]);
+        db.merge(fork.into_patch()).unwrap();
+
+        let snapshot = db.snapshot();
+        assert_eq!(snapshot.get("accounts", &[1]), Some(vec![1]));
+        assert_eq!(
+            snapshot.multi_get("accounts", &[&[1], &[2], &[3]]),
+            vec![Some(vec![1]), Some(vec![2]), None]
+        );
+        assert_eq!(db.stats(), CacheStats { hits: 1, misses: 3 });
+        assert_eq!(
+            snapshot.multi_get("accounts", &[&[3], &[2]]),
+            vec![None, Some(vec![2])]
+        );
+        assert_eq!(db.stats(), CacheStats { hits: 3, misses: 3 });
+    }
+
+    #[test]
+    fn invalidation_by_patch() {
+        let db = CachedDB::new(TestDB::new(), 16);
+        let mut fork = db.fork();
+        fork.put("accounts", vec![1], vec![1]);
+        fork.put("accounts", vec![2], vec![2]);
+        db.merge(fork.into_patch()).unwrap();
+
+        let old = db.snapshot();
+        assert_eq!(old.get("accounts", &[1]), Some(vec![1]));
+        assert_eq!(old.get("accounts", &[2]), Some(vec![2]));
+
+        let mut fork = db.fork();
+        fork.put("accounts", vec![1], vec![10]);
+        db.merge(fork.into_patch()).unwrap();
+
+        // The old snapshot keeps its isolation.
+        assert_eq!(old.get("accounts", &[1]), Some(vec![1]));
+
+        db.reset_stats();
+        let new = db.snapshot();
+        assert_eq!(new.get("accounts", &[1]), Some(vec![10]));
+        assert_eq!(new.get("accounts", &[2]), Some(vec![2]));
+        assert_eq!(db.stats(), CacheStats { hits: 1, misses: 1 });
+    }
+
+    #[test]
+    fn snapshots_during_merge_bypass_cache() {
+        let db = CachedDB::new(TestDB::new(), 16);
+        let mut fork = db.fork();
+        fork.put("accounts", vec![1], vec![1]);
+        db.merge(fork.into_patch()).unwrap();
+
+        let mut fork = db.fork();
+        fork.put("accounts", vec![1], vec![10]);
+        db.merge_with(fork.into_patch(), |inner, patch| {
+            // Taken before the patch reaches the underlying database.
+            let during = db.snapshot();
+            assert_eq!(during.get("accounts", &[1]), Some(vec![1]));
+            assert_eq!(during.get("accounts", &[1]), Some(vec![1]));
+            inner.merge(patch)
+        })
+        .unwrap();
+        assert_eq!(db.stats(), CacheStats { hits: 0, misses: 2 });
+        assert_eq!(db.snapshot().get("accounts", &[1]), Some(vec![10]));
+    }
+}
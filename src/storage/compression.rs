@@ -0,0 +1,347 @@
+//! A `Database` wrapper that transparently compresses stored values.
+//!
+//! Every value written into a compressed column family is prefixed with a one-byte codec tag,
+//! so small values may be kept raw while large ones are deflated, and both kinds can live
+//! side by side in the same column family. Column families that are not selected for
+//! compression are passed to the underlying backend untouched.
+
+use std::collections::HashSet;
+use std::io::{Read, Write};
+use std::iter::Iterator as StdIterator;
+use std::sync::Arc;
+
+use flate2::Compression;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+
+use super::db::{Change, Changes, Database, Iter, Iterator, Patch, Snapshot};
+use super::{Error, Result};
+
+/// Codec tag of a value stored as is.
+pub const CODEC_RAW: u8 = 0;
+/// Codec tag of a value compressed with deflate.
+pub const CODEC_DEFLATE: u8 = 1;
+
+/// Options of the value compression layer.
+#[derive(Debug, Clone)]
+pub struct CompressionOptions {
+    /// Values which are shorter than this number of bytes are stored raw.
+    pub threshold: usize,
+    /// Compression level in the range `0..=9`.
+    pub level: u32,
+    /// Names of the column families to compress; `None` means all of them.
+    pub names: Option<HashSet<String>>,
+}
+
+impl Default for CompressionOptions {
+    fn default() -> Self {
+        Self {
+            threshold: 256,
+            level: 6,
+            names: None,
+        }
+    }
+}
+
+impl CompressionOptions {
+    /// Creates options compressing values of the given column families only.
+    pub fn with_names<I, S>(names: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self {
+            names: Some(names.into_iter().map(Into::into).collect()),
+            ..Self::default()
+        }
+    }
+
+    /// Returns `true` if values of the column family with the given `name` are tagged
+    /// with a codec byte.
+    pub fn is_compressed(&self, name: &str) -> bool {
+        self.names.as_ref().is_none_or(|names| names.contains(name))
+    }
+
+    /// Encodes a value of the compressed column family.
+    pub fn encode(&self, value: &[u8]) -> Vec<u8> {
+        if value.len() >= self.threshold {
+            let mut encoder =
+                DeflateEncoder::new(vec![CODEC_DEFLATE], Compression::new(self.level));
+            encoder.write_all(value).unwrap();
+            let compressed = encoder.finish().unwrap();
+            // Incompressible data is kept raw.
+            if compressed.len() <= value.len() {
+                return compressed;
+            }
+        }
+        let mut buf = Vec::with_capacity(value.len() + 1);
+        buf.push(CODEC_RAW);
+        buf.extend_from_slice(value);
+        buf
+    }
+
+    /// Decodes a tagged value stored under the `key` of the compressed column family `name`.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `Corrupted` error if the value has an unknown codec tag or cannot be
+    /// decompressed.
+    pub fn decode(&self, name: &str, key: &[u8], value: &[u8]) -> Result<Vec<u8>> {
+        let corrupted = |reason: String| Error::Corrupted {
+            name: name.to_string(),
+            key: key.to_vec(),
+            reason,
+        };
+        match value.split_first() {
+            Some((&CODEC_RAW, raw)) => Ok(raw.to_vec()),
+            Some((&CODEC_DEFLATE, compressed)) => {
+                let mut buf = Vec::with_capacity(compressed.len() * 2);
+                DeflateDecoder::new(compressed)
+                    .read_to_end(&mut buf)
+                    .map_err(|e| corrupted(format!("cannot inflate value: {}", e)))?;
+                Ok(buf)
+            }
+            Some((tag, _)) => Err(corrupted(format!("unknown codec tag {}", tag))),
+            None => Err(corrupted("value without codec tag".to_string())),
+        }
+    }
+
+    // `Snapshot` reads cannot report errors, so a corrupted value is fatal for them.
+    fn decode_or_panic(&self, name: &str, key: &[u8], value: &[u8]) -> Vec<u8> {
+        self.decode(name, key, value)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+}
+
+/// Database wrapper which compresses values above the configured size threshold.
+///
+/// ```ignore
+/// let options = CompressionOptions::with_names(vec!["blocks"]);
+/// let db = CompressedDB::new(backend, options);
+/// ```
+#[derive(Debug)]
+pub struct CompressedDB<T: Database> {
+    inner: T,
+    options: Arc<CompressionOptions>,
+}
+
+struct CompressedSnapshot {
+    snapshot: Box<dyn Snapshot>,
+    options: Arc<CompressionOptions>,
+}
+
+struct CompressedIter<'a> {
+    inner: Iter<'a>,
+    options: &'a CompressionOptions,
+    name: String,
+    key: Vec<u8>,
+    value: Vec<u8>,
+    peeked: bool,
+}
+
+impl<T: Database> CompressedDB<T> {
+    /// Wraps the given database.
+    pub fn new(inner: T, options: CompressionOptions) -> Self {
+        Self {
+            inner,
+            options: Arc::new(options),
+        }
+    }
+
+    /// Returns the compression options.
+    pub fn options(&self) -> &CompressionOptions {
+        &self.options
+    }
+
+    /// Returns a reference to the underlying database.
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    fn encode_patch(&self, patch: Patch) -> Patch {
+        let mut encoded = Patch::new();
+        for (name, changes) in patch {
+            if !self.options.is_compressed(&name) {
+                encoded.insert_changes(name, changes);
+                continue;
+            }
+            let mut encoded_changes = Changes::new();
+            for (key, change) in changes {
+                let change = match change {
+                    Change::Put(value) => Change::Put(self.options.encode(&value)),
+                    change => change,
+                };
+                encoded_changes.insert(key, change);
+            }
+            encoded.insert_changes(name, encoded_changes);
+        }
+        encoded
+    }
+}
+
+impl<T: Database> Database for CompressedDB<T> {
+    fn snapshot(&self) -> Box<dyn Snapshot> {
+        Box::new(CompressedSnapshot {
+            snapshot: self.inner.snapshot(),
+            options: Arc::clone(&self.options),
+        })
+    }
+
+    fn merge(&self, patch: Patch) -> Result<()> {
+        self.inner.merge(self.encode_patch(patch))
+    }
+
+    fn merge_sync(&self, patch: Patch) -> Result<()> {
+        self.inner.merge_sync(self.encode_patch(patch))
+    }
+
+    fn merge_patches(&self, patches: &mut dyn StdIterator<Item = Result<Patch>>) -> Result<()> {
+        let mut encoded = patches.map(|patch| patch.map(|patch| self.encode_patch(patch)));
+        self.inner.merge_patches(&mut encoded)
+    }
+}
+
+impl Snapshot for CompressedSnapshot {
+    fn get(&self, name: &str, key: &[u8]) -> Option<Vec<u8>> {
+        let value = self.snapshot.get(name, key)?;
+        if self.options.is_compressed(name) {
+            Some(self.options.decode_or_panic(name, key, &value))
+        } else {
+            Some(value)
+        }
+    }
+
+    fn contains(&self, name: &str, key: &[u8]) -> bool {
+        self.snapshot.contains(name, key)
+    }
+
+    fn multi_get(&self, name: &str, keys: &[&[u8]]) -> Vec<Option<Vec<u8>>> {
+        let values = self.snapshot.multi_get(name, keys);
+        if !self.options.is_compressed(name) {
+            return values;
+        }
+        values
+            .into_iter()
+            .zip(keys)
+            .map(|(value, key)| value.map(|value| self.options.decode_or_panic(name, key, &value)))
+            .collect()
+    }
+
+    fn iter<'a>(&'a self, name: &str, from: &[u8]) -> Iter<'a> {
+        if !self.options.is_compressed(name) {
+            return self.snapshot.iter(name, from);
+        }
+        Box::new(CompressedIter {
+            inner: self.snapshot.iter(name, from),
+            options: &self.options,
+            name: name.to_string(),
+            key: Vec::new(),
+            value: Vec::new(),
+            peeked: false,
+        })
+    }
+}
+
+impl<'a> Iterator for CompressedIter<'a> {
+    fn next(&mut self) -> Option<(&[u8], &[u8])> {
+        if self.peeked {
+            self.peeked = false;
+            self.inner.next();
+        } else {
+            let (key, value) = match self.inner.next() {
+                Some((k, v)) => (k.to_vec(), self.options.decode_or_panic(&self.name, k, v)),
+                None => return None,
+            };
+            self.key = key;
+            self.value = value;
+        }
+        Some((&self.key, &self.value))
+    }
+
+    fn peek(&mut self) -> Option<(&[u8], &[u8])> {
+        if !self.peeked {
+            let (key, value) = match self.inner.peek() {
+                Some((k, v)) => (k.to_vec(), self.options.decode_or_panic(&self.name, k, v)),
+                None => return None,
+            };
+            self.key = key;
+            self.value = value;
+            self.peeked = true;
+        }
+        Some((&self.key, &self.value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::test_utils::TestDB;
+
+    #[test]
+    fn codec_roundtrip() {
+        let options = CompressionOptions::default();
+        let small = vec![7_u8; 10];
+        let large = vec![7_u8; 4096];
+        let random: Vec<u8> = (0..1024_u32).map(|i| (i * 7919 % 251) as u8).collect();
+
+        let encoded = options.encode(&small);
+        assert_eq!(encoded[0], CODEC_RAW);
+        assert_eq!(options.decode("name", &[], &encoded).unwrap(), small);
+
+        let encoded = options.encode(&large);
+        assert_eq!(encoded[0], CODEC_DEFLATE);
+        assert!(encoded.len() < large.len());
+        assert_eq!(options.decode("name", &[], &encoded).unwrap(), large);
+
+        let encoded = options.encode(&random);
+        assert_eq!(options.decode("name", &[], &encoded).unwrap(), random);
+
+        match options.decode("name", &[1], &[42, 0]) {
+            Err(Error::Corrupted { ref key, .. }) => assert_eq!(key, &[1]),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(
+            options
+                .decode("name", &[], &[CODEC_DEFLATE, 1, 2, 3])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn compressed_db() {
+        let db = CompressedDB::new(
+            TestDB::new(),
+            CompressionOptions::with_names(vec!["blocks"]),
+        );
+        let large = vec![1_u8; 1024];
+        let mut fork = db.fork();
+        fork.put("blocks", vec![1], large.clone());
+        fork.put("blocks", vec![2], vec![2]);
+        fork.put("accounts", vec![1], large.clone());
+        db.merge(fork.into_patch()).unwrap();
+
+        let raw = db.inner().snapshot();
+        assert!(raw.get("blocks", &[1]).unwrap().len() < large.len());
+        assert_eq!(raw.get("blocks", &[2]), Some(vec![CODEC_RAW, 2]));
+        assert_eq!(raw.get("accounts", &[1]), Some(large.clone()));
+
+        let snapshot = db.snapshot();
+        assert_eq!(snapshot.get("blocks", &[1]), Some(large.clone()));
+        assert_eq!(snapshot.get("accounts", &[1]), Some(large.clone()));
+        let mut iter = snapshot.iter("blocks", &[]);
+        assert_eq!(iter.peek(), Some((&[1_u8][..], &large[..])));
+        assert_eq!(iter.next(), Some((&[1_u8][..], &large[..])));
+        assert_eq!(iter.next(), Some((&[2_u8][..], &[2_u8][..])));
+        assert_eq!(iter.next(), None);
+
+        let mut fork = db.fork();
+        fork.put("blocks", vec![3], vec![3]);
+        db.merge_patches(&mut vec![Ok(fork.into_patch())].into_iter())
+            .unwrap();
+        assert_eq!(raw.get("blocks", &[3]), None);
+        assert_eq!(
+            db.inner().snapshot().get("blocks", &[3]),
+            Some(vec![CODEC_RAW, 3])
+        );
+    }
+}
@@ -14,37 +14,169 @@
 
 use std::collections::HashMap;
 use std::collections::btree_map::{BTreeMap, IntoIter as BtmIntoIter, Iter as BtmIter, Range};
+use std::collections::btree_set::{BTreeSet, IntoIter as BtsIntoIter, Iter as BtsIter};
 use std::collections::hash_map::{Entry as HmEntry, IntoIter as HmIntoIter, Iter as HmIter};
 use std::collections::Bound::*;
 use std::cmp::Ordering::*;
-use std::iter::{Iterator as StdIterator, Peekable};
+use std::iter::{Chain, Iterator as StdIterator, Map, Peekable};
+use std::sync::Arc;
 
-use super::Result;
+use byteorder::{BigEndian, ByteOrder};
+
+use super::secondary::{SecondaryIndex, SecondaryIndexes};
+use super::{Error, Result};
 use self::NextIterValue::*;
 
+static DELETE_PREFIX: Change = Change::DeletePrefix;
+
 /// Map containing changes with corresponding key.
+///
+/// Besides changes of individual keys, `Changes` may contain range tombstones removing
+/// all keys with a certain prefix from the underlying storage (see [`Change::DeletePrefix`]).
+/// Tombstones are applied before the changes of individual keys, so a key put after
+/// its prefix has been removed survives the merge.
+///
+/// [`Change::DeletePrefix`]: enum.Change.html#variant.DeletePrefix
 #[derive(Debug, Clone)]
 pub struct Changes {
-    data: BTreeMap<Vec<u8>, Change>,
+    pub(crate) data: BTreeMap<Vec<u8>, Change>,
+    // No prefix in the set is a prefix of another one.
+    prefixes: BTreeSet<Vec<u8>>,
 }
 
 impl Changes {
     /// Creates a new empty `Changes` instance.
-    fn new() -> Self {
+    pub(crate) fn new() -> Self {
         Self {
             data: BTreeMap::new(),
+            prefixes: BTreeSet::new(),
         }
     }
 
-    /// Returns iterator over changes.
-    pub fn iter(&self) -> BtmIter<Vec<u8>, Change> {
-        self.data.iter()
+    /// Returns iterator over changes: range tombstones in ascending order of the prefixes,
+    /// followed by changes of individual keys in ascending order of the keys.
+    pub fn iter(&self) -> ChangesIter<'_> {
+        fn delete_prefix(prefix: &Vec<u8>) -> (&Vec<u8>, &Change) {
+            (prefix, &DELETE_PREFIX)
+        }
+        self.prefixes
+            .iter()
+            .map(delete_prefix as fn(&Vec<u8>) -> (&Vec<u8>, &Change))
+            .chain(self.data.iter())
+    }
+
+    /// Returns the number of changes, counting each range tombstone as a single change.
+    pub fn len(&self) -> usize {
+        self.prefixes.len() + self.data.len()
     }
+
+    /// Returns `true` if there are no changes.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns `true` if the stored value of the key is removed by a range tombstone.
+    ///
+    /// Note that the key may still have a value put after the tombstone.
+    pub fn is_removed(&self, key: &[u8]) -> bool {
+        // As prefixes do not overlap, the only candidate is the greatest prefix `<= key`.
+        self.prefixes
+            .range::<[u8], _>((Unbounded, Included(key)))
+            .next_back()
+            .is_some_and(|prefix| key.starts_with(prefix))
+    }
+
+    /// Returns the value of the key as seen through the changes: `Some(Some(value))` if
+    /// the key is put, `Some(None)` if it is removed, and `None` if the key is not affected
+    /// and should be read from the underlying storage.
+    pub(crate) fn lookup(&self, key: &[u8]) -> Option<Option<&Vec<u8>>> {
+        match self.data.get(key) {
+            Some(Change::Put(value)) => Some(Some(value)),
+            Some(Change::Delete) => Some(None),
+            Some(Change::DeletePrefix) => unreachable!(),
+            None if self.is_removed(key) => Some(None),
+            None => None,
+        }
+    }
+
+    /// Applies the change of the key after all the existing changes.
+    pub(crate) fn insert(&mut self, key: Vec<u8>, change: Change) -> Option<Change> {
+        match change {
+            Change::DeletePrefix => {
+                self.remove_prefix(key);
+                None
+            }
+            change => self.data.insert(key, change),
+        }
+    }
+
+    /// Removes all keys starting with the `prefix` using a single range tombstone, and
+    /// returns the record allowing to undo the removal.
+    pub(crate) fn remove_prefix(&mut self, prefix: Vec<u8>) -> RemovedPrefix {
+        let keys = self
+            .data
+            .range::<Vec<u8>, _>((Included(&prefix), Unbounded))
+            .map(|(k, _)| k.clone())
+            .take_while(|k| k.starts_with(&prefix))
+            .collect::<Vec<_>>();
+        let data = keys
+            .into_iter()
+            .map(|k| {
+                let change = self.data.remove(&k).unwrap();
+                (k, change)
+            })
+            .collect();
+        let mut removed = RemovedPrefix {
+            prefix: None,
+            prefixes: Vec::new(),
+            data,
+        };
+        if !self.is_removed(&prefix) {
+            removed.prefixes = self
+                .prefixes
+                .range::<Vec<u8>, _>((Included(&prefix), Unbounded))
+                .take_while(|p| p.starts_with(&prefix))
+                .cloned()
+                .collect();
+            for p in &removed.prefixes {
+                self.prefixes.remove(p);
+            }
+            self.prefixes.insert(prefix.clone());
+            removed.prefix = Some(prefix);
+        }
+        removed
+    }
+
+    /// Undoes the prefix removal described by the record.
+    fn restore(&mut self, removed: RemovedPrefix) {
+        if let Some(prefix) = removed.prefix {
+            self.prefixes.remove(&prefix);
+        }
+        self.prefixes.extend(removed.prefixes);
+        self.data.extend(removed.data);
+    }
+}
+
+/// Record of the changes dropped by `Changes::remove_prefix`.
+pub(crate) struct RemovedPrefix {
+    /// Inserted range tombstone, unless the prefix has already been removed.
+    prefix: Option<Vec<u8>>,
+    /// Range tombstones replaced by the inserted one.
+    prefixes: Vec<Vec<u8>>,
+    /// Changes of the keys starting with the prefix.
+    data: Vec<(Vec<u8>, Change)>,
 }
 
+/// Iterator over the `Changes` data by reference.
+pub type ChangesIter<'a> = Chain<
+    Map<BtsIter<'a, Vec<u8>>, fn(&Vec<u8>) -> (&Vec<u8>, &Change)>,
+    BtmIter<'a, Vec<u8>, Change>,
+>;
+
 /// Iterator over the `Changes` data.
 #[derive(Debug)]
 pub struct ChangesIterator {
+    prefixes: BtsIntoIter<Vec<u8>>,
     inner: BtmIntoIter<Vec<u8>, Change>,
 }
 
@@ -52,7 +184,10 @@ impl StdIterator for ChangesIterator {
     type Item = (Vec<u8>, Change);
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.inner.next()
+        match self.prefixes.next() {
+            Some(prefix) => Some((prefix, Change::DeletePrefix)),
+            None => self.inner.next(),
+        }
     }
 }
 
@@ -62,6 +197,7 @@ impl IntoIterator for Changes {
 
     fn into_iter(self) -> Self::IntoIter {
         Self::IntoIter {
+            prefixes: self.prefixes.into_iter(),
             inner: self.data.into_iter(),
         }
     }
@@ -75,34 +211,47 @@ pub struct Patch {
 
 impl Patch {
     /// Creates a new empty `Patch` instance.
-    fn new() -> Self {
+    pub(crate) fn new() -> Self {
         Self {
             changes: HashMap::new(),
         }
     }
 
     /// Returns changes for the given name.
-    fn changes(&self, name: &str) -> Option<&Changes> {
+    pub(crate) fn changes(&self, name: &str) -> Option<&Changes> {
         self.changes.get(name)
     }
 
     /// Returns a mutable reference to the changes corresponding to the `name`.
-    fn changes_mut(&mut self, name: &str) -> Option<&mut Changes> {
+    pub(crate) fn changes_mut(&mut self, name: &str) -> Option<&mut Changes> {
         self.changes.get_mut(name)
     }
 
     /// Gets the corresponding entry in the map by the given name for in-place manipulation.
-    fn changes_entry(&mut self, name: String) -> HmEntry<String, Changes> {
+    pub(crate) fn changes_entry(&mut self, name: String) -> HmEntry<'_, String, Changes> {
         self.changes.entry(name)
     }
 
     /// Inserts changes with the given name.
-    fn insert_changes(&mut self, name: String, changes: Changes) {
+    pub(crate) fn insert_changes(&mut self, name: String, changes: Changes) {
         self.changes.insert(name, changes);
     }
 
+    /// Applies the changes of `other` after all the changes of this patch.
+    pub(crate) fn merge(&mut self, other: Patch) {
+        for (name, changes) in other {
+            if let Some(in_changes) = self.changes_mut(&name) {
+                for (key, change) in changes {
+                    in_changes.insert(key, change);
+                }
+                continue;
+            }
+            self.insert_changes(name, changes);
+        }
+    }
+
     /// Returns iterator over changes.
-    pub fn iter(&self) -> HmIter<String, Changes> {
+    pub fn iter(&self) -> HmIter<'_, String, Changes> {
         self.changes.iter()
     }
 
@@ -110,7 +259,7 @@ impl Patch {
     pub fn len(&self) -> usize {
         self.changes
             .iter()
-            .fold(0, |acc, (_, changes)| acc + changes.data.len())
+            .fold(0, |acc, (_, changes)| acc + changes.len())
     }
 
     /// Returns `true` if this patch contains no changes and `false` otherwise.
@@ -145,7 +294,7 @@ impl IntoIterator for Patch {
 }
 
 /// A generalized iterator over the storage views.
-pub type Iter<'a> = Box<Iterator + 'a>;
+pub type Iter<'a> = Box<dyn Iterator + 'a>;
 
 /// An enum that represents a kind of change to some key in the storage.
 #[derive(Debug, Clone, PartialEq)]
@@ -154,6 +303,22 @@ pub enum Change {
     Put(Vec<u8>),
     /// Delete a value from the storage for the corresponding key.
     Delete,
+    /// Delete all values from the storage whose keys start with the corresponding key.
+    ///
+    /// Backends must apply these range tombstones before other changes of the same
+    /// column family.
+    DeletePrefix,
+}
+
+/// Returns the smallest key greater than all keys starting with the prefix, or `None`
+/// if there is no such key (the prefix is empty or consists of `0xff` bytes only).
+///
+/// Backends may use it as the exclusive upper bound of a `DeletePrefix` range.
+pub fn next_prefix(prefix: &[u8]) -> Option<Vec<u8>> {
+    let position = prefix.iter().rposition(|&byte| byte != u8::MAX)?;
+    let mut next = prefix[..=position].to_vec();
+    next[position] += 1;
+    Some(next)
 }
 
 /// A combination of a database snapshot and a sequence of changes on top of it.
@@ -192,15 +357,26 @@ pub enum Change {
 /// [`rollback`]: #method.rollback
 // FIXME: make &mut Fork "unwind safe" (ECR-176)
 pub struct Fork {
-    snapshot: Box<Snapshot>,
+    snapshot: Box<dyn Snapshot>,
     patch: Patch,
-    changelog: Vec<(String, Vec<u8>, Option<Change>)>,
+    changelog: Vec<ChangelogEntry>,
     logged: bool,
+    indexes: Option<Arc<SecondaryIndexes>>,
 }
 
-struct ForkIter<'a> {
+/// Record allowing to roll back a change of the fork.
+enum ChangelogEntry {
+    /// Previous change of the key, if any.
+    Key(String, Vec<u8>, Option<Change>),
+    /// Changes dropped by a removal of the keys with a prefix.
+    Prefix(String, RemovedPrefix),
+}
+
+/// Iterator over the entries of a storage view with changes applied on top of them.
+pub(crate) struct ForkIter<'a> {
     snapshot: Iter<'a>,
     changes: Option<Peekable<Range<'a, Vec<u8>, Change>>>,
+    removed: Option<&'a Changes>,
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -210,6 +386,7 @@ enum NextIterValue {
     Inserted,
     Deleted,
     MissDeleted,
+    Shadowed,
     Finished,
 }
 
@@ -226,11 +403,11 @@ enum NextIterValue {
 /// `merge` and `merge_sync` methods take a shared reference to the database (`&self`)
 /// rather than an exclusive one (`&mut self`). This means that the following code compiles:
 ///
-/// ```
+/// ```ignore
 /// use exonum::storage::{Database, MemoryDB};
 ///
 /// // not declared as `mut db`!
-/// let db: Box<Database> = Box::new(MemoryDB::new());
+/// let db: Box<dyn Database> = Box::new(MemoryDB::new());
 /// let mut fork = db.fork();
 /// fork.put("index_name", vec![1, 2, 3], vec![123]);
 /// db.merge(fork.into_patch()).unwrap();
@@ -242,7 +419,7 @@ enum NextIterValue {
 /// [interior-mut]: https://doc.rust-lang.org/book/second-edition/ch15-05-interior-mutability.html
 pub trait Database: Send + Sync + 'static {
     /// Creates a new snapshot of the database from its current state.
-    fn snapshot(&self) -> Box<Snapshot>;
+    fn snapshot(&self) -> Box<dyn Snapshot>;
 
     /// Creates a new fork of the database from its current state.
     fn fork(&self) -> Fork {
@@ -251,6 +428,7 @@ pub trait Database: Send + Sync + 'static {
             patch: Patch::new(),
             changelog: Vec::new(),
             logged: false,
+            indexes: None,
         }
     }
 
@@ -277,6 +455,25 @@ pub trait Database: Send + Sync + 'static {
     /// will be returned. In case of an error the method guarantees no changes were applied to
     /// the database.
     fn merge_sync(&self, patch: Patch) -> Result<()>;
+
+    /// Atomically applies a sequence of patches to the database, each on top of the
+    /// previous ones, taking them from `patches` one at a time.
+    ///
+    /// Default implementation combines the patches into one and calls
+    /// [`merge`](#tymethod.merge); backends are encouraged to override it so that only
+    /// their own representation of the combined changes is held in memory.
+    ///
+    /// # Errors
+    ///
+    /// Returns the first error yielded by `patches` or an error of the merge. In case of
+    /// an error the method guarantees no changes were applied to the database.
+    fn merge_patches(&self, patches: &mut dyn StdIterator<Item = Result<Patch>>) -> Result<()> {
+        let mut combined = Patch::new();
+        for patch in patches {
+            combined.merge(patch?);
+        }
+        self.merge(combined)
+    }
 }
 
 /// A read-only snapshot of a storage backend.
@@ -299,6 +496,14 @@ pub trait Snapshot: 'static {
         self.get(name, key).is_some()
     }
 
+    /// Returns values corresponding to the specified keys, in the same order as the keys.
+    ///
+    /// Default implementation calls [`get`](#tymethod.get) for every key; backends are
+    /// encouraged to override it with a batched lookup.
+    fn multi_get(&self, name: &str, keys: &[&[u8]]) -> Vec<Option<Vec<u8>>> {
+        keys.iter().map(|key| self.get(name, key)).collect()
+    }
+
     /// Returns an iterator over the entries of the snapshot in ascending order starting from
     /// the specified key. The iterator element type is `(&[u8], &[u8])`.
     fn iter<'a>(&'a self, name: &str, from: &[u8]) -> Iter<'a>;
@@ -315,40 +520,48 @@ pub trait Iterator {
 
 impl Snapshot for Fork {
     fn get(&self, name: &str, key: &[u8]) -> Option<Vec<u8>> {
-        if let Some(changes) = self.patch.changes(name) {
-            if let Some(change) = changes.data.get(key) {
-                match *change {
-                    Change::Put(ref v) => return Some(v.clone()),
-                    Change::Delete => return None,
-                }
-            }
+        match self.patch.changes(name).and_then(|c| c.lookup(key)) {
+            Some(value) => value.cloned(),
+            None => self.snapshot.get(name, key),
         }
-        self.snapshot.get(name, key)
     }
 
     fn contains(&self, name: &str, key: &[u8]) -> bool {
-        if let Some(changes) = self.patch.changes(name) {
-            if let Some(change) = changes.data.get(key) {
-                match *change {
-                    Change::Put(..) => return true,
-                    Change::Delete => return false,
+        match self.patch.changes(name).and_then(|c| c.lookup(key)) {
+            Some(value) => value.is_some(),
+            None => self.snapshot.contains(name, key),
+        }
+    }
+
+    fn multi_get(&self, name: &str, keys: &[&[u8]]) -> Vec<Option<Vec<u8>>> {
+        let changes = self.patch.changes(name);
+        let mut values = Vec::with_capacity(keys.len());
+        let mut missed = Vec::new();
+        for (i, key) in keys.iter().enumerate() {
+            match changes.and_then(|changes| changes.lookup(key)) {
+                Some(value) => values.push(value.cloned()),
+                None => {
+                    values.push(None);
+                    missed.push(i);
                 }
             }
         }
-        self.snapshot.contains(name, key)
+        if !missed.is_empty() {
+            let missed_keys = missed.iter().map(|&i| keys[i]).collect::<Vec<_>>();
+            let stored = self.snapshot.multi_get(name, &missed_keys);
+            for (i, value) in missed.into_iter().zip(stored) {
+                values[i] = value;
+            }
+        }
+        values
     }
 
     fn iter<'a>(&'a self, name: &str, from: &[u8]) -> Iter<'a> {
-        let range = (Included(from), Unbounded);
-        let changes = match self.patch.changes(name) {
-            Some(changes) => Some(changes.data.range::<[u8], _>(range).peekable()),
-            None => None,
-        };
-
-        Box::new(ForkIter {
-            snapshot: self.snapshot.iter(name, from),
-            changes,
-        })
+        Box::new(ForkIter::new(
+            self.snapshot.iter(name, from),
+            self.patch.changes(name),
+            from,
+        ))
     }
 }
 
@@ -389,77 +602,192 @@ impl Fork {
         if !self.logged {
             panic!("call rollback before checkpoint");
         }
-        for (name, k, c) in self.changelog.drain(..).rev() {
-            if let Some(changes) = self.patch.changes_mut(&name) {
-                match c {
-                    Some(change) => changes.data.insert(k, change),
-                    None => changes.data.remove(&k),
-                };
+        for entry in self.changelog.drain(..).rev() {
+            match entry {
+                ChangelogEntry::Key(name, k, c) => {
+                    if let Some(changes) = self.patch.changes_mut(&name) {
+                        match c {
+                            Some(change) => changes.data.insert(k, change),
+                            None => changes.data.remove(&k),
+                        };
+                    }
+                }
+                ChangelogEntry::Prefix(name, removed) => {
+                    if let Some(changes) = self.patch.changes_mut(&name) {
+                        changes.restore(removed);
+                    }
+                }
             }
         }
         self.logged = false;
     }
 
+    /// Attaches secondary indexes to the fork.
+    ///
+    /// Subsequent [`put`], [`remove`] and [`remove_by_prefix`] calls on a source column
+    /// family of any of the indexes also update the entries of these indexes.
+    ///
+    /// [`put`]: #method.put
+    /// [`remove`]: #method.remove
+    /// [`remove_by_prefix`]: #method.remove_by_prefix
+    pub fn set_secondary_indexes(&mut self, indexes: Arc<SecondaryIndexes>) {
+        self.indexes = Some(indexes);
+    }
+
     /// Inserts a key-value pair into the fork.
     pub fn put(&mut self, name: &str, key: Vec<u8>, value: Vec<u8>) {
-        let changes = self.patch
-            .changes_entry(name.to_string())
-            .or_insert_with(Changes::new);
-        if self.logged {
-            self.changelog.push((
-                name.to_string(),
-                key.clone(),
-                changes.data.insert(key, Change::Put(value)),
-            ));
-        } else {
-            changes.data.insert(key, Change::Put(value));
+        if let Some(indexes) = self.indexes_for(name) {
+            let old_value = self.get(name, &key);
+            for index in indexes.for_source(name) {
+                if let Some(old_value) = old_value.as_ref() {
+                    self.unindex(index, &key, old_value);
+                }
+                if let Some(entry) = index.entry_key(&key, &value) {
+                    self.write(index.name(), entry, Change::Put(key.clone()));
+                }
+            }
         }
+        self.write(name, key, Change::Put(value));
     }
 
     /// Removes the key from the fork.
     pub fn remove(&mut self, name: &str, key: Vec<u8>) {
+        if let Some(indexes) = self.indexes_for(name)
+            && let Some(old_value) = self.get(name, &key)
+        {
+            for index in indexes.for_source(name) {
+                self.unindex(index, &key, &old_value);
+            }
+        }
+        self.write(name, key, Change::Delete);
+    }
+
+    /// Inserts a key-value pair into the fork if the key is absent in the fork's view.
+    ///
+    /// Returns `true` if the pair is inserted, or `false` if the key already exists.
+    pub fn put_if_absent(&mut self, name: &str, key: Vec<u8>, value: Vec<u8>) -> bool {
+        if self.contains(name, &key) {
+            return false;
+        }
+        self.put(name, key, value);
+        true
+    }
+
+    /// Replaces the value of the key with `new` if the current value in the fork's view
+    /// equals `expected`, where `None` means that the key is absent.
+    ///
+    /// Returns `true` if the value is replaced, or `false` if the current value differs.
+    pub fn compare_and_swap(
+        &mut self,
+        name: &str,
+        key: Vec<u8>,
+        expected: Option<&[u8]>,
+        new: Vec<u8>,
+    ) -> bool {
+        if self.get(name, &key).as_deref() != expected {
+            return false;
+        }
+        self.put(name, key, new);
+        true
+    }
+
+    /// Adds `delta` to the `u64` counter stored under the key in the big-endian
+    /// `StorageKey` format and returns the new value. An absent counter is treated as zero.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `Corrupted` error if the stored value is not a `u64`, or an `Other` error
+    /// if the counter overflows. The fork is not changed in both cases.
+    pub fn increment(&mut self, name: &str, key: Vec<u8>, delta: u64) -> Result<u64> {
+        let current = match self.get(name, &key) {
+            Some(ref value) if value.len() == 8 => BigEndian::read_u64(value),
+            Some(value) => {
+                return Err(Error::Corrupted {
+                    name: name.to_string(),
+                    key,
+                    reason: format!("expected 8 bytes of a counter, got {}", value.len()),
+                });
+            }
+            None => 0,
+        };
+        let value = current
+            .checked_add(delta)
+            .ok_or_else(|| Error::new(format!("counter overflow in `{}`", name)))?;
+        let mut bytes = vec![0; 8];
+        BigEndian::write_u64(&mut bytes, value);
+        self.put(name, key, bytes);
+        Ok(value)
+    }
+
+    /// Removes all keys starting with the specified prefix from the column family
+    /// with the given `name`.
+    ///
+    /// The keys stored in the database are removed with a single range tombstone
+    /// ([`Change::DeletePrefix`]) rather than one change per key. Column families with
+    /// secondary indexes are the exception: their removed entries are visited to clean up
+    /// the indexes.
+    ///
+    /// [`Change::DeletePrefix`]: enum.Change.html#variant.DeletePrefix
+    pub fn remove_by_prefix(&mut self, name: &str, prefix: Option<&Vec<u8>>) {
+        if let Some(indexes) = self.indexes_for(name) {
+            let start = prefix.map_or(&[][..], |k| k.as_slice());
+            let entries = {
+                let mut entries = Vec::new();
+                let mut iter = self.iter(name, start);
+                while let Some((k, v)) = iter.next() {
+                    if !k.starts_with(start) {
+                        break;
+                    }
+                    entries.push((k.to_vec(), v.to_vec()));
+                }
+                entries
+            };
+            for (key, value) in entries {
+                for index in indexes.for_source(name) {
+                    self.unindex(index, &key, &value);
+                }
+            }
+        }
+
         let changes = self.patch
             .changes_entry(name.to_string())
             .or_insert_with(Changes::new);
+        let removed = changes.remove_prefix(prefix.cloned().unwrap_or_default());
         if self.logged {
-            self.changelog.push((
-                name.to_string(),
-                key.clone(),
-                changes.data.insert(key, Change::Delete),
-            ));
-        } else {
-            changes.data.insert(key, Change::Delete);
+            self.changelog.push(ChangelogEntry::Prefix(name.to_string(), removed));
         }
     }
 
-    /// Removes all keys starting with the specified prefix from the column family
-    /// with the given `name`.
-    pub fn remove_by_prefix(&mut self, name: &str, prefix: Option<&Vec<u8>>) {
+    /// Returns the attached secondary indexes if some of them are declared over
+    /// the column family with the given `name`.
+    fn indexes_for(&self, name: &str) -> Option<Arc<SecondaryIndexes>> {
+        self.indexes
+            .as_ref()
+            .filter(|indexes| indexes.has_source(name))
+            .cloned()
+    }
+
+    /// Removes the entry of the secondary index which corresponds to the given
+    /// key-value pair of the source column family.
+    fn unindex(&mut self, index: &SecondaryIndex, key: &[u8], value: &[u8]) {
+        if let Some(entry) = index.entry_key(key, value) {
+            self.write(index.name(), entry, Change::Delete);
+        }
+    }
+
+    /// Records the change of the key, bypassing secondary indexes.
+    fn write(&mut self, name: &str, key: Vec<u8>, change: Change) {
         let changes = self.patch
             .changes_entry(name.to_string())
             .or_insert_with(Changes::new);
-        // Remove changes
-        if let Some(prefix) = prefix {
-            let keys = changes
-                .data
-                .range::<Vec<u8>, _>((Included(prefix), Unbounded))
-                .map(|(k, _)| k.to_vec())
-                .take_while(|k| k.starts_with(prefix))
-                .collect::<Vec<_>>();
-            for k in keys {
-                changes.data.remove(&k);
-            }
+        if self.logged {
+            self.changelog.push(ChangelogEntry::Key(
+                name.to_string(),
+                key.clone(),
+                changes.data.insert(key, change),
+            ));
         } else {
-            changes.data.clear();
-        }
-        // Remove from storage
-        let mut iter = self.snapshot
-            .iter(name, prefix.map_or(&[], |k| k.as_slice()));
-        while let Some((k, ..)) = iter.next() {
-            let change = changes.data.insert(k.to_vec(), Change::Delete);
-            if self.logged {
-                self.changelog.push((name.to_string(), k.to_vec(), change));
-            }
+            changes.data.insert(key, change);
         }
     }
 
@@ -477,6 +805,7 @@ impl Fork {
     ///
     /// If both forks have changed the same data, this can lead to an inconsistent state. Hence,
     /// this method is useful only if you are sure that forks interacted with different indices.
+    /// Secondary indexes attached to this fork are not updated by the merged changes.
     ///
     /// # Panics
     ///
@@ -486,26 +815,18 @@ impl Fork {
             panic!("call merge before commit or rollback");
         }
 
-        for (name, changes) in patch {
-            if let Some(in_changes) = self.patch.changes_mut(&name) {
-                in_changes.data.extend(changes.into_iter());
-                continue;
-            }
-            {
-                self.patch.insert_changes(name.to_owned(), changes);
-            }
-        }
+        self.patch.merge(patch);
     }
 }
 
-impl AsRef<Snapshot> for Snapshot + 'static {
-    fn as_ref(&self) -> &Snapshot {
+impl AsRef<dyn Snapshot> for dyn Snapshot + 'static {
+    fn as_ref(&self) -> &dyn Snapshot {
         self
     }
 }
 
-impl AsRef<Snapshot> for Fork {
-    fn as_ref(&self) -> &Snapshot {
+impl AsRef<dyn Snapshot> for Fork {
+    fn as_ref(&self) -> &dyn Snapshot {
         self
     }
 }
@@ -517,7 +838,33 @@ impl ::std::fmt::Debug for Fork {
 }
 
 impl<'a> ForkIter<'a> {
+    /// Creates an iterator starting from the `from` key over the entries of the `snapshot`
+    /// iterator with the `changes` applied.
+    pub(crate) fn new(snapshot: Iter<'a>, changes: Option<&'a Changes>, from: &[u8]) -> Self {
+        let range = (Included(from), Unbounded);
+        Self {
+            snapshot,
+            changes: changes.map(|changes| changes.data.range::<[u8], _>(range).peekable()),
+            removed: changes,
+        }
+    }
+
     fn step(&mut self) -> NextIterValue {
+        match self.step_changes() {
+            Stored if self.is_shadowed() => Shadowed,
+            value => value,
+        }
+    }
+
+    /// Returns `true` if the next stored entry is removed by a range tombstone.
+    fn is_shadowed(&mut self) -> bool {
+        match (self.removed, self.snapshot.peek()) {
+            (Some(removed), Some((key, ..))) => removed.is_removed(key),
+            _ => false,
+        }
+    }
+
+    fn step_changes(&mut self) -> NextIterValue {
         if let Some(ref mut changes) = self.changes {
             match changes.peek() {
                 Some(&(k, change)) => match self.snapshot.peek() {
@@ -532,10 +879,12 @@ impl<'a> ForkIter<'a> {
                             Less => MissDeleted,
                             Greater => Stored,
                         },
+                        Change::DeletePrefix => unreachable!(),
                     },
                     None => match *change {
                         Change::Put(..) => Inserted,
                         Change::Delete => MissDeleted,
+                        Change::DeletePrefix => unreachable!(),
                     },
                 },
                 None => match self.snapshot.peek() {
@@ -564,7 +913,7 @@ impl<'a> Iterator for ForkIter<'a> {
                             key.as_slice(),
                             match *change {
                                 Change::Put(ref value) => value.as_slice(),
-                                Change::Delete => unreachable!(),
+                                Change::Delete | Change::DeletePrefix => unreachable!(),
                             },
                         )
                     });
@@ -575,7 +924,7 @@ impl<'a> Iterator for ForkIter<'a> {
                             key.as_slice(),
                             match *change {
                                 Change::Put(ref value) => value.as_slice(),
-                                Change::Delete => unreachable!(),
+                                Change::Delete | Change::DeletePrefix => unreachable!(),
                             },
                         )
                     })
@@ -587,6 +936,9 @@ impl<'a> Iterator for ForkIter<'a> {
                 MissDeleted => {
                     self.changes.as_mut().unwrap().next();
                 }
+                Shadowed => {
+                    self.snapshot.next();
+                }
                 Finished => return None,
             }
         }
@@ -602,7 +954,7 @@ impl<'a> Iterator for ForkIter<'a> {
                             key.as_slice(),
                             match *change {
                                 Change::Put(ref value) => value.as_slice(),
-                                Change::Delete => unreachable!(),
+                                Change::Delete | Change::DeletePrefix => unreachable!(),
                             },
                         )
                     })
@@ -614,14 +966,166 @@ impl<'a> Iterator for ForkIter<'a> {
                 MissDeleted => {
                     self.changes.as_mut().unwrap().next();
                 }
+                Shadowed => {
+                    self.snapshot.next();
+                }
                 Finished => return None,
             }
         }
     }
 }
 
-impl<T: Database> From<T> for Box<Database> {
+impl<T: Database> From<T> for Box<dyn Database> {
     fn from(db: T) -> Self {
-        Box::new(db) as Box<Database>
+        Box::new(db) as Box<dyn Database>
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::test_utils::{TestDB, entries};
+
+    #[test]
+    fn prefix_bounds() {
+        assert_eq!(next_prefix(&[1, 2]), Some(vec![1, 3]));
+        assert_eq!(next_prefix(&[1, 255, 255]), Some(vec![2]));
+        assert_eq!(next_prefix(&[255]), None);
+        assert_eq!(next_prefix(&[]), None);
+    }
+
+    #[test]
+    fn fork_multi_get() {
+        let db = TestDB::new();
+        let mut fork = db.fork();
+        fork.put("name", vec![1], vec![1]);
+        fork.put("name", vec![2], vec![2]);
+        fork.put("name", vec![3], vec![3]);
+        db.merge(fork.into_patch()).unwrap();
+
+        let mut fork = db.fork();
+        fork.put("name", vec![2], vec![20]);
+        fork.remove("name", vec![3]);
+        fork.put("name", vec![4], vec![4]);
+        assert_eq!(
+            fork.multi_get("name", &[&[1], &[2], &[3], &[4], &[5]]),
+            vec![Some(vec![1]), Some(vec![20]), None, Some(vec![4]), None]
+        );
+        assert_eq!(fork.multi_get("name", &[]), Vec::<Option<Vec<u8>>>::new());
+    }
+
+    #[test]
+    fn remove_by_prefix_tombstone() {
+        let db = TestDB::new();
+        let mut fork = db.fork();
+        for i in 0..100_u8 {
+            fork.put("name", vec![1, i], vec![i]);
+        }
+        fork.put("name", vec![0], vec![0]);
+        fork.put("name", vec![2], vec![2]);
+        db.merge(fork.into_patch()).unwrap();
+
+        let mut fork = db.fork();
+        fork.put("name", vec![1, 200], vec![200]);
+        fork.remove_by_prefix("name", Some(&vec![1]));
+        fork.put("name", vec![1, 5], vec![50]);
+        assert_eq!(fork.get("name", &[1, 4]), None);
+        assert!(!fork.contains("name", &[1, 200]));
+        assert_eq!(fork.get("name", &[1, 5]), Some(vec![50]));
+        assert_eq!(
+            fork.multi_get("name", &[&[0], &[1, 1], &[1, 5], &[2]]),
+            vec![Some(vec![0]), None, Some(vec![50]), Some(vec![2])]
+        );
+        let expected = vec![
+            (vec![0], vec![0]),
+            (vec![1, 5], vec![50]),
+            (vec![2], vec![2]),
+        ];
+        assert_eq!(entries(&fork, "name"), expected);
+
+        let patch = fork.into_patch();
+        // One tombstone and one put instead of a change per removed key.
+        assert_eq!(patch.len(), 2);
+        db.merge(patch).unwrap();
+        assert_eq!(entries(&*db.snapshot(), "name"), expected);
+    }
+
+    #[test]
+    fn remove_by_prefix_rollback() {
+        let db = TestDB::new();
+        let mut fork = db.fork();
+        fork.put("name", vec![1, 1], vec![1]);
+        fork.put("name", vec![1, 2], vec![2]);
+        db.merge(fork.into_patch()).unwrap();
+
+        let mut fork = db.fork();
+        fork.put("name", vec![1, 3], vec![3]);
+        fork.remove_by_prefix("name", Some(&vec![2]));
+        fork.checkpoint();
+        fork.remove_by_prefix("name", Some(&vec![1]));
+        fork.put("name", vec![1, 4], vec![4]);
+        fork.remove_by_prefix("name", None);
+        fork.put("name", vec![2, 1], vec![5]);
+        assert_eq!(entries(&fork, "name"), vec![(vec![2, 1], vec![5])]);
+        fork.rollback();
+        assert!(fork.patch().changes("name").unwrap().is_removed(&[2, 1]));
+        assert_eq!(
+            entries(&fork, "name"),
+            vec![
+                (vec![1, 1], vec![1]),
+                (vec![1, 2], vec![2]),
+                (vec![1, 3], vec![3]),
+            ]
+        );
+    }
+
+    #[test]
+    fn merge_forks_with_tombstones() {
+        let db = TestDB::new();
+        let mut fork = db.fork();
+        fork.put("name", vec![1, 1], vec![1]);
+        fork.put("name", vec![2, 1], vec![2]);
+        db.merge(fork.into_patch()).unwrap();
+
+        let mut other = db.fork();
+        other.remove_by_prefix("name", Some(&vec![1]));
+        other.put("name", vec![1, 2], vec![3]);
+        let mut fork = db.fork();
+        fork.put("name", vec![1, 3], vec![4]);
+        fork.merge(other.into_patch());
+        assert_eq!(
+            entries(&fork, "name"),
+            vec![(vec![1, 2], vec![3]), (vec![2, 1], vec![2])]
+        );
+    }
+
+    #[test]
+    fn conditional_writes() {
+        let db = TestDB::new();
+        let mut fork = db.fork();
+        fork.put("name", vec![1], vec![1]);
+        db.merge(fork.into_patch()).unwrap();
+
+        let mut fork = db.fork();
+        assert!(!fork.put_if_absent("name", vec![1], vec![10]));
+        assert!(fork.put_if_absent("name", vec![2], vec![2]));
+        assert!(!fork.put_if_absent("name", vec![2], vec![20]));
+        assert_eq!(fork.get("name", &[2]), Some(vec![2]));
+
+        assert!(!fork.compare_and_swap("name", vec![1], Some(&[2]), vec![10]));
+        assert!(fork.compare_and_swap("name", vec![1], Some(&[1]), vec![10]));
+        assert!(!fork.compare_and_swap("name", vec![3], Some(&[1]), vec![3]));
+        assert!(fork.compare_and_swap("name", vec![3], None, vec![3]));
+        fork.remove_by_prefix("name", None);
+        assert!(fork.compare_and_swap("name", vec![1], None, vec![1]));
+
+        assert_eq!(fork.increment("counters", vec![1], 5).unwrap(), 5);
+        assert_eq!(fork.increment("counters", vec![1], 2).unwrap(), 7);
+        assert_eq!(fork.get("counters", &[1]), Some(7_u64.to_be_bytes().to_vec()));
+        fork.put("counters", vec![2], vec![1]);
+        assert!(fork.increment("counters", vec![2], 1).is_err());
+        fork.put("counters", vec![3], u64::MAX.to_be_bytes().to_vec());
+        assert!(fork.increment("counters", vec![3], 1).is_err());
+        assert_eq!(fork.get("counters", &[3]), Some(u64::MAX.to_be_bytes().to_vec()));
     }
 }
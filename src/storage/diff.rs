@@ -0,0 +1,102 @@
+//! Computing the difference between two snapshots as a `Patch`.
+
+use std::cmp::Ordering;
+
+use super::db::{Change, Changes, Patch, Snapshot};
+
+/// Returns a patch which turns the state of the snapshot `a` into the state of
+/// the snapshot `b` for the column families with the given `names`.
+///
+/// Column families which are equal in both snapshots are not included into the patch.
+pub fn diff(a: &dyn Snapshot, b: &dyn Snapshot, names: &[&str]) -> Patch {
+    let mut patch = Patch::new();
+    for name in names {
+        let changes = diff_changes(a, b, name);
+        if !changes.data.is_empty() {
+            patch.insert_changes(name.to_string(), changes);
+        }
+    }
+    patch
+}
+
+fn diff_changes(a: &dyn Snapshot, b: &dyn Snapshot, name: &str) -> Changes {
+    let mut changes = Changes::new();
+    let mut iter_a = a.iter(name, &[]);
+    let mut iter_b = b.iter(name, &[]);
+    loop {
+        // Each step advances either of the iterators or both of them.
+        let (next_a, next_b, change) = match (iter_a.peek(), iter_b.peek()) {
+            (None, None) => break,
+            (Some((key_a, _)), None) => (true, false, Some((key_a.to_vec(), Change::Delete))),
+            (None, Some((key_b, value_b))) => (
+                false,
+                true,
+                Some((key_b.to_vec(), Change::Put(value_b.to_vec()))),
+            ),
+            (Some((key_a, value_a)), Some((key_b, value_b))) => match key_a.cmp(key_b) {
+                Ordering::Less => (true, false, Some((key_a.to_vec(), Change::Delete))),
+                Ordering::Greater => (
+                    false,
+                    true,
+                    Some((key_b.to_vec(), Change::Put(value_b.to_vec()))),
+                ),
+                Ordering::Equal if value_a == value_b => (true, true, None),
+                Ordering::Equal => (
+                    true,
+                    true,
+                    Some((key_b.to_vec(), Change::Put(value_b.to_vec()))),
+                ),
+            },
+        };
+        if next_a {
+            iter_a.next();
+        }
+        if next_b {
+            iter_b.next();
+        }
+        if let Some((key, change)) = change {
+            changes.data.insert(key, change);
+        }
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::db::Database;
+    use crate::storage::test_utils::{TestDB, entries};
+
+    #[test]
+    fn diff_turns_a_into_b() {
+        let db_a = TestDB::new();
+        let mut fork = db_a.fork();
+        fork.put("accounts", vec![1], vec![1]);
+        fork.put("accounts", vec![2], vec![2]);
+        fork.put("accounts", vec![4], vec![4]);
+        fork.put("blocks", vec![1], vec![1]);
+        db_a.merge(fork.into_patch()).unwrap();
+
+        let db_b = TestDB::new();
+        let mut fork = db_b.fork();
+        fork.put("accounts", vec![2], vec![20]);
+        fork.put("accounts", vec![3], vec![3]);
+        fork.put("accounts", vec![4], vec![4]);
+        fork.put("blocks", vec![1], vec![1]);
+        fork.put("headers", vec![1], vec![1]);
+        db_b.merge(fork.into_patch()).unwrap();
+
+        let (a, b) = (db_a.snapshot(), db_b.snapshot());
+        let names = ["accounts", "blocks", "headers"];
+        let patch = diff(&*a, &*b, &names);
+        assert_eq!(patch.len(), 4);
+        assert!(patch.changes("blocks").is_none());
+
+        db_a.merge(patch).unwrap();
+        let a = db_a.snapshot();
+        for name in &names {
+            assert_eq!(entries(&*a, name), entries(&*b, name));
+        }
+        assert!(diff(&*a, &*b, &names).is_empty());
+    }
+}
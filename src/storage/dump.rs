@@ -0,0 +1,206 @@
+//! Human-readable and JSON dumps of `Patch` and `Changes` for debugging.
+//!
+//! Keys are printed as `0x`-prefixed hex. Values of column families with a registered decoder
+//! are printed as decoded JSON; other values are printed as `0x`-prefixed hex as well. In JSON,
+//! such raw values are wrapped into `{"raw": "0x…"}` objects, so they cannot be confused with
+//! decoded strings.
+//!
+//! ```ignore
+//! let mut printer = PatchPrinter::new();
+//! printer.register_value::<u64>("balances");
+//! println!("{}", printer.display(&patch));
+//! let json = printer.to_json(&patch);
+//! ```
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use super::db::{Change, Changes, Patch};
+use super::values::StorageValue;
+use crate::common::to_hex;
+
+type Decoder = dyn Fn(&[u8]) -> Option<Value> + Send + Sync;
+
+/// Printer of patches with per-column-family value decoders.
+#[derive(Default)]
+pub struct PatchPrinter {
+    decoders: HashMap<String, Box<Decoder>>,
+}
+
+/// Text representation of a patch, created by [`PatchPrinter::display`].
+///
+/// [`PatchPrinter::display`]: struct.PatchPrinter.html#method.display
+pub struct PatchDisplay<'a> {
+    printer: &'a PatchPrinter,
+    patch: &'a Patch,
+}
+
+impl PatchPrinter {
+    /// Creates a printer without decoders.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a decoder for values of the column family with the given `name`.
+    ///
+    /// The decoder returns `None` if the value cannot be decoded; such values are printed
+    /// in hex.
+    pub fn register<F>(&mut self, name: &str, decoder: F) -> &mut Self
+    where
+        F: Fn(&[u8]) -> Option<Value> + Send + Sync + 'static,
+    {
+        self.decoders.insert(name.to_string(), Box::new(decoder));
+        self
+    }
+
+    /// Registers a decoder for values of the column family with the given `name`
+    /// stored as `V`.
+    pub fn register_value<V>(&mut self, name: &str) -> &mut Self
+    where
+        V: StorageValue + Serialize,
+    {
+        self.register(name, |bytes| {
+            V::try_from_bytes(Cow::Borrowed(bytes))
+                .ok()
+                .and_then(|value| serde_json::to_value(value).ok())
+        })
+    }
+
+    /// Returns the value decoded for printing, or a `{"raw": "0x…"}` object if the value
+    /// cannot be decoded.
+    pub fn decode(&self, name: &str, value: &[u8]) -> Value {
+        self.try_decode(name, value)
+            .unwrap_or_else(|| json!({ "raw": format!("0x{}", to_hex(value)) }))
+    }
+
+    fn try_decode(&self, name: &str, value: &[u8]) -> Option<Value> {
+        self.decoders.get(name).and_then(|decoder| decoder(value))
+    }
+
+    /// Returns the JSON representation of changes in the column family with the given `name`.
+    ///
+    /// Each change is represented as an object with `key`, `op` (`put`, `delete` or
+    /// `delete_prefix`) and, for puts, `value` fields.
+    pub fn changes_to_json(&self, name: &str, changes: &Changes) -> Value {
+        let changes = changes
+            .iter()
+            .map(|(key, change)| {
+                let mut object = Map::new();
+                object.insert("key".to_string(), json!(format!("0x{}", to_hex(key))));
+                match *change {
+                    Change::Put(ref value) => {
+                        object.insert("op".to_string(), json!("put"));
+                        object.insert("value".to_string(), self.decode(name, value));
+                    }
+                    Change::Delete => {
+                        object.insert("op".to_string(), json!("delete"));
+                    }
+                    Change::DeletePrefix => {
+                        object.insert("op".to_string(), json!("delete_prefix"));
+                    }
+                }
+                Value::Object(object)
+            })
+            .collect();
+        Value::Array(changes)
+    }
+
+    /// Returns the JSON representation of the patch as an object keyed by column family names.
+    pub fn to_json(&self, patch: &Patch) -> Value {
+        let object = patch
+            .iter()
+            .map(|(name, changes)| (name.clone(), self.changes_to_json(name, changes)))
+            .collect::<Map<_, _>>();
+        Value::Object(object)
+    }
+
+    /// Returns the text representation of the patch.
+    pub fn display<'a>(&'a self, patch: &'a Patch) -> PatchDisplay<'a> {
+        PatchDisplay {
+            printer: self,
+            patch,
+        }
+    }
+
+    fn fmt_changes(&self, f: &mut fmt::Formatter, name: &str, changes: &Changes) -> fmt::Result {
+        writeln!(f, "{}:", name)?;
+        for (key, change) in changes.iter() {
+            match *change {
+                Change::Put(ref value) => match self.try_decode(name, value) {
+                    Some(decoded) => writeln!(f, "  + 0x{} = {}", to_hex(key), decoded)?,
+                    None => writeln!(f, "  + 0x{} = 0x{}", to_hex(key), to_hex(value))?,
+                },
+                Change::Delete => writeln!(f, "  - 0x{}", to_hex(key))?,
+                Change::DeletePrefix => writeln!(f, "  - 0x{}*", to_hex(key))?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for PatchPrinter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PatchPrinter")
+            .field("decoders", &self.decoders.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl<'a> fmt::Display for PatchDisplay<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut names = self.patch.iter().collect::<Vec<_>>();
+        names.sort_by(|a, b| a.0.cmp(b.0));
+        for (name, changes) in names {
+            self.printer.fmt_changes(f, name, changes)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::db::Database;
+    use crate::storage::test_utils::TestDB;
+
+    fn patch() -> Patch {
+        let db = TestDB::new();
+        let mut fork = db.fork();
+        fork.put("balances", vec![1], 10_u64.into_bytes());
+        fork.remove("balances", vec![2]);
+        fork.put("blobs", vec![0xab], vec![0xff, 0]);
+        fork.into_patch()
+    }
+
+    #[test]
+    fn text_dump() {
+        let mut printer = PatchPrinter::new();
+        printer.register_value::<u64>("balances");
+        let patch = patch();
+        assert_eq!(
+            printer.display(&patch).to_string(),
+            "balances:\n  + 0x01 = 10\n  - 0x02\nblobs:\n  + 0xab = 0xff00\n"
+        );
+    }
+
+    #[test]
+    fn json_dump() {
+        let mut printer = PatchPrinter::new();
+        printer.register_value::<u64>("balances");
+        let patch = patch();
+        assert_eq!(
+            printer.to_json(&patch),
+            json!({
+                "balances": [
+                    { "key": "0x01", "op": "put", "value": 10 },
+                    { "key": "0x02", "op": "delete" },
+                ],
+                "blobs": [{ "key": "0xab", "op": "put", "value": { "raw": "0xff00" } }],
+            })
+        );
+    }
+}
@@ -1,13 +1,224 @@
-#[derive(Fail, Debug, Clone)]
-#[fail(display = "{}", message)]
-pub struct Error {
-    message: String,
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+
+use failure::Fail;
+
+use crate::common::to_hex;
+
+/// An error that can occur while interacting with storage.
+///
+/// Errors related to a particular entry carry the column family `name` and the raw `key`
+/// of that entry. Underlying errors are shared, so errors can be cloned cheaply.
+#[derive(Debug, Clone)]
+pub enum Error {
+    /// The requested entry does not exist.
+    NotFound {
+        /// Name of the column family.
+        name: String,
+        /// Key of the entry.
+        key: Vec<u8>,
+    },
+    /// The stored value is malformed on the storage level (e.g. has a broken encoding).
+    Corrupted {
+        /// Name of the column family.
+        name: String,
+        /// Key of the entry.
+        key: Vec<u8>,
+        /// Description of the corruption.
+        reason: String,
+    },
+    /// The stored value cannot be decoded into the requested type.
+    Decode {
+        /// Name of the column family.
+        name: String,
+        /// Key of the entry.
+        key: Vec<u8>,
+        /// Error returned by the decoder.
+        cause: Arc<::failure::Error>,
+    },
+    /// An I/O error of the storage backend.
+    Io {
+        /// Description of the failed operation.
+        context: String,
+        /// Underlying I/O error.
+        cause: Arc<io::Error>,
+    },
+    /// The patch conflicts with the current state of the storage.
+    MergeConflict {
+        /// Name of the column family.
+        name: String,
+        /// Key of the conflicting entry.
+        key: Vec<u8>,
+    },
+    /// The name of a column family does not follow the naming rules.
+    InvalidName {
+        /// The rejected name.
+        name: String,
+    },
+    /// The column family has been written as an index of a different type.
+    IndexTypeMismatch {
+        /// Name of the column family.
+        name: String,
+        /// Description of the requested index type.
+        expected: String,
+        /// Description of the stored index type.
+        actual: String,
+    },
+    /// A replication entry does not directly follow the last applied one.
+    ReplicationGap {
+        /// Sequence number of the expected entry.
+        expected: u64,
+        /// Sequence number of the received entry.
+        actual: u64,
+    },
+    /// A replication entry is not chained to the last applied one or its hash is wrong.
+    ReplicationMismatch {
+        /// Sequence number of the rejected entry.
+        seq: u64,
+    },
+    /// The storage backend has been closed.
+    Closed,
+    /// Any other error of the storage backend.
+    Other(String),
 }
 
 impl Error {
+    /// Creates an error of the `Other` kind with the given message.
     pub fn new<T: Into<String>>(message: T) -> Error {
-        Error {
-            message: message.into(),
+        Error::Other(message.into())
+    }
+
+    /// Creates an I/O error with the description of the failed operation.
+    pub fn io<T: Into<String>>(context: T, cause: io::Error) -> Error {
+        Error::Io {
+            context: context.into(),
+            cause: Arc::new(cause),
+        }
+    }
+
+    /// Returns the name of the column family the error relates to, if any.
+    pub fn name(&self) -> Option<&str> {
+        match *self {
+            Error::NotFound { ref name, .. }
+            | Error::Corrupted { ref name, .. }
+            | Error::Decode { ref name, .. }
+            | Error::MergeConflict { ref name, .. }
+            | Error::InvalidName { ref name }
+            | Error::IndexTypeMismatch { ref name, .. } => Some(name),
+            _ => None,
+        }
+    }
+
+    /// Returns the key of the entry the error relates to, if any.
+    pub fn key(&self) -> Option<&[u8]> {
+        match *self {
+            Error::NotFound { ref key, .. }
+            | Error::Corrupted { ref key, .. }
+            | Error::Decode { ref key, .. }
+            | Error::MergeConflict { ref key, .. } => Some(key),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::NotFound { ref name, ref key } => {
+                write!(f, "key 0x{} not found in `{}`", to_hex(key), name)
+            }
+            Error::Corrupted {
+                ref name,
+                ref key,
+                ref reason,
+            } => write!(
+                f,
+                "corrupted value of key 0x{} in `{}`: {}",
+                to_hex(key),
+                name,
+                reason
+            ),
+            Error::Decode {
+                ref name,
+                ref key,
+                ref cause,
+            } => write!(
+                f,
+                "cannot decode value of key 0x{} in `{}`: {}",
+                to_hex(key),
+                name,
+                cause
+            ),
+            Error::Io {
+                ref context,
+                ref cause,
+            } => write!(f, "I/O error while {}: {}", context, cause),
+            Error::MergeConflict { ref name, ref key } => {
+                write!(f, "merge conflict on key 0x{} in `{}`", to_hex(key), name)
+            }
+            Error::InvalidName { ref name } => write!(f, "invalid index name `{}`", name),
+            Error::IndexTypeMismatch {
+                ref name,
+                ref expected,
+                ref actual,
+            } => write!(
+                f,
+                "index `{}` is opened as {}, but stored as {}",
+                name, expected, actual
+            ),
+            Error::ReplicationGap { expected, actual } => write!(
+                f,
+                "replication entry {} received while entry {} is expected",
+                actual, expected
+            ),
+            Error::ReplicationMismatch { seq } => {
+                write!(f, "replication entry {} does not extend the log", seq)
+            }
+            Error::Closed => write!(f, "storage is closed"),
+            Error::Other(ref message) => write!(f, "{}", message),
         }
     }
-}
\ No newline at end of file
+}
+
+impl Fail for Error {
+    fn cause(&self) -> Option<&dyn Fail> {
+        match *self {
+            Error::Decode { ref cause, .. } => Some(cause.as_fail()),
+            Error::Io { ref cause, .. } => Some(&**cause),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(cause: io::Error) -> Error {
+        Error::io("accessing storage", cause)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn context() {
+        let err = Error::NotFound {
+            name: "accounts".to_string(),
+            key: vec![1, 255],
+        };
+        assert_eq!(err.name(), Some("accounts"));
+        assert_eq!(err.key(), Some(&[1_u8, 255][..]));
+        assert_eq!(err.to_string(), "key 0x01ff not found in `accounts`");
+
+        let err = Error::io("syncing log", io::Error::other("disk full"));
+        assert_eq!(err.name(), None);
+        assert_eq!(err.to_string(), "I/O error while syncing log: disk full");
+        assert_eq!(err.cause().unwrap().to_string(), "disk full");
+        assert_eq!(err.clone().to_string(), err.to_string());
+        match err {
+            Error::Io { ref cause, .. } => assert_eq!(cause.kind(), io::ErrorKind::Other),
+            _ => panic!("unexpected error kind"),
+        }
+    }
+}
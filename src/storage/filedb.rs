@@ -0,0 +1,647 @@
+//! A durable `Database` persisting to a local directory.
+//!
+//! The content of the database is kept in memory (see [`MemoryDB`]) and persisted as
+//! a sorted data file plus a write-ahead log of the patches merged since the data file
+//! was written. Both files carry a generation number:
+//!
+//! - `data-<gen>` holds the whole content of the database in ascending order of column
+//!   family names and keys, followed by a checksum of the file;
+//! - `wal-<gen>` holds the records (see the [`wal`] module) of the patches merged on top
+//!   of `data-<gen>`.
+//!
+//! `merge` appends the patch to the log before applying it, and `merge_sync` additionally
+//! flushes the log to disk before returning. If the append fails, the log is truncated back
+//! to its last complete record, so later records are not lost behind a torn one; if even
+//! the truncation fails, the database refuses all further writes. `merge_patches` logs all
+//! its patches as a single record, so they are recovered all or not at all.
+//!
+//! Once the log grows beyond [`FileDBOptions::wal_size_limit`], the content is compacted
+//! into `data-<gen + 1>` and a new empty log is started. Compaction runs after the patch
+//! is committed, so its failure does not fail the merge: it is retried on the next write
+//! and reported by [`FileDB::compaction_error`]. Files are replaced atomically with
+//! a rename, so a crash at any point leaves a complete data file of some generation.
+//!
+//! On open, the newest data file is loaded and its log is replayed up to the last fully
+//! written record; a torn tail left by a crash is truncated.
+//!
+//! [`MemoryDB`]: ../memorydb/struct.MemoryDB.html
+//! [`wal`]: ../wal/index.html
+//! [`FileDBOptions::wal_size_limit`]: struct.FileDBOptions.html#structfield.wal_size_limit
+//! [`FileDB::compaction_error`]: struct.FileDB.html#method.compaction_error
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::iter::{self, Iterator as StdIterator};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use super::db::{Database, Patch, Snapshot};
+use super::memorydb::{Families, Family, MemoryDB};
+use super::wal::{self, read_bytes, write_bytes};
+use super::{Error, Result};
+
+const DATA_PREFIX: &str = "data-";
+const WAL_PREFIX: &str = "wal-";
+pub(crate) const TMP_SUFFIX: &str = ".tmp";
+pub(crate) const DATA_MAGIC: &[u8; 8] = b"CKITDATA";
+
+/// Options of the `FileDB`.
+#[derive(Debug, Clone)]
+pub struct FileDBOptions {
+    /// Size of the write-ahead log in bytes after which the content is compacted into
+    /// a new data file. Default value is 64 MiB.
+    pub wal_size_limit: u64,
+    /// Whether `merge` should flush the log to disk as `merge_sync` does.
+    /// Default value is `false`.
+    pub sync_on_merge: bool,
+}
+
+impl Default for FileDBOptions {
+    fn default() -> Self {
+        Self {
+            wal_size_limit: 64 << 20,
+            sync_on_merge: false,
+        }
+    }
+}
+
+/// Database persisting its content to a local directory.
+pub struct FileDB {
+    dir: PathBuf,
+    options: FileDBOptions,
+    memory: MemoryDB,
+    wal: Mutex<Wal>,
+}
+
+struct Wal {
+    file: File,
+    generation: u64,
+    // Size of the complete records in the log.
+    size: u64,
+    // Whether the log may end with a torn record which could not be truncated.
+    poisoned: bool,
+    compaction_error: Option<Error>,
+}
+
+impl FileDB {
+    /// Opens the database in the given directory, creating the directory if it does
+    /// not exist, and recovers its content.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `Io` error if the files cannot be accessed or the data file is corrupted.
+    pub fn open<P: AsRef<Path>>(dir: P, options: FileDBOptions) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)
+            .map_err(|e| Error::io(format!("creating {}", dir.display()), e))?;
+
+        let generation = cleanup(&dir)?;
+        let families = match generation {
+            Some(generation) => read_data(&data_path(&dir, generation))?,
+            None => Families::new(),
+        };
+        let generation = generation.unwrap_or_default();
+        let memory = MemoryDB::with_families(families);
+        for patch in replay(&wal_path(&dir, generation))? {
+            memory.merge(patch)?;
+        }
+        let wal = Wal::open(&dir, generation)?;
+        Ok(Self {
+            dir,
+            options,
+            memory,
+            wal: Mutex::new(wal),
+        })
+    }
+
+    /// Returns the directory of the database.
+    pub fn path(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Writes the whole content into a new data file and starts a new empty log.
+    ///
+    /// Writers are blocked during compaction; snapshots can still be taken and read.
+    pub fn compact(&self) -> Result<()> {
+        let mut wal = self.wal.lock().unwrap();
+        let result = self.compact_locked(&mut wal);
+        wal.compaction_error = result.clone().err();
+        result
+    }
+
+    /// Returns the error of the last automatic compaction if it has failed. The compaction
+    /// is retried on the next write.
+    pub fn compaction_error(&self) -> Option<Error> {
+        self.wal.lock().unwrap().compaction_error.clone()
+    }
+
+    fn compact_locked(&self, wal: &mut Wal) -> Result<()> {
+        let generation = wal.generation + 1;
+        // The new log is created first: until the new data file appears, it is ignored
+        // on open, and once it does, the log is ready to take the following patches.
+        let new_wal = Wal::open(&self.dir, generation)?;
+        write_data(&data_path(&self.dir, generation), &self.memory.families())?;
+        // The renamed data file takes over the current log on open, so the following
+        // patches must go to the new log even if the rest of the compaction fails.
+        *wal = new_wal;
+        sync_dir(&self.dir)?;
+        cleanup(&self.dir)?;
+        Ok(())
+    }
+
+    fn write(&self, patches: &mut dyn StdIterator<Item = Result<Patch>>, sync: bool) -> Result<()> {
+        let mut wal = self.wal.lock().unwrap();
+        // The patches are applied to a copy of the content, which is published only once
+        // they are all logged as a single record.
+        self.memory.update(|families| {
+            let mut payload = Vec::new();
+            for patch in patches {
+                let patch = patch?;
+                payload.extend(wal::encode_patch(&patch));
+                MemoryDB::apply(families, patch);
+            }
+            wal.append(&payload, sync)
+        })?;
+        if wal.size >= self.options.wal_size_limit {
+            // The patch is already committed, so a failed compaction is only recorded.
+            // Compaction either leaves the current log in use or switches to the new one
+            // before failing, so the log always matches the newest data file.
+            wal.compaction_error = self.compact_locked(&mut wal).err();
+        }
+        Ok(())
+    }
+}
+
+impl Database for FileDB {
+    fn snapshot(&self) -> Box<dyn Snapshot> {
+        self.memory.snapshot()
+    }
+
+    fn merge(&self, patch: Patch) -> Result<()> {
+        self.write(&mut iter::once(Ok(patch)), self.options.sync_on_merge)
+    }
+
+    fn merge_sync(&self, patch: Patch) -> Result<()> {
+        self.write(&mut iter::once(Ok(patch)), true)
+    }
+
+    fn merge_patches(&self, patches: &mut dyn StdIterator<Item = Result<Patch>>) -> Result<()> {
+        self.write(patches, self.options.sync_on_merge)
+    }
+}
+
+impl Wal {
+    fn open(dir: &Path, generation: u64) -> Result<Self> {
+        let path = wal_path(dir, generation);
+        let context = || format!("opening {}", path.display());
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|e| Error::io(context(), e))?;
+        let size = file.metadata().map_err(|e| Error::io(context(), e))?.len();
+        sync_dir(dir)?;
+        Ok(Self {
+            file,
+            generation,
+            size,
+            poisoned: false,
+            compaction_error: None,
+        })
+    }
+
+    fn append(&mut self, payload: &[u8], sync: bool) -> Result<()> {
+        if self.poisoned {
+            return Err(Error::new(
+                "write-ahead log is poisoned by a failed write, the database must be reopened",
+            ));
+        }
+        let mut record = Vec::with_capacity(wal::HEADER_SIZE as usize + payload.len());
+        wal::write_record(&mut record, payload).unwrap();
+        let mut write = || -> io::Result<()> {
+            self.file.write_all(&record)?;
+            if sync {
+                self.file.sync_data()?;
+            }
+            Ok(())
+        };
+        if let Err(e) = write() {
+            // Drop the torn or unsynced record, so that it is neither replayed for
+            // the failed merge nor hides the records appended after it.
+            if self.file.set_len(self.size).is_err() {
+                self.poisoned = true;
+            }
+            return Err(Error::io("appending to the write-ahead log", e));
+        }
+        self.size += record.len() as u64;
+        Ok(())
+    }
+}
+
+pub(crate) fn data_path(dir: &Path, generation: u64) -> PathBuf {
+    dir.join(format!("{}{:020}", DATA_PREFIX, generation))
+}
+
+fn wal_path(dir: &Path, generation: u64) -> PathBuf {
+    dir.join(format!("{}{:020}", WAL_PREFIX, generation))
+}
+
+fn generation(file_name: &str, prefix: &str) -> Option<u64> {
+    file_name
+        .strip_prefix(prefix)
+        .and_then(|generation| generation.parse().ok())
+}
+
+/// Removes unfinished temporary files and files of the previous generations.
+/// Returns the generation of the newest data file.
+fn cleanup(dir: &Path) -> Result<Option<u64>> {
+    let context = || format!("listing {}", dir.display());
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir).map_err(|e| Error::io(context(), e))? {
+        let entry = entry.map_err(|e| Error::io(context(), e))?;
+        if let Ok(name) = entry.file_name().into_string() {
+            files.push(name);
+        }
+    }
+    let current = files
+        .iter()
+        .filter_map(|name| generation(name, DATA_PREFIX))
+        .max();
+    for name in files {
+        let stale = name.ends_with(TMP_SUFFIX)
+            || generation(&name, DATA_PREFIX).is_some_and(|g| Some(g) < current)
+            || generation(&name, WAL_PREFIX).is_some_and(|g| g < current.unwrap_or_default());
+        if stale {
+            let path = dir.join(&name);
+            fs::remove_file(&path)
+                .map_err(|e| Error::io(format!("removing {}", path.display()), e))?;
+        }
+    }
+    Ok(current)
+}
+
+/// Reads the patches of the log, truncating the log after the last complete record.
+fn replay(path: &Path) -> Result<Vec<Patch>> {
+    let context = || format!("replaying {}", path.display());
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(Error::io(context(), e)),
+    };
+    let mut reader = BufReader::new(file);
+    let mut patches = Vec::new();
+    let mut valid = 0;
+    loop {
+        match wal::read_record(&mut reader) {
+            Ok(Some(payload)) => {
+                let patch = wal::decode_patch(&payload).map_err(|e| Error::io(context(), e))?;
+                valid += wal::HEADER_SIZE + payload.len() as u64;
+                patches.push(patch);
+            }
+            Ok(None) => break,
+            // The record was being written when the process stopped.
+            Err(ref e)
+                if e.kind() == io::ErrorKind::UnexpectedEof
+                    || e.kind() == io::ErrorKind::InvalidData =>
+            {
+                let file = OpenOptions::new()
+                    .write(true)
+                    .open(path)
+                    .map_err(|e| Error::io(context(), e))?;
+                file.set_len(valid)
+                    .and_then(|()| file.sync_all())
+                    .map_err(|e| Error::io(context(), e))?;
+                break;
+            }
+            Err(e) => return Err(Error::io(context(), e)),
+        }
+    }
+    Ok(patches)
+}
+
+fn write_data(path: &Path, families: &Families) -> Result<()> {
+    let context = || format!("writing {}", path.display());
+    let tmp_path = path.with_extension(&TMP_SUFFIX[1..]);
+    let mut names = families
+        .iter()
+        .filter(|(_, family)| !family.is_empty())
+        .map(|(name, _)| name)
+        .collect::<Vec<_>>();
+    names.sort();
+
+    let write = || -> io::Result<()> {
+        let mut writer = ChecksumWriter::new(BufWriter::new(File::create(&tmp_path)?));
+        writer.write_all(DATA_MAGIC)?;
+        writer.write_u32::<BigEndian>(names.len() as u32)?;
+        for name in names {
+            let family = &families[name];
+            write_bytes(&mut writer, name.as_bytes())?;
+            writer.write_u64::<BigEndian>(family.len() as u64)?;
+            for (key, value) in family.iter() {
+                write_bytes(&mut writer, key)?;
+                write_bytes(&mut writer, value)?;
+            }
+        }
+        let checksum = writer.hasher.clone().finalize();
+        let mut writer = writer.inner;
+        writer.write_u32::<BigEndian>(checksum)?;
+        writer.into_inner()?.sync_all()?;
+        fs::rename(&tmp_path, path)
+    };
+    write().map_err(|e| Error::io(context(), e))
+}
+
+fn read_data(path: &Path) -> Result<Families> {
+    let context = || format!("reading {}", path.display());
+    let invalid = |message: &str| {
+        Error::io(
+            context(),
+            io::Error::new(io::ErrorKind::InvalidData, message),
+        )
+    };
+
+    let mut bytes = Vec::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_end(&mut bytes))
+        .map_err(|e| Error::io(context(), e))?;
+    if bytes.len() < DATA_MAGIC.len() + 4 || !bytes.starts_with(DATA_MAGIC) {
+        return Err(invalid("not a data file"));
+    }
+    let (content, mut checksum) = bytes.split_at(bytes.len() - 4);
+    if crc32fast::hash(content) != checksum.read_u32::<BigEndian>().unwrap() {
+        return Err(invalid("data file checksum mismatch"));
+    }
+
+    let read = |mut content: &[u8]| -> io::Result<Families> {
+        let mut families = Families::new();
+        for _ in 0..content.read_u32::<BigEndian>()? {
+            let name = String::from_utf8(read_bytes(&mut content)?)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            let mut family = Family::new();
+            for _ in 0..content.read_u64::<BigEndian>()? {
+                let key = read_bytes(&mut content)?;
+                family.insert(key, read_bytes(&mut content)?);
+            }
+            families.insert(name, family);
+        }
+        Ok(families)
+    };
+    read(&content[DATA_MAGIC.len()..]).map_err(|e| Error::io(context(), e))
+}
+
+/// Makes renames and creations of files in the directory durable.
+pub(crate) fn sync_dir(dir: &Path) -> Result<()> {
+    // Directories cannot be opened as files on Windows; renames are durable there anyway.
+    if cfg!(unix) {
+        File::open(dir)
+            .and_then(|dir| dir.sync_all())
+            .map_err(|e| Error::io(format!("syncing {}", dir.display()), e))?;
+    }
+    Ok(())
+}
+
+struct ChecksumWriter<W: Write> {
+    inner: W,
+    hasher: crc32fast::Hasher,
+}
+
+impl<W: Write> ChecksumWriter<W> {
+    fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: crc32fast::Hasher::new(),
+        }
+    }
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::test_utils::TempDir;
+    use std::io::{Seek, SeekFrom};
+
+    fn options(wal_size_limit: u64) -> FileDBOptions {
+        FileDBOptions {
+            wal_size_limit,
+            ..FileDBOptions::default()
+        }
+    }
+
+    fn put(db: &FileDB, name: &str, key: u8, value: u8) {
+        let mut fork = db.fork();
+        fork.put(name, vec![key], vec![value]);
+        db.merge_sync(fork.into_patch()).unwrap();
+    }
+
+    #[test]
+    fn reopen_and_compact() {
+        let dir = TempDir::new("filedb_reopen");
+        {
+            let db = FileDB::open(dir.path(), options(1 << 20)).unwrap();
+            put(&db, "a", 1, 1);
+            put(&db, "a", 2, 2);
+            put(&db, "b", 1, 3);
+            let mut fork = db.fork();
+            fork.remove_by_prefix("a", None);
+            fork.put("a", vec![3], vec![4]);
+            db.merge(fork.into_patch()).unwrap();
+        }
+        {
+            let db = FileDB::open(dir.path(), options(1 << 20)).unwrap();
+            let snapshot = db.snapshot();
+            assert_eq!(snapshot.get("a", &[1]), None);
+            assert_eq!(snapshot.get("a", &[3]), Some(vec![4]));
+            assert_eq!(snapshot.get("b", &[1]), Some(vec![3]));
+            db.compact().unwrap();
+            put(&db, "b", 2, 5);
+        }
+        let db = FileDB::open(dir.path(), options(1 << 20)).unwrap();
+        let snapshot = db.snapshot();
+        assert_eq!(snapshot.get("a", &[3]), Some(vec![4]));
+        assert_eq!(snapshot.get("b", &[2]), Some(vec![5]));
+        let mut files = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(
+            files,
+            vec![format!("data-{:020}", 1), format!("wal-{:020}", 1)]
+        );
+    }
+
+    #[test]
+    fn automatic_compaction() {
+        let dir = TempDir::new("filedb_compaction");
+        let db = FileDB::open(dir.path(), options(64)).unwrap();
+        for i in 0..10 {
+            put(&db, "a", i, i);
+        }
+        assert!(db.wal.lock().unwrap().generation > 0);
+        drop(db);
+        let db = FileDB::open(dir.path(), options(64)).unwrap();
+        assert_eq!(db.snapshot().get("a", &[9]), Some(vec![9]));
+    }
+
+    #[test]
+    fn recovery_from_torn_log() {
+        let dir = TempDir::new("filedb_torn");
+        {
+            let db = FileDB::open(dir.path(), options(1 << 20)).unwrap();
+            put(&db, "a", 1, 1);
+            put(&db, "a", 2, 2);
+        }
+        // Simulate a crash in the middle of writing the second record.
+        let wal = wal_path(dir.path(), 0);
+        let len = fs::metadata(&wal).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&wal)
+            .unwrap()
+            .set_len(len - 1)
+            .unwrap();
+
+        let db = FileDB::open(dir.path(), options(1 << 20)).unwrap();
+        assert_eq!(db.snapshot().get("a", &[1]), Some(vec![1]));
+        assert_eq!(db.snapshot().get("a", &[2]), None);
+        put(&db, "a", 3, 3);
+        drop(db);
+
+        let db = FileDB::open(dir.path(), options(1 << 20)).unwrap();
+        assert_eq!(db.snapshot().get("a", &[3]), Some(vec![3]));
+    }
+
+    #[test]
+    fn failed_append() {
+        let dir = TempDir::new("filedb_failed_append");
+        let db = FileDB::open(dir.path(), options(1 << 20)).unwrap();
+        put(&db, "a", 1, 1);
+        // Neither writes nor truncation are possible through a read-only handle.
+        db.wal.lock().unwrap().file = File::open(wal_path(dir.path(), 0)).unwrap();
+        let mut fork = db.fork();
+        fork.put("a", vec![2], vec![2]);
+        assert!(db.merge_sync(fork.into_patch()).is_err());
+        assert_eq!(db.snapshot().get("a", &[2]), None);
+        assert!(db.wal.lock().unwrap().poisoned);
+
+        // A writable log is not used any more either.
+        db.wal.lock().unwrap().file = OpenOptions::new()
+            .append(true)
+            .open(wal_path(dir.path(), 0))
+            .unwrap();
+        let mut fork = db.fork();
+        fork.put("a", vec![3], vec![3]);
+        assert!(db.merge(fork.into_patch()).is_err());
+        drop(db);
+
+        let db = FileDB::open(dir.path(), options(1 << 20)).unwrap();
+        assert_eq!(db.snapshot().get("a", &[1]), Some(vec![1]));
+        put(&db, "a", 4, 4);
+    }
+
+    #[test]
+    fn failed_compaction() {
+        let dir = TempDir::new("filedb_failed_compaction");
+        let db = FileDB::open(dir.path(), options(64)).unwrap();
+        // The data file of the next generation cannot be created in place of a directory.
+        fs::create_dir(data_path(dir.path(), 1).with_extension(&TMP_SUFFIX[1..])).unwrap();
+        for i in 0..10 {
+            put(&db, "a", i, i);
+        }
+        assert!(db.compaction_error().is_some());
+        assert_eq!(db.wal.lock().unwrap().generation, 0);
+
+        fs::remove_dir(data_path(dir.path(), 1).with_extension(&TMP_SUFFIX[1..])).unwrap();
+        put(&db, "a", 10, 10);
+        assert!(db.compaction_error().is_none());
+        drop(db);
+        let db = FileDB::open(dir.path(), options(64)).unwrap();
+        assert_eq!(db.snapshot().get("a", &[10]), Some(vec![10]));
+        assert_eq!(db.snapshot().get("a", &[0]), Some(vec![0]));
+    }
+
+    #[test]
+    fn failed_compaction_after_rename() {
+        let dir = TempDir::new("filedb_failed_compaction_after_rename");
+        let db = FileDB::open(dir.path(), options(64)).unwrap();
+        // A stale file which cannot be removed fails the compaction after the rename.
+        let stale = dir.path().join(format!("stale{}", TMP_SUFFIX));
+        fs::create_dir(&stale).unwrap();
+        for i in 0..10 {
+            put(&db, "a", i, i);
+        }
+        assert!(db.compaction_error().is_some());
+        let generation = db.wal.lock().unwrap().generation;
+        assert!(generation > 0);
+        assert!(data_path(dir.path(), generation).exists());
+
+        put(&db, "b", 1, 1);
+        drop(db);
+        fs::remove_dir(&stale).unwrap();
+        let db = FileDB::open(dir.path(), options(1 << 20)).unwrap();
+        assert_eq!(db.snapshot().get("b", &[1]), Some(vec![1]));
+        assert_eq!(db.snapshot().get("a", &[9]), Some(vec![9]));
+    }
+
+    #[test]
+    fn merge_patches() {
+        let dir = TempDir::new("filedb_merge_patches");
+        {
+            let db = FileDB::open(dir.path(), options(1 << 20)).unwrap();
+            let mut fork = db.fork();
+            fork.put("a", vec![1], vec![1]);
+            fork.put("a", vec![2], vec![2]);
+            let first = fork.into_patch();
+            let mut fork = db.fork();
+            fork.remove("a", vec![2]);
+            fork.put("b", vec![1], vec![3]);
+            let second = fork.into_patch();
+
+            let failed = vec![Ok(first.clone()), Err(Error::new("broken patch"))];
+            assert!(db.merge_patches(&mut failed.into_iter()).is_err());
+            assert_eq!(db.snapshot().get("a", &[1]), None);
+            db.merge_patches(&mut vec![Ok(first), Ok(second)].into_iter())
+                .unwrap();
+        }
+        let db = FileDB::open(dir.path(), options(1 << 20)).unwrap();
+        let snapshot = db.snapshot();
+        assert_eq!(snapshot.get("a", &[1]), Some(vec![1]));
+        assert_eq!(snapshot.get("a", &[2]), None);
+        assert_eq!(snapshot.get("b", &[1]), Some(vec![3]));
+    }
+
+    #[test]
+    fn corrupted_data_file() {
+        let dir = TempDir::new("filedb_corrupted");
+        {
+            let db = FileDB::open(dir.path(), options(1 << 20)).unwrap();
+            put(&db, "a", 1, 1);
+            db.compact().unwrap();
+        }
+        let data = data_path(dir.path(), 1);
+        let mut file = OpenOptions::new().write(true).open(&data).unwrap();
+        file.seek(SeekFrom::Start(DATA_MAGIC.len() as u64)).unwrap();
+        file.write_all(&[0xff]).unwrap();
+        drop(file);
+        match FileDB::open(dir.path(), options(1 << 20)) {
+            Err(Error::Io { ref cause, .. }) => {
+                assert_eq!(cause.kind(), io::ErrorKind::InvalidData)
+            }
+            _ => panic!("corrupted data file is accepted"),
+        }
+    }
+}
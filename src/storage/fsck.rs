@@ -0,0 +1,689 @@
+//! Consistency checks of the stored data.
+//!
+//! A [`Checker`] walks the column families of a `Snapshot` and collects every problem it
+//! finds into a [`Report`] instead of failing on the first malformed record. Since a
+//! snapshot cannot enumerate its column families, the checker walks the column families
+//! recorded in [`METADATA_NAME`] together with the system column families:
+//!
+//! - keys and values are decoded with the `StorageKey` and `StorageValue` types registered
+//!   for the type tags of the column family metadata; column families with types which are
+//!   not registered are listed in [`Report::unchecked`];
+//! - blob stores and maps with expiring entries are checked for records which are
+//!   inconsistent with each other, e.g. reference counts of missing blobs or expiry index
+//!   entries of missing map entries;
+//! - roots of authenticated indexes registered in [`STATE_AGGREGATOR_NAME`] are compared
+//!   with the roots recomputed by the functions registered with [`register_root`];
+//! - the replication log must form a hash chain from its anchor, or from the first entry if
+//!   the log has never been truncated, to the replication head.
+//!
+//! [`Checker`]: struct.Checker.html
+//! [`Report`]: struct.Report.html
+//! [`Report::unchecked`]: struct.Report.html#structfield.unchecked
+//! [`METADATA_NAME`]: ../metadata/constant.METADATA_NAME.html
+//! [`STATE_AGGREGATOR_NAME`]: ../state/constant.STATE_AGGREGATOR_NAME.html
+//! [`register_root`]: struct.Checker.html#method.register_root
+
+use std::borrow::{Borrow, Cow};
+use std::collections::HashMap;
+use std::fmt;
+
+use byteorder::{BigEndian, ByteOrder};
+use chrono::{DateTime, Utc};
+
+use crate::common::to_hex;
+use crate::crypto::{self, HASH_SIZE, Hash};
+
+use super::address::NAME_SEPARATOR;
+use super::blobs::{BLOB_TAG, GARBAGE_TAG, REFS_TAG};
+use super::db::Snapshot;
+use super::keys::StorageKey;
+use super::metadata::{CODEC_VERSION, IndexKind, IndexMetadata, METADATA_NAME, TypeTag};
+use super::replication::{
+    ANCHOR_KEY, HEAD_KEY, Head, REPLICATION_HEAD_NAME, REPLICATION_LOG_NAME, ReplicationEntry,
+};
+use super::state::STATE_AGGREGATOR_NAME;
+use super::ttl::EXPIRY_SIZE;
+use super::typed::key_bytes;
+use super::values::StorageValue;
+use super::wal;
+
+type Decoder = dyn Fn(&[u8]) -> ::std::result::Result<(), String> + Send + Sync;
+type RootFn = dyn Fn(&dyn Snapshot) -> Hash + Send + Sync;
+
+/// Kind of a problem found by the `Checker`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IssueKind {
+    /// The entry is malformed on the storage level or contradicts other entries.
+    Corrupted(String),
+    /// The key cannot be decoded as the registered key type.
+    UndecodableKey(String),
+    /// The value cannot be decoded as the registered value type.
+    UndecodableValue(String),
+    /// The entry refers to an entry which does not exist.
+    Orphaned(String),
+    /// The registered root of an authenticated index differs from the recomputed one.
+    RootMismatch {
+        /// Root registered in the state aggregator.
+        stored: Hash,
+        /// Root recomputed from the entries of the index.
+        computed: Hash,
+    },
+}
+
+/// Problem found by the `Checker`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Issue {
+    /// Name of the column family.
+    pub name: String,
+    /// Key of the entry, or `None` if the problem relates to the whole column family.
+    pub key: Option<Vec<u8>>,
+    /// Kind of the problem.
+    pub kind: IssueKind,
+}
+
+/// Results of a consistency check.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Report {
+    /// Names of the walked column families.
+    pub names: Vec<String>,
+    /// Names of the column families which have been checked only partially, because the
+    /// types of their keys or values or the function computing their root are not registered.
+    pub unchecked: Vec<String>,
+    /// Number of the walked entries.
+    pub entries: u64,
+    /// Found problems.
+    pub issues: Vec<Issue>,
+}
+
+/// Checker of the consistency of the stored data.
+#[derive(Default)]
+pub struct Checker {
+    keys: HashMap<String, Box<Decoder>>,
+    values: HashMap<String, Box<Decoder>>,
+    roots: HashMap<String, Box<RootFn>>,
+}
+
+impl fmt::Display for IssueKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            IssueKind::Corrupted(ref reason) => write!(f, "corrupted: {}", reason),
+            IssueKind::UndecodableKey(ref reason) => write!(f, "undecodable key: {}", reason),
+            IssueKind::UndecodableValue(ref reason) => {
+                write!(f, "undecodable value: {}", reason)
+            }
+            IssueKind::Orphaned(ref reason) => write!(f, "orphaned: {}", reason),
+            IssueKind::RootMismatch { stored, computed } => write!(
+                f,
+                "root mismatch: stored {}, computed {}",
+                to_hex(stored),
+                to_hex(computed)
+            ),
+        }
+    }
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.key {
+            Some(ref key) => write!(f, "`{}` 0x{}: {}", self.name, to_hex(key), self.kind),
+            None => write!(f, "`{}`: {}", self.name, self.kind),
+        }
+    }
+}
+
+impl Report {
+    /// Returns `true` if no problems have been found.
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    fn issue(&mut self, name: &str, key: Option<&[u8]>, kind: IssueKind) {
+        self.issues.push(Issue {
+            name: name.to_string(),
+            key: key.map(<[u8]>::to_vec),
+            kind,
+        });
+    }
+
+    fn corrupted<S: Into<String>>(&mut self, name: &str, key: &[u8], reason: S) {
+        self.issue(name, Some(key), IssueKind::Corrupted(reason.into()));
+    }
+
+    fn orphaned<S: Into<String>>(&mut self, name: &str, key: &[u8], reason: S) {
+        self.issue(name, Some(key), IssueKind::Orphaned(reason.into()));
+    }
+
+    fn unchecked(&mut self, name: &str) {
+        if !self.unchecked.iter().any(|unchecked| unchecked == name) {
+            self.unchecked.push(name.to_string());
+        }
+    }
+}
+
+fn check_key<K: StorageKey + ?Sized>(bytes: &[u8]) -> ::std::result::Result<(), String> {
+    let key = K::try_read(bytes).map_err(|e| e.to_string())?;
+    if key_bytes(key.borrow()) != bytes {
+        return Err("is not encoded canonically".to_string());
+    }
+    Ok(())
+}
+
+fn check_value<V: StorageValue>(bytes: &[u8]) -> ::std::result::Result<(), String> {
+    V::try_from_bytes(Cow::Borrowed(bytes))
+        .map(drop)
+        .map_err(|e| e.to_string())
+}
+
+impl Checker {
+    /// Creates a checker without registered types.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers the key type `K` for the column families with the `TYPE_TAG` of `K` as
+    /// the key type tag.
+    pub fn register_key<K>(&mut self) -> &mut Self
+    where
+        K: StorageKey + TypeTag + ?Sized + 'static,
+    {
+        self.keys
+            .insert(K::TYPE_TAG.to_string(), Box::new(check_key::<K>));
+        self
+    }
+
+    /// Registers the value type `V` for the column families with the `TYPE_TAG` of `V` as
+    /// the value type tag.
+    pub fn register_value<V>(&mut self) -> &mut Self
+    where
+        V: StorageValue + TypeTag + 'static,
+    {
+        self.values.insert(
+            V::TYPE_TAG.to_string(),
+            Box::new(check_value::<V>),
+        );
+        self
+    }
+
+    /// Registers the function recomputing the root of the authenticated index with
+    /// the given `name` from its entries.
+    pub fn register_root<F>(&mut self, name: &str, root: F) -> &mut Self
+    where
+        F: Fn(&dyn Snapshot) -> Hash + Send + Sync + 'static,
+    {
+        self.roots.insert(name.to_string(), Box::new(root));
+        self
+    }
+
+    /// Checks the data in the view and returns the found problems.
+    pub fn check(&self, view: &dyn Snapshot) -> Report {
+        let mut report = Report::default();
+        let families = self.check_metadata(view, &mut report);
+        for (name, metadata) in &families {
+            match metadata.kind {
+                IndexKind::Blobs => self.check_blobs(view, name, &mut report),
+                IndexKind::TtlMap => self.check_ttl(view, name, metadata, &mut report),
+                _ => self.check_entries(view, name, metadata, &mut report),
+            }
+        }
+        self.check_roots(view, &families, &mut report);
+        self.check_replication(view, &mut report);
+        report
+    }
+
+    fn check_metadata(
+        &self,
+        view: &dyn Snapshot,
+        report: &mut Report,
+    ) -> Vec<(String, IndexMetadata)> {
+        report.names.push(METADATA_NAME.to_string());
+        let mut families = Vec::new();
+        let mut iter = view.iter(METADATA_NAME, &[]);
+        while let Some((key, value)) = iter.next() {
+            report.entries += 1;
+            let name = match String::from_utf8(key.to_vec()) {
+                Ok(name) => name,
+                Err(_) => {
+                    report.corrupted(METADATA_NAME, key, "column family name is not UTF-8");
+                    continue;
+                }
+            };
+            match serde_json::from_slice::<IndexMetadata>(value) {
+                Ok(ref metadata) if metadata.version > CODEC_VERSION => report.corrupted(
+                    METADATA_NAME,
+                    key,
+                    format!("unsupported codec version {}", metadata.version),
+                ),
+                Ok(metadata) => families.push((name, metadata)),
+                Err(e) => report.corrupted(METADATA_NAME, key, e.to_string()),
+            }
+        }
+        families
+    }
+
+    fn decoders(
+        &self,
+        name: &str,
+        metadata: &IndexMetadata,
+        report: &mut Report,
+    ) -> (Option<&Decoder>, Option<&Decoder>) {
+        let key = self.keys.get(&metadata.key_type).map(Box::as_ref);
+        let value = self.values.get(&metadata.value_type).map(Box::as_ref);
+        if key.is_none() || value.is_none() {
+            report.unchecked(name);
+        }
+        (key, value)
+    }
+
+    fn check_entry(
+        name: &str,
+        key: &[u8],
+        value: &[u8],
+        decoders: (Option<&Decoder>, Option<&Decoder>),
+        report: &mut Report,
+    ) {
+        if let Some(Err(reason)) = decoders.0.map(|decode| decode(key)) {
+            report.issue(name, Some(key), IssueKind::UndecodableKey(reason));
+        }
+        if let Some(Err(reason)) = decoders.1.map(|decode| decode(value)) {
+            report.issue(name, Some(key), IssueKind::UndecodableValue(reason));
+        }
+    }
+
+    fn check_entries(
+        &self,
+        view: &dyn Snapshot,
+        name: &str,
+        metadata: &IndexMetadata,
+        report: &mut Report,
+    ) {
+        report.names.push(name.to_string());
+        let decoders = self.decoders(name, metadata, report);
+        let mut iter = view.iter(name, &[]);
+        while let Some((key, value)) = iter.next() {
+            report.entries += 1;
+            Self::check_entry(name, key, value, decoders, report);
+        }
+    }
+
+    fn check_blobs(&self, view: &dyn Snapshot, name: &str, report: &mut Report) {
+        report.names.push(name.to_string());
+        let record = |tag: u8, hash: &[u8]| {
+            let mut key = vec![tag];
+            key.extend_from_slice(hash);
+            key
+        };
+        let mut iter = view.iter(name, &[]);
+        while let Some((key, value)) = iter.next() {
+            report.entries += 1;
+            if key.len() != 1 + HASH_SIZE {
+                report.corrupted(name, key, "malformed record key");
+                continue;
+            }
+            let hash = &key[1..];
+            match key[0] {
+                BLOB_TAG => {
+                    if crypto::hash(value).as_ref() != hash {
+                        report.corrupted(name, key, "blob does not match its hash");
+                    }
+                    if !view.contains(name, &record(REFS_TAG, hash))
+                        && !view.contains(name, &record(GARBAGE_TAG, hash))
+                    {
+                        report.orphaned(name, key, "blob is neither referenced nor garbage");
+                    }
+                }
+                REFS_TAG => {
+                    if value.len() != 8 {
+                        report.corrupted(name, key, "malformed reference count");
+                    } else if BigEndian::read_u64(value) == 0 {
+                        report.corrupted(name, key, "zero reference count");
+                    }
+                    if view.contains(name, &record(GARBAGE_TAG, hash)) {
+                        report.corrupted(name, key, "referenced blob is marked as garbage");
+                    }
+                    if !view.contains(name, &record(BLOB_TAG, hash)) {
+                        report.orphaned(name, key, "reference count of a missing blob");
+                    }
+                }
+                GARBAGE_TAG => {
+                    if !view.contains(name, &record(BLOB_TAG, hash)) {
+                        report.orphaned(name, key, "garbage mark of a missing blob");
+                    }
+                }
+                tag => report.corrupted(name, key, format!("unknown record tag {}", tag)),
+            }
+        }
+    }
+
+    fn check_ttl(
+        &self,
+        view: &dyn Snapshot,
+        name: &str,
+        metadata: &IndexMetadata,
+        report: &mut Report,
+    ) {
+        let expiry_name = format!("{}{}expiry", name, NAME_SEPARATOR);
+        report.names.push(name.to_string());
+        report.names.push(expiry_name.clone());
+        let decoders = self.decoders(name, metadata, report);
+        let mut iter = view.iter(name, &[]);
+        while let Some((key, value)) = iter.next() {
+            report.entries += 1;
+            if value.len() < EXPIRY_SIZE {
+                report.corrupted(name, key, "value is shorter than the expiry time");
+                continue;
+            }
+            if let Err(e) = DateTime::<Utc>::try_read(&value[..EXPIRY_SIZE]) {
+                report.corrupted(name, key, format!("invalid expiry time: {}", e));
+            }
+            Self::check_entry(name, key, &value[EXPIRY_SIZE..], decoders, report);
+            let mut index_key = value[..EXPIRY_SIZE].to_vec();
+            index_key.extend_from_slice(key);
+            if view.get(&expiry_name, &index_key).as_deref() != Some(key) {
+                report.corrupted(name, key, "entry is missing from the expiry index");
+            }
+        }
+
+        let mut iter = view.iter(&expiry_name, &[]);
+        while let Some((index_key, key)) = iter.next() {
+            report.entries += 1;
+            if index_key.len() < EXPIRY_SIZE || &index_key[EXPIRY_SIZE..] != key {
+                report.corrupted(&expiry_name, index_key, "malformed expiry index entry");
+                continue;
+            }
+            let expiry = &index_key[..EXPIRY_SIZE];
+            match view.get(name, key) {
+                Some(ref value) if value.starts_with(expiry) => {}
+                _ => report.orphaned(&expiry_name, index_key, "expiry of a missing entry"),
+            }
+        }
+    }
+
+    fn check_roots(
+        &self,
+        view: &dyn Snapshot,
+        families: &[(String, IndexMetadata)],
+        report: &mut Report,
+    ) {
+        report.names.push(STATE_AGGREGATOR_NAME.to_string());
+        let mut registered = Vec::new();
+        let mut iter = view.iter(STATE_AGGREGATOR_NAME, &[]);
+        while let Some((key, value)) = iter.next() {
+            report.entries += 1;
+            let name = match String::from_utf8(key.to_vec()) {
+                Ok(name) => name,
+                Err(_) => {
+                    report.corrupted(STATE_AGGREGATOR_NAME, key, "index name is not UTF-8");
+                    continue;
+                }
+            };
+            registered.push(name.clone());
+            if value.len() != HASH_SIZE {
+                report.corrupted(STATE_AGGREGATOR_NAME, key, "malformed root hash");
+                continue;
+            }
+            let stored = Hash::new(value);
+            let has_metadata = families.iter().any(|(family, _)| *family == name);
+            if !has_metadata && view.iter(&name, &[]).peek().is_none() {
+                report.orphaned(STATE_AGGREGATOR_NAME, key, "root of an empty index");
+            }
+            match self.roots.get(&name) {
+                Some(root) => {
+                    let computed = root(view);
+                    if computed != stored {
+                        report.issue(
+                            STATE_AGGREGATOR_NAME,
+                            Some(key),
+                            IssueKind::RootMismatch { stored, computed },
+                        );
+                    }
+                }
+                None => report.unchecked(&name),
+            }
+        }
+
+        for (name, metadata) in families {
+            let authenticated = matches!(metadata.kind, IndexKind::ProofList | IndexKind::ProofMap);
+            if authenticated && !registered.contains(name) && view.iter(name, &[]).peek().is_some()
+            {
+                report.issue(
+                    name,
+                    None,
+                    IssueKind::Corrupted("authenticated index has no registered root".to_string()),
+                );
+            }
+        }
+    }
+
+    fn check_replication(&self, view: &dyn Snapshot, report: &mut Report) {
+        report.names.push(REPLICATION_LOG_NAME.to_string());
+        // A truncated log continues from its anchor, and a complete one from the empty
+        // position. `last` is `None` once the chain cannot be followed.
+        let mut last = match Head::read_anchor(view) {
+            Ok(anchor) => {
+                report.entries += u64::from(anchor != Head::empty());
+                Some(anchor)
+            }
+            Err(e) => {
+                report.entries += 1;
+                report.corrupted(REPLICATION_HEAD_NAME, ANCHOR_KEY, e.to_string());
+                None
+            }
+        };
+        let mut iter = view.iter(REPLICATION_LOG_NAME, &[]);
+        while let Some((key, value)) = iter.next() {
+            report.entries += 1;
+            let entry = match ReplicationEntry::from_bytes(value) {
+                Some(ref entry) if key.len() == 8 && BigEndian::read_u64(key) == entry.seq => {
+                    entry.clone()
+                }
+                _ => {
+                    report.corrupted(REPLICATION_LOG_NAME, key, "malformed log entry");
+                    last = None;
+                    continue;
+                }
+            };
+            if let Err(e) = wal::decode_patch(&entry.patch) {
+                report.corrupted(REPLICATION_LOG_NAME, key, format!("malformed patch: {}", e));
+            }
+            let chained = last
+                .as_ref()
+                .is_none_or(|prev| prev.seq + 1 == entry.seq && prev.hash == entry.prev_hash);
+            if !chained {
+                report.corrupted(
+                    REPLICATION_LOG_NAME,
+                    key,
+                    "entry is not chained to the previous one",
+                );
+            }
+            last = Some(entry.head());
+        }
+
+        report.names.push(REPLICATION_HEAD_NAME.to_string());
+        match Head::read(view) {
+            Ok(head) => {
+                report.entries += u64::from(head != Head::empty());
+                if let Some(last) = last.filter(|last| *last != head) {
+                    let reason = format!(
+                        "log ends at entry {}, but the head is at entry {}",
+                        last.seq, head.seq
+                    );
+                    report.corrupted(REPLICATION_HEAD_NAME, HEAD_KEY, reason);
+                }
+            }
+            Err(e) => {
+                report.entries += 1;
+                report.corrupted(REPLICATION_HEAD_NAME, HEAD_KEY, e.to_string());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::blobs::BlobStore;
+    use crate::storage::db::Database;
+    use crate::storage::metadata::ensure_metadata;
+    use crate::storage::replication::ReplicatedDB;
+    use crate::storage::state::update_index_root;
+    use crate::storage::test_utils::TestDB;
+    use crate::storage::ttl::TtlMap;
+
+    fn checker() -> Checker {
+        let mut checker = Checker::new();
+        checker.register_key::<u64>().register_value::<u64>();
+        checker.register_root("balances", |view| {
+            let mut stream = crypto::HashStream::new();
+            let mut iter = view.iter("balances", &[]);
+            while let Some((key, value)) = iter.next() {
+                stream = stream.update(key).update(value);
+            }
+            stream.hash()
+        });
+        checker
+    }
+
+    fn fill(db: &TestDB) {
+        let mut fork = db.fork();
+        let balances = IndexMetadata::new::<u64, u64>(IndexKind::ProofMap);
+        ensure_metadata(&mut fork, "balances", &balances).unwrap();
+        fork.put("balances", key_bytes(&1_u64), 10_u64.into_bytes());
+        fork.put("balances", key_bytes(&2_u64), 20_u64.into_bytes());
+        let root = checker().roots["balances"](&fork);
+        update_index_root(&mut fork, "balances", &root);
+
+        BlobStore::new("code").put(&mut fork, b"contract").unwrap();
+        let bans = TtlMap::<u64, u64>::new("bans");
+        bans.put(&mut fork, &1, 1, chrono::Utc::now()).unwrap();
+        db.merge(fork.into_patch()).unwrap();
+    }
+
+    #[test]
+    fn consistent_data() {
+        let db = TestDB::new();
+        fill(&db);
+        let report = checker().check(&*db.snapshot());
+        assert!(report.is_ok(), "{:?}", report.issues);
+        assert!(report.names.contains(&"bans.expiry".to_string()));
+        // Metadata of 3 indexes, 2 balances, 2 blob records, 2 TTL records and 1 root.
+        assert_eq!(report.entries, 10);
+        assert!(report.unchecked.is_empty());
+
+        let report = Checker::new().check(&*db.snapshot());
+        assert!(report.is_ok());
+        assert_eq!(report.unchecked, vec!["balances", "bans"]);
+    }
+
+    #[test]
+    fn corrupted_data() {
+        let db = TestDB::new();
+        fill(&db);
+        let mut fork = db.fork();
+        fork.put("balances", vec![1, 2, 3], b"30".to_vec());
+        fork.put("balances", 3_u64.to_be_bytes().to_vec(), b"thirty".to_vec());
+        fork.remove("code", {
+            let mut key = vec![REFS_TAG];
+            key.extend_from_slice(crypto::hash(b"contract").as_ref());
+            key
+        });
+        fork.remove("bans", 1_u64.to_be_bytes().to_vec());
+        fork.put(STATE_AGGREGATOR_NAME, b"gone".to_vec(), vec![0; HASH_SIZE]);
+        fork.put(METADATA_NAME, b"broken".to_vec(), b"{}".to_vec());
+        db.merge(fork.into_patch()).unwrap();
+
+        let report = checker().check(&*db.snapshot());
+        let kinds = report
+            .issues
+            .iter()
+            .map(|issue| (issue.name.as_str(), &issue.kind))
+            .collect::<Vec<_>>();
+        assert_eq!(report.issues.len(), 7, "{:?}", kinds);
+        assert!(matches!(kinds[0], (METADATA_NAME, IssueKind::Corrupted(_))));
+        assert!(matches!(
+            kinds[1],
+            ("balances", IssueKind::UndecodableValue(_))
+        ));
+        assert!(matches!(
+            kinds[2],
+            ("balances", IssueKind::UndecodableKey(_))
+        ));
+        assert!(matches!(kinds[3], ("bans.expiry", IssueKind::Orphaned(_))));
+        assert!(matches!(kinds[4], ("code", IssueKind::Orphaned(_))));
+        assert!(matches!(
+            kinds[5],
+            (STATE_AGGREGATOR_NAME, IssueKind::RootMismatch { .. })
+        ));
+        assert!(matches!(
+            kinds[6],
+            (STATE_AGGREGATOR_NAME, IssueKind::Orphaned(_))
+        ));
+        assert_eq!(
+            report.issues[4].to_string(),
+            format!(
+                "`code` 0x00{}: orphaned: blob is neither referenced nor garbage",
+                to_hex(crypto::hash(b"contract"))
+            )
+        );
+    }
+
+    #[test]
+    fn invalid_expiry() {
+        let db = TestDB::new();
+        fill(&db);
+        let mut fork = db.fork();
+        let key = key_bytes(&2_u64);
+        let mut value = vec![0xff; EXPIRY_SIZE];
+        let mut index_key = value.clone();
+        value.extend(2_u64.into_bytes());
+        index_key.extend_from_slice(&key);
+        fork.put("bans", key.clone(), value);
+        fork.put("bans.expiry", index_key, key);
+        db.merge(fork.into_patch()).unwrap();
+
+        let report = checker().check(&*db.snapshot());
+        assert_eq!(report.issues.len(), 1, "{:?}", report.issues);
+        assert_eq!(report.issues[0].name, "bans");
+        assert!(matches!(report.issues[0].kind, IssueKind::Corrupted(_)));
+    }
+
+    #[test]
+    fn broken_replication_log() {
+        let db = ReplicatedDB::new(TestDB::new()).unwrap();
+        for i in 1..=2_u8 {
+            let mut fork = db.fork();
+            fork.put("a", vec![i], vec![i]);
+            db.merge(fork.into_patch()).unwrap();
+        }
+        let issues = |db: &TestDB| {
+            Checker::new()
+                .check(&*db.snapshot())
+                .issues
+                .into_iter()
+                .map(|issue| (issue.name, issue.kind))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(issues(db.inner()), vec![]);
+
+        // Without an anchor, the log must start from the first entry.
+        let mut fork = db.inner().fork();
+        fork.remove(REPLICATION_LOG_NAME, 1_u64.to_be_bytes().to_vec());
+        db.inner().merge(fork.into_patch()).unwrap();
+        let found = issues(db.inner());
+        assert_eq!(found.len(), 1, "{:?}", found);
+        assert!(matches!(
+            found[0],
+            (ref name, IssueKind::Corrupted(_)) if name == REPLICATION_LOG_NAME
+        ));
+
+        // An empty log must have an empty head.
+        let mut fork = db.inner().fork();
+        fork.remove(REPLICATION_LOG_NAME, 2_u64.to_be_bytes().to_vec());
+        db.inner().merge(fork.into_patch()).unwrap();
+        let found = issues(db.inner());
+        assert_eq!(found.len(), 1, "{:?}", found);
+        assert!(matches!(
+            found[0],
+            (ref name, IssueKind::Corrupted(_)) if name == REPLICATION_HEAD_NAME
+        ));
+    }
+}
@@ -15,6 +15,8 @@
 #![allow(unsafe_code)]
 
 //! A definition of `StorageKey` trait and implementations for common types.
+use std::borrow::Borrow;
+
 use crate::crypto::{HASH_SIZE, Hash};
 use crate::ethkey::{Public, SIGNATURE_SIZE, Signature};
 use crate::types::Zero;
@@ -38,6 +40,18 @@ pub trait StorageKey: ToOwned {
     /// Deserializes the key from the specified buffer of bytes.
     // TODO: Should be unsafe? (ECR-174)
     fn read(buffer: &[u8]) -> Self::Owned;
+
+    /// Deserializes the key from the specified buffer of bytes, returning an error instead
+    /// of panicking if the buffer has a wrong size or holds an invalid key.
+    ///
+    /// Default implementation calls `read` and checks that the key has the size of the buffer,
+    /// so it must be overridden if `read` may panic on malformed input.
+    fn try_read(buffer: &[u8]) -> Result<Self::Owned, failure::Error> {
+        let key = Self::read(buffer);
+        let size = key.borrow().size();
+        ensure!(size == buffer.len(), "expected {} bytes, got {}", size, buffer.len());
+        Ok(key)
+    }
 }
 
 /// No-op implementation.
@@ -81,6 +95,11 @@ impl StorageKey for u8 {
     fn read(buffer: &[u8]) -> Self::Owned {
         buffer[0]
     }
+
+    fn try_read(buffer: &[u8]) -> Result<Self::Owned, failure::Error> {
+        ensure!(buffer.len() == 1, "expected 1 byte, got {}", buffer.len());
+        Ok(Self::read(buffer))
+    }
 }
 
 /// Uses encoding with the values mapped to `u8`
@@ -97,6 +116,11 @@ impl StorageKey for i8 {
     fn read(buffer: &[u8]) -> Self::Owned {
         buffer[0].wrapping_sub(i8::min_value() as u8) as i8
     }
+
+    fn try_read(buffer: &[u8]) -> Result<Self::Owned, failure::Error> {
+        ensure!(buffer.len() == 1, "expected 1 byte, got {}", buffer.len());
+        Ok(Self::read(buffer))
+    }
 }
 
 /// Uses UTF-8 string serialization.
@@ -112,6 +136,10 @@ impl StorageKey for String {
     fn read(buffer: &[u8]) -> Self::Owned {
         unsafe { ::std::str::from_utf8_unchecked(buffer).to_string() }
     }
+
+    fn try_read(buffer: &[u8]) -> Result<Self::Owned, failure::Error> {
+        Ok(String::from_utf8(buffer.to_vec())?)
+    }
 }
 
 impl StorageKey for str {
@@ -127,6 +155,10 @@ impl StorageKey for str {
     fn read(buffer: &[u8]) -> Self::Owned {
         String::from_utf8_lossy(buffer).to_string()
     }
+
+    fn try_read(buffer: &[u8]) -> Result<Self::Owned, failure::Error> {
+        Ok(String::from_utf8(buffer.to_vec())?)
+    }
 }
 
 /// `chrono::DateTime` uses only 12 bytes in the storage. It is represented by number of seconds
@@ -150,6 +182,14 @@ impl StorageKey for DateTime<Utc> {
         let nanos = u32::read(&buffer[8..12]);
         DateTime::from_timestamp(secs, nanos).unwrap_or_else(Utc::now)
     }
+
+    fn try_read(buffer: &[u8]) -> Result<Self::Owned, failure::Error> {
+        ensure!(buffer.len() == 12, "expected 12 bytes, got {}", buffer.len());
+        let secs = i64::read(&buffer[0..8]);
+        let nanos = u32::read(&buffer[8..12]);
+        DateTime::from_timestamp(secs, nanos)
+            .ok_or_else(|| format_err!("timestamp {}.{:09} is out of range", secs, nanos))
+    }
 }
 
 impl StorageKey for Uuid {
@@ -164,6 +204,11 @@ impl StorageKey for Uuid {
     fn read(buffer: &[u8]) -> Self::Owned {
         Uuid::from_bytes(buffer).unwrap()
     }
+
+    fn try_read(buffer: &[u8]) -> Result<Self::Owned, failure::Error> {
+        ensure!(buffer.len() == 16, "expected 16 bytes, got {}", buffer.len());
+        Ok(Self::read(buffer))
+    }
 }
 
 impl StorageKey for Decimal {
@@ -180,6 +225,11 @@ impl StorageKey for Decimal {
         bytes.copy_from_slice(buffer);
         Self::deserialize(bytes)
     }
+
+    fn try_read(buffer: &[u8]) -> Result<Self::Owned, failure::Error> {
+        ensure!(buffer.len() == 16, "expected 16 bytes, got {}", buffer.len());
+        Ok(Self::read(buffer))
+    }
 }
 
 macro_rules! storage_key_for_ints {
@@ -197,6 +247,11 @@ macro_rules! storage_key_for_ints {
             fn read(buffer: &[u8]) -> Self {
                 BigEndian::$read_method(buffer)
             }
+
+            fn try_read(buffer: &[u8]) -> Result<Self, failure::Error> {
+                ensure!(buffer.len() == $size, "expected {} bytes, got {}", $size, buffer.len());
+                Ok(Self::read(buffer))
+            }
         }
 
         /// Uses big-endian encoding with the values mapped to the unsigned format
@@ -214,6 +269,11 @@ macro_rules! storage_key_for_ints {
                 BigEndian::$read_method(buffer).wrapping_sub($itype::min_value() as $utype)
                     as $itype
             }
+
+            fn try_read(buffer: &[u8]) -> Result<Self, failure::Error> {
+                ensure!(buffer.len() == $size, "expected {} bytes, got {}", $size, buffer.len());
+                Ok(Self::read(buffer))
+            }
         }
     };
 }
@@ -237,6 +297,16 @@ macro_rules! storage_key_for_crypto_types {
             fn read(buffer: &[u8]) -> Self {
                 $type::from_slice(buffer)
             }
+
+            fn try_read(buffer: &[u8]) -> Result<Self, failure::Error> {
+                failure::ensure!(
+                    buffer.len() == $size,
+                    "expected {} bytes, got {}",
+                    $size,
+                    buffer.len()
+                );
+                Ok($type::from_slice(buffer))
+            }
         }
     };
 }
@@ -259,6 +329,16 @@ macro_rules! storage_key_for_crypto_option_types {
                     None => $type::default(),
                 }
             }
+
+            fn try_read(buffer: &[u8]) -> Result<Self, failure::Error> {
+                failure::ensure!(
+                    buffer.len() == $size,
+                    "expected {} bytes, got {}",
+                    $size,
+                    buffer.len()
+                );
+                $type::from_slice(buffer).ok_or_else(|| failure::format_err!("invalid key"))
+            }
         }
     };
 }
@@ -277,4 +357,17 @@ mod test {
     fn mannul() {
         let keypair = Random.generate().unwrap();
     }
+
+    #[test]
+    fn try_read() {
+        assert_eq!(u64::try_read(&42_u64.to_be_bytes()).unwrap(), 42);
+        assert!(u64::try_read(&[0; 7]).is_err());
+        assert!(i8::try_read(&[]).is_err());
+        assert!(Zero::try_read(&[0]).is_err());
+        assert!(<()>::try_read(&[]).is_ok());
+        assert!(<()>::try_read(&[1]).is_err());
+        assert!(String::try_read(&[0xff]).is_err());
+        assert!(Hash::try_read(&[0; HASH_SIZE + 1]).is_err());
+        assert!(DateTime::<Utc>::try_read(&[0xff; 12]).is_err());
+    }
 }
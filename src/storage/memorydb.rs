@@ -0,0 +1,280 @@
+//! An in-memory `Database` built on persistent ordered maps.
+//!
+//! Every version of the database is an immutable map from column family names to
+//! [`im::OrdMap`]s, which share unchanged nodes with previous versions. Taking a snapshot
+//! clones a pointer to the current version, so it is O(1) and never waits for a writer.
+//! `merge` applies the patch to a copy of the current version (paying only for the touched
+//! paths of the trees) and publishes the result with a single atomic store; concurrent
+//! merges are serialized by a writer lock that readers never take.
+//!
+//! [`im::OrdMap`]: https://docs.rs/im/15/im/ordmap/struct.OrdMap.html
+
+use std::collections::Bound::*;
+use std::collections::HashMap;
+use std::fmt;
+use std::iter::{Iterator as StdIterator, Peekable};
+use std::sync::{Arc, Mutex};
+
+use arc_swap::ArcSwap;
+use im::ordmap::{OrdMap, RangedIter};
+
+use super::Result;
+use super::db::{Change, Database, Iter, Iterator, Patch, Snapshot};
+
+pub(crate) type Family = OrdMap<Vec<u8>, Vec<u8>>;
+pub(crate) type Families = HashMap<String, Family>;
+
+/// Database keeping all column families in memory.
+///
+/// `MemoryDB` is intended for tests and short-lived nodes; its content is lost when the
+/// database is dropped.
+pub struct MemoryDB {
+    state: ArcSwap<Families>,
+    writer: Mutex<()>,
+}
+
+struct MemorySnapshot {
+    state: Arc<Families>,
+}
+
+struct MemoryIter<'a> {
+    iter: Option<Peekable<RangedIter<'a, Vec<u8>, Vec<u8>>>>,
+}
+
+impl MemoryDB {
+    /// Creates an empty database.
+    pub fn new() -> Self {
+        Self::with_families(Families::new())
+    }
+
+    /// Creates a database with the given content.
+    pub(crate) fn with_families(families: Families) -> Self {
+        Self {
+            state: ArcSwap::from_pointee(families),
+            writer: Mutex::new(()),
+        }
+    }
+
+    /// Returns the current version of the content.
+    pub(crate) fn families(&self) -> Arc<Families> {
+        self.state.load_full()
+    }
+
+    /// Applies `update` to a copy of the current version and publishes the copy if the
+    /// update succeeds. Updates are serialized with merges.
+    pub(crate) fn update<F>(&self, update: F) -> Result<()>
+    where
+        F: FnOnce(&mut Families) -> Result<()>,
+    {
+        let _writer = self.writer.lock().unwrap();
+        // Cloning the families only copies the roots of the trees.
+        let mut families = Families::clone(&self.state.load());
+        update(&mut families)?;
+        self.state.store(Arc::new(families));
+        Ok(())
+    }
+
+    pub(crate) fn apply(families: &mut Families, patch: Patch) {
+        for (name, changes) in patch {
+            let family = families.entry(name).or_default();
+            for (key, change) in changes {
+                match change {
+                    Change::Put(value) => {
+                        family.insert(key, value);
+                    }
+                    Change::Delete => {
+                        family.remove(&key);
+                    }
+                    Change::DeletePrefix => {
+                        let keys = family
+                            .range::<_, [u8]>((Included(&key[..]), Unbounded))
+                            .map(|(k, _)| k)
+                            .take_while(|k| k.starts_with(&key))
+                            .cloned()
+                            .collect::<Vec<_>>();
+                        for k in keys {
+                            family.remove(&k);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Default for MemoryDB {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for MemoryDB {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = self.state.load();
+        f.debug_struct("MemoryDB")
+            .field("families", &state.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl Database for MemoryDB {
+    fn snapshot(&self) -> Box<dyn Snapshot> {
+        Box::new(MemorySnapshot {
+            state: self.state.load_full(),
+        })
+    }
+
+    fn merge(&self, patch: Patch) -> Result<()> {
+        self.update(|families| {
+            Self::apply(families, patch);
+            Ok(())
+        })
+    }
+
+    fn merge_sync(&self, patch: Patch) -> Result<()> {
+        self.merge(patch)
+    }
+
+    fn merge_patches(&self, patches: &mut dyn StdIterator<Item = Result<Patch>>) -> Result<()> {
+        self.update(|families| {
+            for patch in patches {
+                Self::apply(families, patch?);
+            }
+            Ok(())
+        })
+    }
+}
+
+impl Snapshot for MemorySnapshot {
+    fn get(&self, name: &str, key: &[u8]) -> Option<Vec<u8>> {
+        self.state
+            .get(name)
+            .and_then(|family| family.get(key).cloned())
+    }
+
+    fn contains(&self, name: &str, key: &[u8]) -> bool {
+        self.state
+            .get(name)
+            .is_some_and(|family| family.contains_key(key))
+    }
+
+    fn iter<'a>(&'a self, name: &str, from: &[u8]) -> Iter<'a> {
+        let iter = self.state.get(name).map(|family| {
+            family
+                .range::<_, [u8]>((Included(from), Unbounded))
+                .peekable()
+        });
+        Box::new(MemoryIter { iter })
+    }
+}
+
+impl<'a> Iterator for MemoryIter<'a> {
+    fn next(&mut self) -> Option<(&[u8], &[u8])> {
+        self.iter
+            .as_mut()
+            .and_then(|iter| iter.next())
+            .map(|(k, v)| (k.as_slice(), v.as_slice()))
+    }
+
+    fn peek(&mut self) -> Option<(&[u8], &[u8])> {
+        self.iter
+            .as_mut()
+            .and_then(|iter| iter.peek())
+            .map(|&(k, v)| (k.as_slice(), v.as_slice()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::storage::Error;
+    use crate::storage::test_utils::entries;
+
+    #[test]
+    fn snapshot_isolation() {
+        let db = MemoryDB::new();
+        let mut fork = db.fork();
+        fork.put("name", vec![1], vec![1]);
+        fork.put("name", vec![2, 1], vec![2]);
+        fork.put("name", vec![2, 2], vec![3]);
+        db.merge(fork.into_patch()).unwrap();
+        let before = db.snapshot();
+
+        let mut fork = db.fork();
+        fork.put("name", vec![1], vec![10]);
+        fork.remove_by_prefix("name", Some(&vec![2]));
+        fork.put("other", vec![1], vec![1]);
+        db.merge(fork.into_patch()).unwrap();
+        let after = db.snapshot();
+
+        assert_eq!(before.get("name", &[1]), Some(vec![1]));
+        assert!(before.contains("name", &[2, 2]));
+        assert!(!before.contains("other", &[1]));
+        assert_eq!(entries(&*before, "name").len(), 3);
+        assert_eq!(entries(&*after, "name"), vec![(vec![1], vec![10])]);
+        assert_eq!(after.get("other", &[1]), Some(vec![1]));
+        assert_eq!(entries(&*after, "missing"), vec![]);
+    }
+
+    #[test]
+    fn merge_patches() {
+        let db = MemoryDB::new();
+        let mut fork = db.fork();
+        fork.put("name", vec![1], vec![1]);
+        fork.put("name", vec![2, 1], vec![2]);
+        let first = fork.into_patch();
+        let mut fork = db.fork();
+        fork.remove_by_prefix("name", Some(&vec![2]));
+        fork.put("name", vec![2, 2], vec![3]);
+        let second = fork.into_patch();
+
+        let failed = vec![Ok(first.clone()), Err(Error::new("broken patch"))];
+        assert!(db.merge_patches(&mut failed.into_iter()).is_err());
+        assert_eq!(entries(&*db.snapshot(), "name"), vec![]);
+
+        db.merge_patches(&mut vec![Ok(first), Ok(second)].into_iter())
+            .unwrap();
+        assert_eq!(
+            entries(&*db.snapshot(), "name"),
+            vec![(vec![1], vec![1]), (vec![2, 2], vec![3])]
+        );
+    }
+
+    #[test]
+    fn concurrent_readers_and_writers() {
+        let db = Arc::new(MemoryDB::new());
+        let writers = (0..4_u8)
+            .map(|writer| {
+                let db = Arc::clone(&db);
+                thread::spawn(move || {
+                    for i in 0..50_u8 {
+                        let mut fork = db.fork();
+                        fork.put("name", vec![writer, i], vec![i]);
+                        db.merge(fork.into_patch()).unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        let reader = {
+            let db = Arc::clone(&db);
+            thread::spawn(move || {
+                let mut last = 0;
+                for _ in 0..100 {
+                    // Versions only grow, and a snapshot never changes under the reader.
+                    let snapshot = db.snapshot();
+                    let len = entries(&*snapshot, "name").len();
+                    assert!(len >= last);
+                    assert_eq!(entries(&*snapshot, "name").len(), len);
+                    last = len;
+                }
+            })
+        };
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        reader.join().unwrap();
+        assert_eq!(entries(&*db.snapshot(), "name").len(), 200);
+    }
+}
@@ -0,0 +1,248 @@
+//! Metadata records describing the layout of column families.
+//!
+//! The first write to an index stores a small [`IndexMetadata`] record (index kind, key and
+//! value type tags and codec version) in the system column family [`METADATA_NAME`].
+//! Opening the column family later with an incompatible type fails with an
+//! `IndexTypeMismatch` error instead of silently misinterpreting the stored data.
+//!
+//! [`IndexMetadata`]: struct.IndexMetadata.html
+//! [`METADATA_NAME`]: constant.METADATA_NAME.html
+
+use std::fmt;
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use rust_decimal::Decimal;
+use uuid::Uuid;
+
+use super::db::{Fork, Snapshot};
+use super::{Error, Result};
+use crate::crypto::Hash;
+use crate::ethkey::{Public, Signature};
+use crate::types::Zero;
+
+/// Name of the system column family holding metadata of other column families.
+pub const METADATA_NAME: &str = "__metadata";
+
+/// Current version of the value encoding.
+pub const CODEC_VERSION: u32 = 1;
+
+/// Stable tag of a key or value type, recorded in the metadata of column families.
+///
+/// Unlike `std::any::type_name`, the tag does not depend on the compiler version, so it must
+/// not be changed once data of the type has been stored. Only indexes recording metadata
+/// require their key and value types to implement this trait.
+pub trait TypeTag {
+    /// Tag of the type.
+    const TYPE_TAG: &'static str;
+}
+
+macro_rules! implement_type_tag {
+    ($($type:ty => $tag:expr),* $(,)*) => {
+        $(
+            impl TypeTag for $type {
+                const TYPE_TAG: &'static str = $tag;
+            }
+        )*
+    };
+}
+
+implement_type_tag! {
+    () => "()",
+    Zero => "Zero",
+    bool => "bool",
+    u8 => "u8",
+    u16 => "u16",
+    u32 => "u32",
+    u64 => "u64",
+    i8 => "i8",
+    i16 => "i16",
+    i32 => "i32",
+    i64 => "i64",
+    String => "String",
+    str => "String",
+    Vec<u8> => "Vec<u8>",
+    DateTime<Utc> => "DateTime<Utc>",
+    Uuid => "Uuid",
+    Decimal => "Decimal",
+    Hash => "Hash",
+    Public => "Public",
+    Signature => "Signature",
+}
+
+/// Kind of an index stored in a column family.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum IndexKind {
+    /// A single value.
+    Entry,
+    /// A list of values indexed by a `u64`.
+    List,
+    /// A map from keys to values.
+    Map,
+    /// A set of keys.
+    KeySet,
+    /// A set of values indexed by their hashes.
+    ValueSet,
+    /// An authenticated list.
+    ProofList,
+    /// An authenticated map.
+    ProofMap,
+    /// A content-addressed blob store.
+    Blobs,
+    /// A map with expiring entries.
+    TtlMap,
+}
+
+/// Layout of the data stored in a column family.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IndexMetadata {
+    /// Kind of the index.
+    pub kind: IndexKind,
+    /// Type tag of the keys.
+    pub key_type: String,
+    /// Type tag of the values.
+    pub value_type: String,
+    /// Version of the value encoding.
+    pub version: u32,
+}
+
+impl IndexMetadata {
+    /// Creates metadata of an index of the given kind with keys of type `K` and values
+    /// of type `V`, using the current codec version. The types are recorded by their
+    /// `TYPE_TAG`s.
+    pub fn new<K, V>(kind: IndexKind) -> Self
+    where
+        K: TypeTag + ?Sized,
+        V: TypeTag,
+    {
+        Self {
+            kind,
+            key_type: K::TYPE_TAG.to_string(),
+            value_type: V::TYPE_TAG.to_string(),
+            version: CODEC_VERSION,
+        }
+    }
+
+    /// Returns `true` if data stored with the `stored` metadata can be read as described
+    /// by this metadata.
+    ///
+    /// Kind and type tags must match exactly; data written by a newer codec version cannot
+    /// be read.
+    pub fn is_compatible(&self, stored: &IndexMetadata) -> bool {
+        self.kind == stored.kind
+            && self.key_type == stored.key_type
+            && self.value_type == stored.value_type
+            && stored.version <= self.version
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap()
+    }
+}
+
+impl fmt::Display for IndexMetadata {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:?}<{}, {}> v{}",
+            self.kind, self.key_type, self.value_type, self.version
+        )
+    }
+}
+
+/// Returns the metadata of the column family with the given `name`, or `None` if nothing
+/// has been written to it yet.
+///
+/// # Errors
+///
+/// Returns a `Decode` error if the stored metadata record is malformed.
+pub fn get_metadata(view: &dyn Snapshot, name: &str) -> Result<Option<IndexMetadata>> {
+    match view.get(METADATA_NAME, name.as_bytes()) {
+        Some(bytes) => serde_json::from_slice(&bytes)
+            .map(Some)
+            .map_err(|e| Error::Decode {
+                name: METADATA_NAME.to_string(),
+                key: name.as_bytes().to_vec(),
+                cause: Arc::new(e.into()),
+            }),
+        None => Ok(None),
+    }
+}
+
+/// Checks that the column family with the given `name` may be read as described by
+/// the `expected` metadata. A column family without metadata passes the check.
+///
+/// # Errors
+///
+/// Returns an `IndexTypeMismatch` error if the stored metadata is incompatible.
+pub fn check_metadata(view: &dyn Snapshot, name: &str, expected: &IndexMetadata) -> Result<()> {
+    match get_metadata(view, name)? {
+        Some(ref stored) if !expected.is_compatible(stored) => Err(Error::IndexTypeMismatch {
+            name: name.to_string(),
+            expected: expected.to_string(),
+            actual: stored.to_string(),
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Checks the metadata of the column family with the given `name` before writing to it,
+/// storing the `expected` metadata if the column family has none yet.
+///
+/// # Errors
+///
+/// Returns an `IndexTypeMismatch` error if the stored metadata is incompatible.
+pub fn ensure_metadata(fork: &mut Fork, name: &str, expected: &IndexMetadata) -> Result<()> {
+    if get_metadata(fork, name)?.is_none() {
+        fork.put(METADATA_NAME, name.as_bytes().to_vec(), expected.to_bytes());
+        return Ok(());
+    }
+    check_metadata(fork, name, expected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::Hash;
+    use crate::storage::db::Database;
+    use crate::storage::test_utils::TestDB;
+
+    #[test]
+    fn metadata_mismatch() {
+        let db = TestDB::new();
+        let map = IndexMetadata::new::<Hash, u64>(IndexKind::Map);
+        let list = IndexMetadata::new::<u64, u64>(IndexKind::List);
+        let other_map = IndexMetadata::new::<Hash, String>(IndexKind::Map);
+        assert_eq!(other_map.to_string(), "Map<Hash, String> v1");
+
+        let mut fork = db.fork();
+        assert!(check_metadata(&fork, "balances", &list).is_ok());
+        ensure_metadata(&mut fork, "balances", &map).unwrap();
+        ensure_metadata(&mut fork, "balances", &map).unwrap();
+        db.merge(fork.into_patch()).unwrap();
+
+        let snapshot = db.snapshot();
+        assert_eq!(
+            get_metadata(&*snapshot, "balances").unwrap(),
+            Some(map.clone())
+        );
+        assert!(check_metadata(&*snapshot, "balances", &map).is_ok());
+        match check_metadata(&*snapshot, "balances", &list) {
+            Err(Error::IndexTypeMismatch { ref name, .. }) => assert_eq!(name, "balances"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        let mut fork = db.fork();
+        assert!(ensure_metadata(&mut fork, "balances", &other_map).is_err());
+    }
+
+    #[test]
+    fn codec_version() {
+        let current = IndexMetadata::new::<u64, u64>(IndexKind::List);
+        let mut newer = current.clone();
+        newer.version += 1;
+        let mut older = current.clone();
+        older.version -= 1;
+        assert!(current.is_compatible(&older));
+        assert!(!current.is_compatible(&newer));
+    }
+}
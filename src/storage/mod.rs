@@ -1,9 +1,38 @@
+pub mod address;
+pub mod blobs;
+pub mod bloom;
+pub mod bulk;
+pub mod cache;
+pub mod compression;
+pub mod db;
+pub mod diff;
+pub mod dump;
 pub mod error;
+pub mod filedb;
+pub mod fsck;
 pub mod hash;
 #[macro_use]
 pub mod keys;
+pub mod memorydb;
+pub mod metadata;
+pub mod overlay;
+pub mod replication;
+#[cfg(feature = "rocksdb")]
+pub mod rocksdb;
+pub mod routing;
+pub mod secondary;
+pub mod spill;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod state;
+pub mod ttl;
+pub mod typed;
 #[macro_use]
 pub mod values;
+pub mod wal;
+
+#[cfg(test)]
+mod test_utils;
 
 pub use self::error::Error;
 pub use crate::encoding;
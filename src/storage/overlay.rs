@@ -0,0 +1,268 @@
+//! A `Database` writing only the differences over a read-only base database.
+//!
+//! `OverlayDatabase` keeps a stack of delta layers on top of the base: reads go through the
+//! layers from the top down to the base, so an upper layer wins over lower ones and a removal
+//! in a layer shadows the entries of all layers below it. Patches are merged into the top
+//! layer only and the base is never written to, which makes the overlay suitable for test
+//! networks and dry-runs over a large exported state.
+//!
+//! Layers are shared with snapshots and never modified while shared. A patch merged while
+//! a snapshot holds the top layer becomes a new layer instead; to keep reads fast, the new
+//! layer is combined with the layers below it as long as they are not larger than it, so
+//! every change is copied a logarithmic number of times.
+//!
+//! The top layer may be frozen with [`push_layer`] to get a cheap checkpoint, and the
+//! accumulated layers may be combined later with [`flatten`] or extracted as a single patch
+//! with [`delta`].
+//!
+//! [`push_layer`]: struct.OverlayDatabase.html#method.push_layer
+//! [`flatten`]: struct.OverlayDatabase.html#method.flatten
+//! [`delta`]: struct.OverlayDatabase.html#method.delta
+
+use std::sync::{Arc, RwLock};
+
+use super::Result;
+use super::db::{Database, ForkIter, Iter, Patch, Snapshot};
+
+/// Database reading through a stack of delta layers over a read-only base database.
+pub struct OverlayDatabase<T: Database> {
+    base: T,
+    // Delta layers from the bottom to the top; there is always at least one layer.
+    layers: RwLock<Vec<Arc<Patch>>>,
+}
+
+struct OverlaySnapshot {
+    base: Box<dyn Snapshot>,
+    layers: Vec<Arc<Patch>>,
+}
+
+impl<T: Database> OverlayDatabase<T> {
+    /// Creates an overlay with a single empty layer over the `base` database.
+    pub fn new(base: T) -> Self {
+        Self {
+            base,
+            layers: RwLock::new(vec![Arc::new(Patch::new())]),
+        }
+    }
+
+    /// Returns the base database.
+    pub fn base(&self) -> &T {
+        &self.base
+    }
+
+    /// Returns the number of delta layers, including the ones added by merges while
+    /// the top layer was held by a snapshot.
+    pub fn layers(&self) -> usize {
+        self.layers.read().unwrap().len()
+    }
+
+    /// Freezes the top layer and starts a new empty one for subsequent merges.
+    pub fn push_layer(&self) {
+        self.layers.write().unwrap().push(Arc::new(Patch::new()));
+    }
+
+    /// Combines all delta layers into a single layer. The view of the database is not
+    /// changed.
+    pub fn flatten(&self) {
+        let mut layers = self.layers.write().unwrap();
+        let delta = combine(&layers);
+        *layers = vec![Arc::new(delta)];
+    }
+
+    /// Returns all the differences from the base database as a single patch, which may be
+    /// merged into a copy of the base to persist the state of the overlay.
+    pub fn delta(&self) -> Patch {
+        combine(&self.layers.read().unwrap())
+    }
+}
+
+fn combine(layers: &[Arc<Patch>]) -> Patch {
+    let mut delta = Patch::new();
+    for layer in layers {
+        delta.merge(Patch::clone(layer));
+    }
+    delta
+}
+
+// Combines the top layer with the layers below it which are not larger than it.
+fn compact(layers: &mut Vec<Arc<Patch>>) {
+    while layers.len() > 1 {
+        let top = layers.len() - 1;
+        if layers[top].len() < layers[top - 1].len() {
+            break;
+        }
+        let delta = combine(&layers[top - 1..]);
+        layers.truncate(top - 1);
+        layers.push(Arc::new(delta));
+    }
+}
+
+impl<T: Database> Database for OverlayDatabase<T> {
+    fn snapshot(&self) -> Box<dyn Snapshot> {
+        // The base snapshot is taken while holding the lock, so no layer is missed.
+        let layers = self.layers.read().unwrap();
+        Box::new(OverlaySnapshot {
+            base: self.base.snapshot(),
+            layers: layers.clone(),
+        })
+    }
+
+    fn merge(&self, patch: Patch) -> Result<()> {
+        let mut layers = self.layers.write().unwrap();
+        let top = layers.last_mut().expect("overlay has no layers");
+        if let Some(top) = Arc::get_mut(top) {
+            top.merge(patch);
+            return Ok(());
+        }
+        // The top layer is held by a snapshot, so it is not copied.
+        layers.push(Arc::new(patch));
+        compact(&mut layers);
+        Ok(())
+    }
+
+    fn merge_sync(&self, patch: Patch) -> Result<()> {
+        self.merge(patch)
+    }
+}
+
+impl Snapshot for OverlaySnapshot {
+    fn get(&self, name: &str, key: &[u8]) -> Option<Vec<u8>> {
+        for layer in self.layers.iter().rev() {
+            if let Some(value) = layer.changes(name).and_then(|c| c.lookup(key)) {
+                return value.cloned();
+            }
+        }
+        self.base.get(name, key)
+    }
+
+    fn contains(&self, name: &str, key: &[u8]) -> bool {
+        for layer in self.layers.iter().rev() {
+            if let Some(value) = layer.changes(name).and_then(|c| c.lookup(key)) {
+                return value.is_some();
+            }
+        }
+        self.base.contains(name, key)
+    }
+
+    fn iter<'a>(&'a self, name: &str, from: &[u8]) -> Iter<'a> {
+        self.layers
+            .iter()
+            .fold(self.base.iter(name, from), |iter, layer| {
+                Box::new(ForkIter::new(iter, layer.changes(name), from))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::test_utils::{TestDB, entries};
+
+    fn base() -> TestDB {
+        let base = TestDB::new();
+        let mut fork = base.fork();
+        for i in 0..4_u8 {
+            fork.put("a", vec![i], vec![i]);
+            fork.put("a", vec![i, 0], vec![i]);
+        }
+        fork.put("b", vec![1], vec![1]);
+        base.merge(fork.into_patch()).unwrap();
+        base
+    }
+
+    #[test]
+    fn layers_shadow_lower_ones() {
+        let db = OverlayDatabase::new(base());
+        let mut fork = db.fork();
+        fork.put("a", vec![1], vec![10]);
+        fork.remove("a", vec![2]);
+        fork.remove_by_prefix("a", Some(&vec![3]));
+        db.merge(fork.into_patch()).unwrap();
+
+        db.push_layer();
+        let mut fork = db.fork();
+        fork.put("a", vec![2], vec![20]);
+        fork.put("a", vec![3, 1], vec![30]);
+        fork.remove("b", vec![1]);
+        db.merge(fork.into_patch()).unwrap();
+        assert_eq!(db.layers(), 2);
+
+        let expected = vec![
+            (vec![0], vec![0]),
+            (vec![0, 0], vec![0]),
+            (vec![1], vec![10]),
+            (vec![1, 0], vec![1]),
+            (vec![2], vec![20]),
+            (vec![2, 0], vec![2]),
+            (vec![3, 1], vec![30]),
+        ];
+        let snapshot = db.snapshot();
+        assert_eq!(entries(&*snapshot, "a"), expected);
+        assert_eq!(snapshot.get("a", &[1]), Some(vec![10]));
+        assert_eq!(snapshot.get("a", &[3, 0]), None);
+        assert!(!snapshot.contains("a", &[3]));
+        assert!(!snapshot.contains("b", &[1]));
+
+        let mut iter = snapshot.iter("a", &[2, 0]);
+        assert_eq!(iter.peek(), Some((&[2_u8, 0][..], &[2_u8][..])));
+        assert_eq!(iter.next(), Some((&[2_u8, 0][..], &[2_u8][..])));
+        assert_eq!(iter.next(), Some((&[3_u8, 1][..], &[30_u8][..])));
+        assert_eq!(iter.next(), None);
+
+        // The base is not modified.
+        assert_eq!(entries(&*db.base().snapshot(), "a").len(), 8);
+        assert_eq!(db.base().snapshot().get("b", &[1]), Some(vec![1]));
+
+        db.flatten();
+        assert_eq!(db.layers(), 1);
+        assert_eq!(entries(&*db.snapshot(), "a"), expected);
+        assert_eq!(entries(&*snapshot, "a"), expected);
+
+        let copy = base();
+        copy.merge(db.delta()).unwrap();
+        assert_eq!(entries(&*copy.snapshot(), "a"), expected);
+        assert!(!copy.snapshot().contains("b", &[1]));
+    }
+
+    #[test]
+    fn snapshots_are_isolated() {
+        let db = OverlayDatabase::new(base());
+        let before = db.snapshot();
+        let mut fork = db.fork();
+        fork.put("a", vec![0], vec![5]);
+        db.merge(fork.into_patch()).unwrap();
+
+        assert_eq!(before.get("a", &[0]), Some(vec![0]));
+        assert_eq!(db.snapshot().get("a", &[0]), Some(vec![5]));
+    }
+
+    #[test]
+    fn shared_layers_are_not_copied() {
+        let db = OverlayDatabase::new(base());
+        let mut snapshots = Vec::new();
+        for i in 0..16_u8 {
+            snapshots.push(db.snapshot());
+            let mut fork = db.fork();
+            fork.put("c", vec![i], vec![i]);
+            db.merge(fork.into_patch()).unwrap();
+        }
+        // Layers of 16 changes in total are combined by sizes like a binary counter.
+        assert_eq!(db.layers(), 1);
+        // A layer which is not shared is merged into.
+        let mut fork = db.fork();
+        fork.put("c", vec![16], vec![16]);
+        db.merge(fork.into_patch()).unwrap();
+        assert_eq!(db.layers(), 1);
+        snapshots.push(db.snapshot());
+        let mut fork = db.fork();
+        fork.put("c", vec![17], vec![17]);
+        db.merge(fork.into_patch()).unwrap();
+        assert_eq!(db.layers(), 2);
+
+        assert_eq!(entries(&*db.snapshot(), "c").len(), 18);
+        assert_eq!(entries(&*snapshots[16], "c").len(), 17);
+        for (i, snapshot) in snapshots.iter().take(16).enumerate() {
+            assert_eq!(entries(&**snapshot, "c").len(), i);
+        }
+    }
+}
@@ -0,0 +1,561 @@
+//! Primary-to-replica replication by shipping merged patches.
+//!
+//! A primary database wrapped into [`ReplicatedDB`] appends each merged patch to the
+//! replication log (the system column family [`REPLICATION_LOG_NAME`]) as an entry with
+//! a sequence number and a hash chained to the previous entry. The entry is written in
+//! the same patch as the data it describes, so the log never diverges from the state.
+//!
+//! A [`Replica`] applies entries strictly in order: an entry is rejected if its sequence
+//! number does not directly follow the last applied one or if it is not chained to the
+//! last applied entry. Both sides record the last entry they have written or applied in
+//! the column family [`REPLICATION_HEAD_NAME`].
+//!
+//! The primary may truncate the log once all replicas have applied its beginning. The
+//! position of the last removed entry is kept as the anchor of the chain, so the first
+//! remaining entry can still be verified.
+//!
+//! Entries travel over any byte stream (e.g. a local socket or a pipe) as length-prefixed,
+//! checksummed records (see the [`wal`] module). A replica starts a session by sending the
+//! sequence number of the next entry it needs as a big-endian `u64`; the primary answers
+//! with all entries starting from that number and closes the stream. Entries that have
+//! been truncated cannot be served.
+//!
+//! [`ReplicatedDB`]: struct.ReplicatedDB.html
+//! [`Replica`]: struct.Replica.html
+//! [`REPLICATION_LOG_NAME`]: constant.REPLICATION_LOG_NAME.html
+//! [`REPLICATION_HEAD_NAME`]: constant.REPLICATION_HEAD_NAME.html
+//! [`wal`]: ../wal/index.html
+
+use std::io::{self, Read, Write};
+use std::sync::Mutex;
+
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
+
+use crate::crypto::{HASH_SIZE, Hash, HashStream};
+
+use super::db::{Database, Fork, Patch, Snapshot};
+use super::wal;
+use super::{Error, Result};
+
+/// Name of the system column family holding the replication log.
+pub const REPLICATION_LOG_NAME: &str = "__replication_log";
+/// Name of the system column family holding the position of the last written or applied
+/// replication entry.
+pub const REPLICATION_HEAD_NAME: &str = "__replication_head";
+
+pub(crate) const HEAD_KEY: &[u8] = b"head";
+pub(crate) const ANCHOR_KEY: &[u8] = b"anchor";
+
+/// An entry of the replication log.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplicationEntry {
+    /// Sequence number of the entry; the first entry has number `1`.
+    pub seq: u64,
+    /// Hash of the previous entry, or `Hash::zero()` for the first one.
+    pub prev_hash: Hash,
+    /// Encoded patch.
+    pub patch: Vec<u8>,
+}
+
+impl ReplicationEntry {
+    /// Creates an entry for the patch following the entry with the given position.
+    pub fn new(prev: &Head, patch: &Patch) -> Self {
+        Self {
+            seq: prev.seq + 1,
+            prev_hash: prev.hash,
+            patch: wal::encode_patch(patch),
+        }
+    }
+
+    /// Returns the hash of the entry, which covers its sequence number, the hash of the
+    /// previous entry and the patch.
+    pub fn hash(&self) -> Hash {
+        HashStream::new()
+            .update(&self.seq.to_be_bytes())
+            .update(self.prev_hash.as_ref())
+            .update(&self.patch)
+            .hash()
+    }
+
+    /// Returns the position of the log after this entry.
+    pub fn head(&self) -> Head {
+        Head {
+            seq: self.seq,
+            hash: self.hash(),
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(8 + HASH_SIZE + self.patch.len());
+        bytes.write_u64::<BigEndian>(self.seq).unwrap();
+        bytes.extend_from_slice(self.prev_hash.as_ref());
+        bytes.extend_from_slice(&self.patch);
+        bytes
+    }
+
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < 8 + HASH_SIZE {
+            return None;
+        }
+        Some(Self {
+            seq: BigEndian::read_u64(&bytes[..8]),
+            prev_hash: Hash::new(&bytes[8..8 + HASH_SIZE]),
+            patch: bytes[8 + HASH_SIZE..].to_vec(),
+        })
+    }
+}
+
+/// Position in the replication log: the sequence number and the hash of the last entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Head {
+    /// Sequence number of the last entry, or `0` if there are no entries.
+    pub seq: u64,
+    /// Hash of the last entry, or `Hash::zero()` if there are no entries.
+    pub hash: Hash,
+}
+
+impl Head {
+    /// Returns the position of an empty log.
+    pub fn empty() -> Self {
+        Self {
+            seq: 0,
+            hash: Hash::zero(),
+        }
+    }
+
+    /// Returns the position stored in the view.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `Corrupted` error if the stored position is malformed.
+    pub fn read(view: &dyn Snapshot) -> Result<Self> {
+        Self::read_at(view, HEAD_KEY)
+    }
+
+    /// Returns the position of the last truncated entry of the log stored in the view.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `Corrupted` error if the stored position is malformed.
+    pub fn read_anchor(view: &dyn Snapshot) -> Result<Self> {
+        Self::read_at(view, ANCHOR_KEY)
+    }
+
+    fn read_at(view: &dyn Snapshot, key: &[u8]) -> Result<Self> {
+        match view.get(REPLICATION_HEAD_NAME, key) {
+            Some(ref value) if value.len() == 8 + HASH_SIZE => Ok(Self {
+                seq: BigEndian::read_u64(&value[..8]),
+                hash: Hash::new(&value[8..]),
+            }),
+            Some(_) => Err(Error::Corrupted {
+                name: REPLICATION_HEAD_NAME.to_string(),
+                key: key.to_vec(),
+                reason: "malformed replication head".to_string(),
+            }),
+            None => Ok(Self::empty()),
+        }
+    }
+
+    fn write(&self, fork: &mut Fork) {
+        self.write_at(fork, HEAD_KEY);
+    }
+
+    fn write_at(&self, fork: &mut Fork, key: &[u8]) {
+        let mut value = Vec::with_capacity(8 + HASH_SIZE);
+        value.write_u64::<BigEndian>(self.seq).unwrap();
+        value.extend_from_slice(self.hash.as_ref());
+        fork.put(REPLICATION_HEAD_NAME, key.to_vec(), value);
+    }
+}
+
+fn decode_entry(key: &[u8], value: &[u8]) -> Result<ReplicationEntry> {
+    ReplicationEntry::from_bytes(value)
+        .filter(|entry| entry.seq.to_be_bytes() == key)
+        .ok_or_else(|| Error::Corrupted {
+            name: REPLICATION_LOG_NAME.to_string(),
+            key: key.to_vec(),
+            reason: "malformed replication entry".to_string(),
+        })
+}
+
+// Checks that the entries starting from `from` have not been truncated.
+fn check_truncated(view: &dyn Snapshot, from: u64) -> Result<()> {
+    let anchor = Head::read_anchor(view)?;
+    if from <= anchor.seq {
+        return Err(Error::ReplicationGap {
+            expected: from,
+            actual: anchor.seq + 1,
+        });
+    }
+    Ok(())
+}
+
+/// Writes the entry to the stream as a single record.
+pub fn write_entry<W: Write>(writer: &mut W, entry: &ReplicationEntry) -> Result<()> {
+    wal::write_record(writer, &entry.to_bytes())
+        .map_err(|e| Error::io("sending a replication entry", e))
+}
+
+/// Reads the next entry from the stream, or `None` if the stream is closed.
+///
+/// # Errors
+///
+/// Returns an `Io` error if the stream fails or contains a malformed record.
+pub fn read_entry<R: Read>(reader: &mut R) -> Result<Option<ReplicationEntry>> {
+    let context = "receiving a replication entry";
+    match wal::read_record(reader).map_err(|e| Error::io(context, e))? {
+        Some(bytes) => ReplicationEntry::from_bytes(&bytes)
+            .map(Some)
+            .ok_or_else(|| {
+                let e = io::Error::new(io::ErrorKind::InvalidData, "malformed replication entry");
+                Error::io(context, e)
+            }),
+        None => Ok(None),
+    }
+}
+
+/// Primary database recording merged patches in the replication log.
+pub struct ReplicatedDB<T: Database> {
+    inner: T,
+    head: Mutex<Head>,
+}
+
+impl<T: Database> ReplicatedDB<T> {
+    /// Wraps the given database, continuing its replication log.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `Corrupted` error if the stored position of the log is malformed.
+    pub fn new(inner: T) -> Result<Self> {
+        let head = Head::read(&*inner.snapshot())?;
+        Ok(Self {
+            inner,
+            head: Mutex::new(head),
+        })
+    }
+
+    /// Returns a reference to the underlying database.
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    /// Returns the position of the last entry of the log.
+    pub fn head(&self) -> Head {
+        *self.head.lock().unwrap()
+    }
+
+    /// Returns the position of the last truncated entry of the log, or an empty position
+    /// if the log has never been truncated.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `Corrupted` error if the stored position is malformed.
+    pub fn anchor(&self) -> Result<Head> {
+        Head::read_anchor(&*self.inner.snapshot())
+    }
+
+    /// Returns at most `limit` entries of the log starting from the given sequence number.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `ReplicationGap` error if the entry with the given sequence number has
+    /// been truncated, and a `Corrupted` error if a stored entry is malformed.
+    pub fn entries(&self, from: u64, limit: usize) -> Result<Vec<ReplicationEntry>> {
+        let snapshot = self.inner.snapshot();
+        check_truncated(&*snapshot, from)?;
+        let mut entries = Vec::new();
+        let mut iter = snapshot.iter(REPLICATION_LOG_NAME, &from.to_be_bytes());
+        while entries.len() < limit {
+            match iter.next() {
+                Some((key, value)) => entries.push(decode_entry(key, value)?),
+                None => break,
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Removes the entries of the log with sequence numbers less than `before` and
+    /// records the last removed entry as the anchor of the chain. Entries following
+    /// the head of the log are never removed. Returns the number of removed entries.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `Corrupted` error if a stored entry is malformed.
+    pub fn truncate(&self, before: u64) -> Result<usize> {
+        // Holding the head serializes truncation with writes to the log.
+        let head = self.head.lock().unwrap();
+        let snapshot = self.inner.snapshot();
+        let before = before.min(head.seq + 1);
+        let mut anchor = Head::read_anchor(&*snapshot)?;
+        let mut fork = self.inner.fork();
+        let mut removed = 0;
+        let mut iter = snapshot.iter(REPLICATION_LOG_NAME, &[]);
+        while let Some((key, value)) = iter.next() {
+            let entry = decode_entry(key, value)?;
+            if entry.seq >= before {
+                break;
+            }
+            fork.remove(REPLICATION_LOG_NAME, key.to_vec());
+            anchor = entry.head();
+            removed += 1;
+        }
+        if removed > 0 {
+            anchor.write_at(&mut fork, ANCHOR_KEY);
+            self.inner.merge_sync(fork.into_patch())?;
+        }
+        Ok(removed)
+    }
+
+    /// Serves a replica session: reads the requested sequence number from the `reader`
+    /// and writes all entries starting from it to the `writer`. Returns the number of
+    /// written entries.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `ReplicationGap` error if the requested entry has been truncated.
+    pub fn serve<R: Read, W: Write>(&self, reader: &mut R, writer: &mut W) -> Result<usize> {
+        let from = reader
+            .read_u64::<BigEndian>()
+            .map_err(|e| Error::io("receiving a replication request", e))?;
+        let snapshot = self.inner.snapshot();
+        check_truncated(&*snapshot, from)?;
+        let mut written = 0;
+        let mut iter = snapshot.iter(REPLICATION_LOG_NAME, &from.to_be_bytes());
+        while let Some((key, value)) = iter.next() {
+            write_entry(writer, &decode_entry(key, value)?)?;
+            written += 1;
+        }
+        writer
+            .flush()
+            .map_err(|e| Error::io("sending replication entries", e))?;
+        Ok(written)
+    }
+
+    fn write(&self, patch: Patch, sync: bool) -> Result<()> {
+        let mut head = self.head.lock().unwrap();
+        let entry = ReplicationEntry::new(&head, &patch);
+        let mut fork = self.inner.fork();
+        fork.merge(patch);
+        fork.put(
+            REPLICATION_LOG_NAME,
+            entry.seq.to_be_bytes().to_vec(),
+            entry.to_bytes(),
+        );
+        entry.head().write(&mut fork);
+        if sync {
+            self.inner.merge_sync(fork.into_patch())?;
+        } else {
+            self.inner.merge(fork.into_patch())?;
+        }
+        *head = entry.head();
+        Ok(())
+    }
+}
+
+impl<T: Database> Database for ReplicatedDB<T> {
+    fn snapshot(&self) -> Box<dyn Snapshot> {
+        self.inner.snapshot()
+    }
+
+    fn fork(&self) -> Fork {
+        self.inner.fork()
+    }
+
+    fn merge(&self, patch: Patch) -> Result<()> {
+        self.write(patch, false)
+    }
+
+    fn merge_sync(&self, patch: Patch) -> Result<()> {
+        self.write(patch, true)
+    }
+}
+
+/// Replica applying entries of the replication log of a primary database.
+#[derive(Debug)]
+pub struct Replica<T: Database> {
+    db: T,
+    head: Mutex<Head>,
+}
+
+impl<T: Database> Replica<T> {
+    /// Wraps the given database, continuing from the last applied entry.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `Corrupted` error if the stored position of the log is malformed.
+    pub fn new(db: T) -> Result<Self> {
+        let head = Head::read(&*db.snapshot())?;
+        Ok(Self {
+            db,
+            head: Mutex::new(head),
+        })
+    }
+
+    /// Returns a reference to the replicated database.
+    pub fn database(&self) -> &T {
+        &self.db
+    }
+
+    /// Returns the position of the last applied entry.
+    pub fn head(&self) -> Head {
+        *self.head.lock().unwrap()
+    }
+
+    /// Applies the entry on top of the last applied one.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `ReplicationGap` error if the entry does not directly follow the last
+    /// applied one, and a `ReplicationMismatch` error if it is not chained to it.
+    pub fn apply(&self, entry: &ReplicationEntry) -> Result<()> {
+        let mut head = self.head.lock().unwrap();
+        if entry.seq != head.seq + 1 {
+            return Err(Error::ReplicationGap {
+                expected: head.seq + 1,
+                actual: entry.seq,
+            });
+        }
+        if entry.prev_hash != head.hash {
+            return Err(Error::ReplicationMismatch { seq: entry.seq });
+        }
+        let patch = wal::decode_patch(&entry.patch)
+            .map_err(|e| Error::io("decoding a replicated patch", e))?;
+        let mut fork = self.db.fork();
+        fork.merge(patch);
+        entry.head().write(&mut fork);
+        self.db.merge_sync(fork.into_patch())?;
+        *head = entry.head();
+        Ok(())
+    }
+
+    /// Requests entries following the last applied one by writing to the `writer`, then
+    /// applies entries read from the `reader` until the stream is closed. Returns
+    /// the number of applied entries.
+    pub fn sync<R: Read, W: Write>(&self, reader: &mut R, writer: &mut W) -> Result<usize> {
+        let next = self.head().seq + 1;
+        writer
+            .write_u64::<BigEndian>(next)
+            .and_then(|()| writer.flush())
+            .map_err(|e| Error::io("sending a replication request", e))?;
+        let mut applied = 0;
+        while let Some(entry) = read_entry(reader)? {
+            self.apply(&entry)?;
+            applied += 1;
+        }
+        Ok(applied)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memorydb::MemoryDB;
+
+    fn put(db: &dyn Database, key: u8, value: u8) {
+        let mut fork = db.fork();
+        fork.put("data", vec![key], vec![value]);
+        db.merge(fork.into_patch()).unwrap();
+    }
+
+    #[test]
+    fn apply_in_order() {
+        let primary = ReplicatedDB::new(MemoryDB::new()).unwrap();
+        put(&primary, 1, 1);
+        put(&primary, 2, 2);
+        let mut fork = primary.fork();
+        fork.remove_by_prefix("data", None);
+        fork.put("data", vec![3], vec![3]);
+        primary.merge(fork.into_patch()).unwrap();
+        assert_eq!(primary.head().seq, 3);
+
+        let entries = primary.entries(1, usize::MAX).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[1].prev_hash, entries[0].hash());
+
+        let replica = Replica::new(MemoryDB::new()).unwrap();
+        match replica.apply(&entries[1]) {
+            Err(Error::ReplicationGap { expected, actual }) => {
+                assert_eq!((expected, actual), (1, 2))
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        replica.apply(&entries[0]).unwrap();
+        let mut forged = entries[1].clone();
+        forged.prev_hash = Hash::zero();
+        match replica.apply(&forged) {
+            Err(Error::ReplicationMismatch { seq }) => assert_eq!(seq, 2),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        replica.apply(&entries[1]).unwrap();
+        assert!(replica.apply(&entries[1]).is_err());
+        replica.apply(&entries[2]).unwrap();
+
+        assert_eq!(replica.head(), primary.head());
+        let snapshot = replica.database().snapshot();
+        assert_eq!(snapshot.get("data", &[1]), None);
+        assert_eq!(snapshot.get("data", &[3]), Some(vec![3]));
+        assert_eq!(Head::read(&*snapshot).unwrap(), primary.head());
+
+        assert_eq!(primary.entries(2, 1).unwrap(), vec![entries[1].clone()]);
+        assert_eq!(primary.truncate(3).unwrap(), 2);
+        assert_eq!(primary.anchor().unwrap(), entries[1].head());
+        assert_eq!(primary.entries(3, usize::MAX).unwrap(), vec![entries[2].clone()]);
+        match primary.entries(2, usize::MAX) {
+            Err(Error::ReplicationGap { expected, actual }) => {
+                assert_eq!((expected, actual), (2, 3))
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(primary.truncate(10).unwrap(), 1);
+        assert_eq!(primary.anchor().unwrap(), primary.head());
+        assert_eq!(primary.entries(4, usize::MAX).unwrap(), vec![]);
+
+        // Both sides continue from the stored position.
+        let primary = ReplicatedDB::new(primary.inner).unwrap();
+        assert_eq!(primary.head().seq, 3);
+        let replica = Replica::new(replica.db).unwrap();
+        assert_eq!(replica.head().seq, 3);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn stream_protocol() {
+        use std::net::Shutdown;
+        use std::os::unix::net::UnixStream;
+        use std::sync::Arc;
+        use std::thread;
+
+        let primary = Arc::new(ReplicatedDB::new(MemoryDB::new()).unwrap());
+        let replica = Replica::new(MemoryDB::new()).unwrap();
+        put(&*primary, 1, 1);
+        put(&*primary, 2, 2);
+
+        for &expected in &[2, 0] {
+            let (primary_end, replica_end) = UnixStream::pair().unwrap();
+            let server = {
+                let primary = Arc::clone(&primary);
+                thread::spawn(move || {
+                    let served = primary.serve(&mut &primary_end, &mut &primary_end);
+                    primary_end.shutdown(Shutdown::Write).unwrap();
+                    served.unwrap()
+                })
+            };
+            let applied = replica.sync(&mut &replica_end, &mut &replica_end).unwrap();
+            assert_eq!(server.join().unwrap(), expected);
+            assert_eq!(applied, expected);
+        }
+        assert_eq!(replica.head(), primary.head());
+        assert_eq!(
+            replica.database().snapshot().get("data", &[2]),
+            Some(vec![2])
+        );
+
+        // A truncated record is not applied.
+        let replica = Replica::new(MemoryDB::new()).unwrap();
+        let mut response = Vec::new();
+        write_entry(&mut response, &primary.entries(1, usize::MAX).unwrap()[0]).unwrap();
+        response.pop();
+        assert!(replica.sync(&mut &response[..], &mut Vec::new()).is_err());
+        assert_eq!(replica.head(), Head::empty());
+    }
+}
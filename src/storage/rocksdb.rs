@@ -0,0 +1,356 @@
+//! A `Database` implementation on top of [RocksDB](https://rocksdb.org).
+//!
+//! Available with the `rocksdb` cargo feature. Each column family of the storage is mapped
+//! to a RocksDB column family with the same name; column families are created on demand by
+//! the first merged patch touching them. A patch is written as a single atomic
+//! `WriteBatch`, with range tombstones translated into `delete_range` operations;
+//! `merge_patches` adds all its patches to one batch, which is written once they are read.
+
+use std::collections::HashMap;
+use std::io;
+use std::iter::{self, Iterator as StdIterator};
+use std::mem;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use rocksdb::{
+    BoundColumnFamily, DBRawIteratorWithThreadMode, DBWithThreadMode, MultiThreaded, Options,
+    SnapshotWithThreadMode, WriteBatch, WriteOptions,
+};
+
+use super::db::{Change, Database, Iter, Iterator, Patch, Snapshot, next_prefix};
+use super::{Error, Result};
+
+type DB = DBWithThreadMode<MultiThreaded>;
+
+/// Options of the `RocksDB` database.
+#[derive(Debug, Clone)]
+pub struct RocksDBOptions {
+    /// Whether to create the database if it is missing. Default value is `true`.
+    pub create_if_missing: bool,
+    /// Maximum number of open files; `-1` means no limit. Default value is `-1`.
+    pub max_open_files: i32,
+}
+
+impl Default for RocksDBOptions {
+    fn default() -> Self {
+        Self {
+            create_if_missing: true,
+            max_open_files: -1,
+        }
+    }
+}
+
+impl RocksDBOptions {
+    fn to_rocksdb(&self) -> Options {
+        let mut options = Options::default();
+        options.create_if_missing(self.create_if_missing);
+        options.create_missing_column_families(true);
+        options.set_max_open_files(self.max_open_files);
+        options
+    }
+}
+
+/// Database backed by RocksDB.
+pub struct RocksDB {
+    db: Arc<DB>,
+    // Options the database was opened with, reused for column families created later.
+    options: Options,
+    // Serializes writes, so that keys read while building a batch are not changed
+    // concurrently before the batch is written.
+    write_lock: Mutex<()>,
+}
+
+/// Snapshot of the `RocksDB` database, based on a RocksDB snapshot.
+pub struct RocksDBSnapshot {
+    // Borrows from the `DB` owned by `db`. Fields are dropped in declaration order, so
+    // the snapshot is released before the last reference to the database can go away.
+    snapshot: SnapshotWithThreadMode<'static, DB>,
+    db: Arc<DB>,
+}
+
+struct RocksDBIter<'a> {
+    iter: Option<DBRawIteratorWithThreadMode<'a, DB>>,
+    // Whether the cursor points at the entry to be returned by the next call to `next`.
+    pending: bool,
+}
+
+fn rocksdb_error(context: &str, error: rocksdb::Error) -> Error {
+    Error::io(context, io::Error::other(error))
+}
+
+impl RocksDB {
+    /// Opens the database at the given path with all its existing column families.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `Io` error if RocksDB fails to open the database.
+    pub fn open<P: AsRef<Path>>(path: P, options: &RocksDBOptions) -> Result<Self> {
+        let options = options.to_rocksdb();
+        // Listing fails if the database does not exist yet.
+        let names = DB::list_cf(&options, &path).unwrap_or_default();
+        let db =
+            DB::open_cf(&options, &path, names).map_err(|e| rocksdb_error("opening RocksDB", e))?;
+        Ok(Self {
+            db: Arc::new(db),
+            options,
+            write_lock: Mutex::new(()),
+        })
+    }
+
+    fn cf_handle(&self, name: &str) -> Result<Arc<BoundColumnFamily<'_>>> {
+        if let Some(handle) = self.db.cf_handle(name) {
+            return Ok(handle);
+        }
+        // Another merge may have created the column family concurrently.
+        if let Err(e) = self.db.create_cf(name, &self.options) {
+            return self
+                .db
+                .cf_handle(name)
+                .ok_or_else(|| rocksdb_error("creating a column family", e));
+        }
+        Ok(self.db.cf_handle(name).unwrap())
+    }
+
+    fn rocksdb_snapshot(&self) -> RocksDBSnapshot {
+        RocksDBSnapshot {
+            // SAFETY: the snapshot borrows the `DB` behind the `Arc`, whose address does not
+            // change while any clone of the `Arc` exists. The struct holds such a clone and
+            // drops the snapshot first, so the borrow never outlives the database. The
+            // `'static` lifetime is never exposed: iterators borrow from `&self`.
+            snapshot: unsafe { mem::transmute(self.db.snapshot()) },
+            db: Arc::clone(&self.db),
+        }
+    }
+
+    fn write(&self, patches: &mut dyn StdIterator<Item = Result<Patch>>, sync: bool) -> Result<()> {
+        let _guard = self.write_lock.lock().unwrap_or_else(|e| e.into_inner());
+        let mut batch = WriteBatch::default();
+        // Greatest key put into the batch in each column family.
+        let mut last_puts = HashMap::<String, Vec<u8>>::new();
+        for patch in patches {
+            for (name, changes) in patch? {
+                let cf = self.cf_handle(&name)?;
+                let mut last_put = None;
+                for (key, change) in changes {
+                    match change {
+                        Change::Put(value) => {
+                            batch.put_cf(&cf, &key, value);
+                            last_put = Some(key);
+                        }
+                        Change::Delete => batch.delete_cf(&cf, key),
+                        Change::DeletePrefix => {
+                            let put = last_puts.get(&name);
+                            self.delete_prefix(&mut batch, &cf, key, put)
+                        }
+                    }
+                }
+                // Changes are ordered by key, so the last put is the greatest one.
+                if let Some(key) = last_put {
+                    let last = last_puts.entry(name).or_default();
+                    if key > *last {
+                        *last = key;
+                    }
+                }
+            }
+        }
+        let mut options = WriteOptions::default();
+        options.set_sync(sync);
+        self.db
+            .write_opt(batch, &options)
+            .map_err(|e| rocksdb_error("writing a batch to RocksDB", e))
+    }
+
+    fn delete_prefix(
+        &self,
+        batch: &mut WriteBatch,
+        cf: &Arc<BoundColumnFamily<'_>>,
+        prefix: Vec<u8>,
+        last_put: Option<&Vec<u8>>,
+    ) {
+        if let Some(end) = next_prefix(&prefix) {
+            batch.delete_range_cf(cf, prefix, end);
+            return;
+        }
+        // No key bounds the range from above, so the last key of the prefix, either stored
+        // or put earlier in the batch, is removed separately. The caller holds the write
+        // lock, so the stored key cannot change before the batch is written.
+        let mut iter = self.db.raw_iterator_cf(cf);
+        iter.seek_to_last();
+        let last = iter
+            .key()
+            .map(<[u8]>::to_vec)
+            .into_iter()
+            .chain(last_put.cloned())
+            .filter(|key| key.starts_with(&prefix))
+            .max();
+        if let Some(last) = last {
+            batch.delete_range_cf(cf, &prefix, &last);
+            batch.delete_cf(cf, last);
+        }
+    }
+}
+
+impl Database for RocksDB {
+    fn snapshot(&self) -> Box<dyn Snapshot> {
+        Box::new(self.rocksdb_snapshot())
+    }
+
+    fn merge(&self, patch: Patch) -> Result<()> {
+        self.write(&mut iter::once(Ok(patch)), false)
+    }
+
+    fn merge_sync(&self, patch: Patch) -> Result<()> {
+        self.write(&mut iter::once(Ok(patch)), true)
+    }
+
+    fn merge_patches(&self, patches: &mut dyn StdIterator<Item = Result<Patch>>) -> Result<()> {
+        self.write(patches, false)
+    }
+}
+
+impl Snapshot for RocksDBSnapshot {
+    fn get(&self, name: &str, key: &[u8]) -> Option<Vec<u8>> {
+        let cf = self.db.cf_handle(name)?;
+        match self.snapshot.get_cf(&cf, key) {
+            Ok(value) => value,
+            Err(e) => panic!("{}", rocksdb_error("reading from RocksDB", e)),
+        }
+    }
+
+    fn multi_get(&self, name: &str, keys: &[&[u8]]) -> Vec<Option<Vec<u8>>> {
+        let Some(cf) = self.db.cf_handle(name) else {
+            return vec![None; keys.len()];
+        };
+        self.snapshot
+            .multi_get_cf(keys.iter().map(|key| (&cf, *key)))
+            .into_iter()
+            .map(|value| {
+                value.unwrap_or_else(|e| panic!("{}", rocksdb_error("reading from RocksDB", e)))
+            })
+            .collect()
+    }
+
+    fn iter<'a>(&'a self, name: &str, from: &[u8]) -> Iter<'a> {
+        let iter = self.db.cf_handle(name).map(|cf| {
+            let mut iter = self.snapshot.raw_iterator_cf(&cf);
+            iter.seek(from);
+            iter
+        });
+        Box::new(RocksDBIter {
+            iter,
+            pending: true,
+        })
+    }
+}
+
+impl<'a> Iterator for RocksDBIter<'a> {
+    fn next(&mut self) -> Option<(&[u8], &[u8])> {
+        let iter = self.iter.as_mut()?;
+        if !self.pending {
+            iter.next();
+        }
+        self.pending = false;
+        current(iter)
+    }
+
+    fn peek(&mut self) -> Option<(&[u8], &[u8])> {
+        let iter = self.iter.as_mut()?;
+        if !self.pending {
+            iter.next();
+            self.pending = true;
+        }
+        current(iter)
+    }
+}
+
+/// Returns the entry under the cursor, or `None` at the end of the column family.
+///
+/// # Panics
+///
+/// Panics if the iteration has stopped because of a read error.
+fn current<'i>(iter: &'i DBRawIteratorWithThreadMode<'_, DB>) -> Option<(&'i [u8], &'i [u8])> {
+    if iter.valid() {
+        return Some((iter.key().unwrap(), iter.value().unwrap()));
+    }
+    if let Err(e) = iter.status() {
+        panic!("{}", rocksdb_error("iterating over RocksDB", e));
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::test_utils::{TempDir, entries};
+
+    #[test]
+    fn merge_and_reopen() {
+        let dir = TempDir::new("rocksdb");
+        {
+            let db = RocksDB::open(dir.path(), &RocksDBOptions::default()).unwrap();
+            let mut fork = db.fork();
+            fork.put("a", vec![1], vec![1]);
+            fork.put("a", vec![2, 1], vec![2]);
+            fork.put("a", vec![255, 1], vec![3]);
+            fork.put("b", vec![1], vec![4]);
+            db.merge(fork.into_patch()).unwrap();
+            let before = db.snapshot();
+
+            let mut fork = db.fork();
+            fork.remove_by_prefix("a", Some(&vec![2]));
+            fork.remove_by_prefix("a", Some(&vec![255]));
+            fork.remove("b", vec![1]);
+            fork.put("a", vec![2, 2], vec![5]);
+            db.merge_sync(fork.into_patch()).unwrap();
+
+            assert_eq!(entries(&*before, "a").len(), 3);
+            assert_eq!(before.get("b", &[1]), Some(vec![4]));
+            assert_eq!(entries(&*before, "missing"), vec![]);
+        }
+        let db = RocksDB::open(dir.path(), &RocksDBOptions::default()).unwrap();
+        let snapshot = db.snapshot();
+        assert_eq!(
+            entries(&*snapshot, "a"),
+            vec![(vec![1], vec![1]), (vec![2, 2], vec![5])]
+        );
+        assert!(!snapshot.contains("b", &[1]));
+        assert_eq!(
+            snapshot.multi_get("a", &[&[2, 2], &[2, 1], &[1]]),
+            vec![Some(vec![5]), None, Some(vec![1])]
+        );
+        assert_eq!(snapshot.multi_get("missing", &[&[1]]), vec![None]);
+
+        let mut iter = snapshot.iter("a", &[2]);
+        assert_eq!(iter.peek(), Some((&[2_u8, 2][..], &[5_u8][..])));
+        assert_eq!(iter.next(), Some((&[2_u8, 2][..], &[5_u8][..])));
+        assert_eq!(iter.peek(), None);
+        assert_eq!(iter.next(), None);
+    }
+
+    #[test]
+    fn merge_patches() {
+        let dir = TempDir::new("rocksdb_merge_patches");
+        let db = RocksDB::open(dir.path(), &RocksDBOptions::default()).unwrap();
+        let mut fork = db.fork();
+        fork.put("a", vec![1], vec![1]);
+        fork.put("a", vec![255, 1], vec![2]);
+        let first = fork.into_patch();
+        // The prefix has no upper bound and covers a key put only by the first patch.
+        let mut fork = db.fork();
+        fork.remove_by_prefix("a", Some(&vec![255]));
+        fork.put("a", vec![2], vec![3]);
+        let second = fork.into_patch();
+
+        let failed = vec![Ok(first.clone()), Err(Error::new("broken patch"))];
+        assert!(db.merge_patches(&mut failed.into_iter()).is_err());
+        assert_eq!(entries(&*db.snapshot(), "a"), vec![]);
+
+        db.merge_patches(&mut vec![Ok(first), Ok(second)].into_iter())
+            .unwrap();
+        assert_eq!(
+            entries(&*db.snapshot(), "a"),
+            vec![(vec![1], vec![1]), (vec![2], vec![3])]
+        );
+    }
+}
@@ -0,0 +1,516 @@
+//! A `Database` distributing column families across several backends.
+//!
+//! [`Routes`] map column family names or name prefixes to backends, so that e.g. hot column
+//! families (mempool, caches) live in memory while cold ones (blocks) are kept on disk. An
+//! exact name takes precedence over prefixes, the longest matching prefix wins, and column
+//! families without a route are stored in the default backend (backend `0`).
+//!
+//! # Atomicity
+//!
+//! A patch touching a single backend is applied with a single merge, so it is exactly as
+//! atomic as the backend itself. A patch spanning several backends gets the next sequence
+//! number and is applied with a two-phase commit:
+//!
+//! 1. Prepare: the parts of the patch for the other backends are durably staged under
+//!    the sequence number in the [`ROUTING_PENDING_NAME`] column family of their backends.
+//! 2. Commit: the part of the default backend is durably merged together with the sequence
+//!    number of the patch as the last committed one.
+//! 3. The staged parts are merged into their backends, each together with the removal of
+//!    the staged part and the sequence number of the patch as the last applied one.
+//!
+//! If the process crashes (or a backend fails) in between, recovery decides by the last
+//! committed sequence number: staged parts of committed patches which are not applied yet
+//! are applied in the order of their sequence numbers, and staged parts of patches which
+//! have not been committed are discarded. A part is never applied twice, since it is
+//! removed atomically with being applied. Thus after recovery either all or none of
+//! the changes of a patch are visible.
+//!
+//! Recovery runs in [`RoutingDB::new`] and before the next merge after a failed one, so
+//! a staged part never overwrites the changes of later patches. Merges and snapshots are
+//! serialized by a lock, so a snapshot never observes a cross-backend patch half-applied,
+//! except after a merge that failed once committed and until it is recovered.
+//!
+//! [`Routes`]: struct.Routes.html
+//! [`ROUTING_PENDING_NAME`]: constant.ROUTING_PENDING_NAME.html
+//! [`RoutingDB::new`]: struct.RoutingDB.html#method.new
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use byteorder::{BigEndian, ByteOrder};
+
+use super::db::{Change, Changes, Database, Iter, Patch, Snapshot};
+use super::{Error, Result, wal};
+
+/// Name of the system column family of every backend holding staged parts of cross-backend
+/// patches along with their sequence numbers.
+pub const ROUTING_PENDING_NAME: &str = "__routing_pending";
+
+// Sequence number of the last committed patch, stored in the default backend.
+const COMMIT_KEY: &[u8] = b"commit";
+// Sequence number of the last patch applied to a backend other than the default one.
+const APPLIED_KEY: &[u8] = b"applied";
+// Tag of the keys of staged parts, followed by the sequence number of the patch.
+const STAGED_TAG: u8 = b'p';
+
+/// Mapping of column families to backends, identified by their positions.
+#[derive(Debug, Clone, Default)]
+pub struct Routes {
+    names: HashMap<String, usize>,
+    // Sorted by descending length, so the longest matching prefix is found first.
+    prefixes: Vec<(String, usize)>,
+}
+
+impl Routes {
+    /// Creates routes storing all column families in the default backend.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stores the column family with the given `name` in the given backend.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `name` is the system column family of pending patches, which is kept in
+    /// every backend.
+    pub fn route(&mut self, name: &str, backend: usize) -> &mut Self {
+        assert_ne!(
+            name, ROUTING_PENDING_NAME,
+            "{} cannot be routed",
+            ROUTING_PENDING_NAME
+        );
+        self.names.insert(name.to_string(), backend);
+        self
+    }
+
+    /// Stores the column families with names starting with `prefix` in the given backend.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the prefix covers the system column family of pending patches.
+    pub fn route_prefix(&mut self, prefix: &str, backend: usize) -> &mut Self {
+        assert!(
+            !ROUTING_PENDING_NAME.starts_with(prefix),
+            "{} cannot be routed",
+            ROUTING_PENDING_NAME
+        );
+        self.prefixes.retain(|(other, _)| other != prefix);
+        self.prefixes.push((prefix.to_string(), backend));
+        self.prefixes
+            .sort_by(|(a, _), (b, _)| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+        self
+    }
+
+    /// Returns the backend storing the column family with the given `name`.
+    pub fn backend(&self, name: &str) -> usize {
+        if let Some(&backend) = self.names.get(name) {
+            return backend;
+        }
+        self.prefixes
+            .iter()
+            .find(|(prefix, _)| name.starts_with(prefix.as_str()))
+            .map_or(0, |&(_, backend)| backend)
+    }
+
+    fn max_backend(&self) -> usize {
+        self.names
+            .values()
+            .chain(self.prefixes.iter().map(|(_, backend)| backend))
+            .cloned()
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+/// Database routing column families to several backends.
+pub struct RoutingDB {
+    backends: Vec<Box<dyn Database>>,
+    routes: Arc<Routes>,
+    state: RwLock<State>,
+}
+
+struct State {
+    // Sequence number of the last cross-backend patch.
+    seq: u64,
+    // Whether a merge has failed and the backends need to be recovered.
+    dirty: bool,
+}
+
+struct RoutingSnapshot {
+    snapshots: Vec<Box<dyn Snapshot>>,
+    routes: Arc<Routes>,
+}
+
+impl RoutingDB {
+    /// Creates the database over the given backends, the first of which is the default one,
+    /// and finishes or discards cross-backend patches interrupted by a crash, if any.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `Corrupted` error if a staged part or a sequence number is malformed, or
+    /// an error of a backend failing to apply or discard a staged part.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there are no backends, or if a route refers to a missing backend.
+    pub fn new(backends: Vec<Box<dyn Database>>, routes: Routes) -> Result<Self> {
+        assert!(!backends.is_empty(), "no default backend");
+        assert!(
+            routes.max_backend() < backends.len(),
+            "route to a missing backend"
+        );
+        let db = Self {
+            backends,
+            routes: Arc::new(routes),
+            state: RwLock::new(State {
+                seq: 0,
+                dirty: true,
+            }),
+        };
+        db.recover(&mut db.state.write().unwrap())?;
+        Ok(db)
+    }
+
+    /// Returns the backend at the given position.
+    pub fn backend(&self, index: usize) -> &dyn Database {
+        &*self.backends[index]
+    }
+
+    /// Returns the routes of the database.
+    pub fn routes(&self) -> &Routes {
+        &self.routes
+    }
+
+    fn recover(&self, state: &mut State) -> Result<()> {
+        let commit = read_seq(&*self.backends[0].snapshot(), COMMIT_KEY)?;
+        let mut last = commit;
+        for backend in &self.backends[1..] {
+            let snapshot = backend.snapshot();
+            let applied = read_seq(&*snapshot, APPLIED_KEY)?;
+            last = last.max(applied);
+            let mut iter = snapshot.iter(ROUTING_PENDING_NAME, &[STAGED_TAG]);
+            while let Some((key, value)) = iter.next() {
+                if key[0] != STAGED_TAG {
+                    break;
+                }
+                if key.len() != 9 {
+                    return Err(corrupted(key, "malformed key of a staged part".to_string()));
+                }
+                let seq = BigEndian::read_u64(&key[1..]);
+                last = last.max(seq);
+                // Parts of patches which are not committed are discarded.
+                let mut patch = Patch::new();
+                if seq <= commit && seq > applied {
+                    patch = wal::decode_patch(value).map_err(|e| corrupted(key, e.to_string()))?;
+                    put_seq(&mut patch, APPLIED_KEY, seq);
+                }
+                pending(&mut patch).insert(key.to_vec(), Change::Delete);
+                backend.merge_sync(patch)?;
+            }
+        }
+        state.seq = last;
+        state.dirty = false;
+        Ok(())
+    }
+
+    fn split(&self, patch: Patch) -> HashMap<usize, Patch> {
+        let mut parts = HashMap::new();
+        for (name, changes) in patch {
+            parts
+                .entry(self.routes.backend(&name))
+                .or_insert_with(Patch::new)
+                .insert_changes(name, changes);
+        }
+        parts
+    }
+
+    fn merge_into(&self, index: usize, patch: Patch, sync: bool) -> Result<()> {
+        if sync {
+            self.backends[index].merge_sync(patch)
+        } else {
+            self.backends[index].merge(patch)
+        }
+    }
+
+    fn write(&self, patch: Patch, sync: bool) -> Result<()> {
+        let mut state = self.state.write().unwrap();
+        if state.dirty {
+            self.recover(&mut state)?;
+        }
+        let mut backends = patch.iter().map(|(name, _)| self.routes.backend(name));
+        let single = match backends.next() {
+            Some(first) if backends.all(|backend| backend == first) => Some(first),
+            Some(_) => None,
+            None => return Ok(()),
+        };
+        if let Some(index) = single {
+            return self.merge_into(index, patch, sync);
+        }
+
+        // Until the patch is fully applied, a failure leaves the backends to be recovered.
+        state.dirty = true;
+        state.seq += 1;
+        let seq = state.seq;
+        let mut parts = self.split(patch);
+        let mut commit = parts.remove(&0).unwrap_or_else(Patch::new);
+        for (&index, part) in &parts {
+            let mut staged = Patch::new();
+            pending(&mut staged).insert(staged_key(seq), Change::Put(wal::encode_patch(part)));
+            self.backends[index].merge_sync(staged)?;
+        }
+        put_seq(&mut commit, COMMIT_KEY, seq);
+        self.backends[0].merge_sync(commit)?;
+        for (index, mut part) in parts {
+            put_seq(&mut part, APPLIED_KEY, seq);
+            pending(&mut part).insert(staged_key(seq), Change::Delete);
+            self.merge_into(index, part, sync)?;
+        }
+        state.dirty = false;
+        Ok(())
+    }
+}
+
+fn pending(patch: &mut Patch) -> &mut Changes {
+    patch
+        .changes_entry(ROUTING_PENDING_NAME.to_string())
+        .or_insert_with(Changes::new)
+}
+
+fn staged_key(seq: u64) -> Vec<u8> {
+    let mut key = vec![STAGED_TAG];
+    key.extend_from_slice(&seq.to_be_bytes());
+    key
+}
+
+fn put_seq(patch: &mut Patch, key: &[u8], seq: u64) {
+    pending(patch).insert(key.to_vec(), Change::Put(seq.to_be_bytes().to_vec()));
+}
+
+fn read_seq(view: &dyn Snapshot, key: &[u8]) -> Result<u64> {
+    match view.get(ROUTING_PENDING_NAME, key) {
+        Some(ref value) if value.len() == 8 => Ok(BigEndian::read_u64(value)),
+        Some(_) => Err(corrupted(key, "malformed sequence number".to_string())),
+        None => Ok(0),
+    }
+}
+
+fn corrupted(key: &[u8], reason: String) -> Error {
+    Error::Corrupted {
+        name: ROUTING_PENDING_NAME.to_string(),
+        key: key.to_vec(),
+        reason,
+    }
+}
+
+impl Database for RoutingDB {
+    fn snapshot(&self) -> Box<dyn Snapshot> {
+        let _state = self.state.read().unwrap();
+        Box::new(RoutingSnapshot {
+            snapshots: self.backends.iter().map(|db| db.snapshot()).collect(),
+            routes: Arc::clone(&self.routes),
+        })
+    }
+
+    fn merge(&self, patch: Patch) -> Result<()> {
+        self.write(patch, false)
+    }
+
+    fn merge_sync(&self, patch: Patch) -> Result<()> {
+        self.write(patch, true)
+    }
+}
+
+impl RoutingSnapshot {
+    fn snapshot(&self, name: &str) -> &dyn Snapshot {
+        &*self.snapshots[self.routes.backend(name)]
+    }
+}
+
+impl Snapshot for RoutingSnapshot {
+    fn get(&self, name: &str, key: &[u8]) -> Option<Vec<u8>> {
+        self.snapshot(name).get(name, key)
+    }
+
+    fn contains(&self, name: &str, key: &[u8]) -> bool {
+        self.snapshot(name).contains(name, key)
+    }
+
+    fn multi_get(&self, name: &str, keys: &[&[u8]]) -> Vec<Option<Vec<u8>>> {
+        self.snapshot(name).multi_get(name, keys)
+    }
+
+    fn iter<'a>(&'a self, name: &str, from: &[u8]) -> Iter<'a> {
+        self.snapshot(name).iter(name, from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::test_utils::TestDB;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    fn test_routes() -> Routes {
+        let mut routes = Routes::new();
+        routes
+            .route_prefix("mem", 1)
+            .route_prefix("mempool_", 0)
+            .route("blocks", 0);
+        routes
+    }
+
+    fn routed() -> (RoutingDB, TestDB, TestDB) {
+        let (cold, hot) = (TestDB::new(), TestDB::new());
+        let backends: Vec<Box<dyn Database>> = vec![Box::new(cold.clone()), Box::new(hot.clone())];
+        (RoutingDB::new(backends, test_routes()).unwrap(), cold, hot)
+    }
+
+    fn cross_patch(db: &RoutingDB, block: u8, tx: u8) -> Patch {
+        let mut fork = db.fork();
+        fork.put("blocks", vec![block], vec![block]);
+        fork.put("mempool", vec![tx], vec![tx]);
+        fork.into_patch()
+    }
+
+    fn has_staged(db: &TestDB) -> bool {
+        let snapshot = db.snapshot();
+        let mut iter = snapshot.iter(ROUTING_PENDING_NAME, &[STAGED_TAG]);
+        iter.next().is_some_and(|(key, _)| key[0] == STAGED_TAG)
+    }
+
+    #[test]
+    fn routes() {
+        let (db, ..) = routed();
+        let routes = db.routes();
+        assert_eq!(routes.backend("blocks"), 0);
+        assert_eq!(routes.backend("mempool"), 1);
+        assert_eq!(routes.backend("mempool_"), 0);
+        assert_eq!(routes.backend("mempool_index"), 0);
+        assert_eq!(routes.backend("wallets"), 0);
+    }
+
+    #[test]
+    fn split_patches() {
+        let (db, cold, hot) = routed();
+        db.merge(cross_patch(&db, 1, 2)).unwrap();
+
+        assert_eq!(cold.snapshot().get("blocks", &[1]), Some(vec![1]));
+        assert!(!cold.snapshot().contains("mempool", &[2]));
+        assert_eq!(hot.snapshot().get("mempool", &[2]), Some(vec![2]));
+        assert_eq!(read_seq(&*cold.snapshot(), COMMIT_KEY).unwrap(), 1);
+        assert_eq!(read_seq(&*hot.snapshot(), APPLIED_KEY).unwrap(), 1);
+        assert!(!has_staged(&hot));
+
+        let snapshot = db.snapshot();
+        assert_eq!(snapshot.get("blocks", &[1]), Some(vec![1]));
+        assert_eq!(snapshot.get("mempool", &[2]), Some(vec![2]));
+        let mut iter = snapshot.iter("mempool", &[]);
+        assert_eq!(iter.next(), Some((&[2_u8][..], &[2_u8][..])));
+        assert_eq!(iter.next(), None);
+
+        let mut fork = db.fork();
+        fork.remove("mempool", vec![2]);
+        db.merge_sync(fork.into_patch()).unwrap();
+        assert!(!db.snapshot().contains("mempool", &[2]));
+        assert_eq!(snapshot.get("mempool", &[2]), Some(vec![2]));
+    }
+
+    #[test]
+    fn recovery_of_staged_parts() {
+        let (db, cold, hot) = routed();
+        // Patch 1 is applied, but its staged part is left over; patch 2 is committed, but
+        // not applied; patch 3 is staged, but not committed.
+        let stage = |seq: u64, tx: u8| {
+            let mut part = hot.fork();
+            part.put("mempool", vec![tx], vec![tx]);
+            let part = part.into_patch();
+            let mut staged = Patch::new();
+            pending(&mut staged).insert(staged_key(seq), Change::Put(wal::encode_patch(&part)));
+            hot.merge(staged).unwrap();
+        };
+        stage(1, 1);
+        stage(2, 2);
+        stage(3, 3);
+        let mut seqs = Patch::new();
+        put_seq(&mut seqs, COMMIT_KEY, 2);
+        cold.merge(seqs).unwrap();
+        let mut seqs = Patch::new();
+        put_seq(&mut seqs, APPLIED_KEY, 1);
+        hot.merge(seqs).unwrap();
+        // The value written by patch 1 has been overwritten since.
+        let mut fork = hot.fork();
+        fork.put("mempool", vec![1], vec![10]);
+        hot.merge(fork.into_patch()).unwrap();
+        drop(db);
+
+        let backends: Vec<Box<dyn Database>> = vec![Box::new(cold.clone()), Box::new(hot.clone())];
+        let db = RoutingDB::new(backends, test_routes()).unwrap();
+        let snapshot = db.snapshot();
+        assert_eq!(snapshot.get("mempool", &[1]), Some(vec![10]));
+        assert_eq!(snapshot.get("mempool", &[2]), Some(vec![2]));
+        assert!(!snapshot.contains("mempool", &[3]));
+        assert_eq!(read_seq(&*hot.snapshot(), APPLIED_KEY).unwrap(), 2);
+        assert!(!has_staged(&hot));
+
+        // Sequence numbers of discarded patches are not reused.
+        db.merge(cross_patch(&db, 4, 4)).unwrap();
+        assert_eq!(read_seq(&*cold.snapshot(), COMMIT_KEY).unwrap(), 4);
+    }
+
+    // Backend failing all merges, or only the ones which are not synced.
+    struct Failing {
+        db: TestDB,
+        merges: Arc<AtomicBool>,
+        syncs: Arc<AtomicBool>,
+    }
+
+    impl Database for Failing {
+        fn snapshot(&self) -> Box<dyn Snapshot> {
+            self.db.snapshot()
+        }
+
+        fn merge(&self, patch: Patch) -> Result<()> {
+            if self.merges.load(Ordering::SeqCst) {
+                return Err(Error::new("backend failure"));
+            }
+            self.db.merge(patch)
+        }
+
+        fn merge_sync(&self, patch: Patch) -> Result<()> {
+            if self.syncs.load(Ordering::SeqCst) {
+                return Err(Error::new("backend failure"));
+            }
+            self.db.merge_sync(patch)
+        }
+    }
+
+    #[test]
+    fn failed_merges() {
+        let (cold, hot) = (TestDB::new(), TestDB::new());
+        let merges = Arc::new(AtomicBool::new(true));
+        let syncs = Arc::new(AtomicBool::new(true));
+        let failing = Failing {
+            db: hot.clone(),
+            merges: Arc::clone(&merges),
+            syncs: Arc::clone(&syncs),
+        };
+        let backends: Vec<Box<dyn Database>> = vec![Box::new(cold.clone()), Box::new(failing)];
+        let db = RoutingDB::new(backends, test_routes()).unwrap();
+
+        // A patch failing to be staged is not visible at all.
+        assert!(db.merge(cross_patch(&db, 1, 1)).is_err());
+        assert!(!db.snapshot().contains("blocks", &[1]));
+
+        // A patch failing to be applied once committed is finished by the next merge.
+        syncs.store(false, Ordering::SeqCst);
+        assert!(db.merge(cross_patch(&db, 2, 2)).is_err());
+        assert!(db.snapshot().contains("blocks", &[2]));
+        assert!(!db.snapshot().contains("mempool", &[2]));
+        merges.store(false, Ordering::SeqCst);
+        let mut fork = db.fork();
+        fork.put("mempool", vec![2], vec![20]);
+        db.merge(fork.into_patch()).unwrap();
+        assert_eq!(db.snapshot().get("mempool", &[2]), Some(vec![20]));
+        assert!(!has_staged(&hot));
+    }
+}
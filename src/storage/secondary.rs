@@ -0,0 +1,249 @@
+//! Secondary indexes automatically maintained by writes through a `Fork`.
+//!
+//! A secondary index is declared on a source column family as a function from the
+//! `(key, value)` pair of the source to an index key. The index itself is stored in a
+//! separate column family, where each entry has the key `index key ++ source key` and
+//! the source key as a value, so several source entries may share the same index key.
+//! Index keys of variable length should be prefix-free (e.g. length-prefixed), otherwise
+//! [`SecondaryIndex::lookup`] may return entries for a longer index key as well.
+//!
+//! [`SecondaryIndex::lookup`]: struct.SecondaryIndex.html#method.lookup
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use super::db::{Database, Fork, Patch, Snapshot};
+use super::Result;
+
+type IndexKeyFn = dyn Fn(&[u8], &[u8]) -> Option<Vec<u8>> + Send + Sync;
+
+/// Declaration of a secondary index over a column family.
+pub struct SecondaryIndex {
+    name: String,
+    source: String,
+    index_key: Box<IndexKeyFn>,
+}
+
+impl SecondaryIndex {
+    /// Declares an index with the given `name` over the `source` column family.
+    ///
+    /// `index_key` maps a key-value pair of the source to the index key, or to `None` if the
+    /// pair should not be indexed.
+    pub fn new<F>(source: &str, name: &str, index_key: F) -> Self
+    where
+        F: Fn(&[u8], &[u8]) -> Option<Vec<u8>> + Send + Sync + 'static,
+    {
+        assert_ne!(source, name, "index cannot be stored in its source column family");
+        Self {
+            name: name.to_string(),
+            source: source.to_string(),
+            index_key: Box::new(index_key),
+        }
+    }
+
+    /// Returns the name of the column family holding the index.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the name of the indexed column family.
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Returns the key of the index entry for the given key-value pair of the source.
+    pub fn entry_key(&self, key: &[u8], value: &[u8]) -> Option<Vec<u8>> {
+        (self.index_key)(key, value).map(|mut entry| {
+            entry.extend_from_slice(key);
+            entry
+        })
+    }
+
+    /// Returns keys of the source entries indexed by the given index key, in ascending order.
+    pub fn lookup(&self, view: &dyn Snapshot, index_key: &[u8]) -> Vec<Vec<u8>> {
+        let mut keys = Vec::new();
+        let mut iter = view.iter(&self.name, index_key);
+        while let Some((entry, key)) = iter.next() {
+            if !entry.starts_with(index_key) {
+                break;
+            }
+            keys.push(key.to_vec());
+        }
+        keys
+    }
+}
+
+impl fmt::Debug for SecondaryIndex {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SecondaryIndex")
+            .field("name", &self.name)
+            .field("source", &self.source)
+            .finish()
+    }
+}
+
+/// A set of secondary indexes grouped by their source column families.
+#[derive(Debug, Default)]
+pub struct SecondaryIndexes {
+    indexes: HashMap<String, Vec<SecondaryIndex>>,
+}
+
+impl SecondaryIndexes {
+    /// Creates an empty set of indexes.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the index to the set.
+    ///
+    /// # Panics
+    ///
+    /// Panics if an index with the same name is already declared, or if the index is
+    /// stored in a column family which is itself indexed.
+    pub fn add(&mut self, index: SecondaryIndex) -> &mut Self {
+        assert!(
+            self.iter().all(|other| other.name != index.name),
+            "secondary index {} is already declared",
+            index.name
+        );
+        assert!(
+            !self.has_source(&index.name) && self.iter().all(|other| other.name != index.source),
+            "secondary index {} cannot be indexed itself",
+            index.name
+        );
+        self.indexes
+            .entry(index.source.clone())
+            .or_default()
+            .push(index);
+        self
+    }
+
+    /// Returns `true` if there are indexes over the column family with the given `name`.
+    pub fn has_source(&self, name: &str) -> bool {
+        self.indexes.contains_key(name)
+    }
+
+    /// Returns indexes over the column family with the given `name`.
+    pub fn for_source(&self, name: &str) -> &[SecondaryIndex] {
+        self.indexes.get(name).map_or(&[], Vec::as_slice)
+    }
+
+    /// Returns an index by its name.
+    pub fn get(&self, name: &str) -> Option<&SecondaryIndex> {
+        self.iter().find(|index| index.name == name)
+    }
+
+    /// Returns an iterator over all declared indexes.
+    pub fn iter(&self) -> impl Iterator<Item = &SecondaryIndex> {
+        self.indexes.values().flat_map(|indexes| indexes.iter())
+    }
+}
+
+/// Database wrapper whose forks keep the declared secondary indexes up to date.
+#[derive(Debug)]
+pub struct IndexedDB<T: Database> {
+    inner: T,
+    indexes: Arc<SecondaryIndexes>,
+}
+
+impl<T: Database> IndexedDB<T> {
+    /// Wraps the given database.
+    pub fn new(inner: T, indexes: SecondaryIndexes) -> Self {
+        Self {
+            inner,
+            indexes: Arc::new(indexes),
+        }
+    }
+
+    /// Returns the declared secondary indexes.
+    pub fn indexes(&self) -> &SecondaryIndexes {
+        &self.indexes
+    }
+
+    /// Returns a reference to the underlying database.
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T: Database> Database for IndexedDB<T> {
+    fn snapshot(&self) -> Box<dyn Snapshot> {
+        self.inner.snapshot()
+    }
+
+    fn fork(&self) -> Fork {
+        let mut fork = self.inner.fork();
+        fork.set_secondary_indexes(Arc::clone(&self.indexes));
+        fork
+    }
+
+    fn merge(&self, patch: Patch) -> Result<()> {
+        self.inner.merge(patch)
+    }
+
+    fn merge_sync(&self, patch: Patch) -> Result<()> {
+        self.inner.merge_sync(patch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::test_utils::TestDB;
+
+    // Transactions are stored as `sender byte ++ payload`.
+    fn db() -> IndexedDB<TestDB> {
+        let mut indexes = SecondaryIndexes::new();
+        indexes.add(SecondaryIndex::new("txs", "txs_by_sender", |_, value| {
+            value.first().map(|sender| vec![*sender])
+        }));
+        IndexedDB::new(TestDB::new(), indexes)
+    }
+
+    fn by_sender(db: &IndexedDB<TestDB>, view: &dyn Snapshot, sender: u8) -> Vec<Vec<u8>> {
+        db.indexes().get("txs_by_sender").unwrap().lookup(view, &[sender])
+    }
+
+    #[test]
+    fn put_overwrite_remove() {
+        let db = db();
+        let mut fork = db.fork();
+        fork.put("txs", vec![1], vec![10, 0]);
+        fork.put("txs", vec![2], vec![10, 1]);
+        fork.put("txs", vec![3], vec![20, 0]);
+        assert_eq!(by_sender(&db, &fork, 10), vec![vec![1], vec![2]]);
+        db.merge(fork.into_patch()).unwrap();
+
+        let mut fork = db.fork();
+        fork.put("txs", vec![2], vec![20, 1]);
+        fork.remove("txs", vec![1]);
+        fork.remove("txs", vec![4]);
+        assert_eq!(by_sender(&db, &fork, 10), Vec::<Vec<u8>>::new());
+        assert_eq!(by_sender(&db, &fork, 20), vec![vec![2], vec![3]]);
+        db.merge(fork.into_patch()).unwrap();
+
+        let snapshot = db.snapshot();
+        assert_eq!(by_sender(&db, &*snapshot, 20), vec![vec![2], vec![3]]);
+        assert_eq!(snapshot.get("txs_by_sender", &[20, 3]), Some(vec![3]));
+    }
+
+    #[test]
+    fn remove_by_prefix_and_rollback() {
+        let db = db();
+        let mut fork = db.fork();
+        fork.put("txs", vec![1, 1], vec![10]);
+        fork.put("txs", vec![1, 2], vec![20]);
+        fork.put("txs", vec![2, 1], vec![10]);
+        db.merge(fork.into_patch()).unwrap();
+
+        let mut fork = db.fork();
+        fork.checkpoint();
+        fork.remove_by_prefix("txs", Some(&vec![1]));
+        assert_eq!(by_sender(&db, &fork, 10), vec![vec![2, 1]]);
+        assert_eq!(by_sender(&db, &fork, 20), Vec::<Vec<u8>>::new());
+        fork.rollback();
+        assert_eq!(by_sender(&db, &fork, 10), vec![vec![1, 1], vec![2, 1]]);
+        assert_eq!(by_sender(&db, &fork, 20), vec![vec![1, 2]]);
+    }
+}
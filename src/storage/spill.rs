@@ -0,0 +1,658 @@
+//! A fork spilling its changes to temporary files to bound memory usage.
+//!
+//! A [`SpillingFork`] buffers changes in memory like a regular `Fork`. Once the buffered
+//! changes exceed the configured threshold, they are written to a temporary file as a
+//! sorted run and the buffer is cleared. Each run keeps in memory only its range
+//! tombstones and a sparse index with the first key of every block of entries, so reads
+//! through the fork remain correct: a lookup checks the buffer, then the runs from the
+//! newest to the oldest, then the snapshot, and iterators merge all the layers.
+//!
+//! [`merge_into`] passes the runs, followed by the buffer, to [`Database::merge_patches`],
+//! reading them back one at a time and dropping each one as soon as the database has taken
+//! it. The merge is thus as atomic as a regular one. Backends overriding `merge_patches`
+//! hold only their own representation of the combined changes while merging, such as
+//! a RocksDB write batch; with the default implementation, the combined changes are held
+//! in memory as a single patch.
+//!
+//! [`SpillingFork`]: struct.SpillingFork.html
+//! [`merge_into`]: struct.SpillingFork.html#method.merge_into
+//! [`Database::merge_patches`]: ../db/trait.Database.html#method.merge_patches
+
+use std::cmp::Ordering::*;
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::iter;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{env, process};
+
+use byteorder::{ReadBytesExt, WriteBytesExt};
+
+use super::db::{Change, Changes, Database, ForkIter, Iter, Iterator, Patch, Snapshot};
+use super::wal::{read_bytes, write_bytes};
+use super::{Error, Result};
+
+const TAG_PUT: u8 = 0;
+const TAG_DELETE: u8 = 1;
+/// Number of entries between two keys of the sparse index of a run.
+const BLOCK_SIZE: usize = 64;
+
+/// Key of a run entry with its value, or `None` if the key is removed.
+type RunEntry = (Vec<u8>, Option<Vec<u8>>);
+
+/// Options of the `SpillingFork`.
+#[derive(Debug, Clone)]
+pub struct SpillOptions {
+    /// Approximate size of the buffered changes in bytes after which they are written to
+    /// a temporary file. Default value is 64 MiB.
+    pub threshold: usize,
+    /// Directory for the temporary files. Default value is the system temporary directory.
+    pub dir: PathBuf,
+}
+
+impl Default for SpillOptions {
+    fn default() -> Self {
+        Self {
+            threshold: 64 << 20,
+            dir: env::temp_dir(),
+        }
+    }
+}
+
+/// Fork writing its changes to temporary files once they exceed a memory threshold.
+///
+/// # Panics
+///
+/// Reads through the fork with `Snapshot::get` and `Snapshot::iter` (including advancing
+/// the returned iterators) panic if a run cannot be read back from its temporary file,
+/// since `Snapshot` methods cannot return errors. Runs are fully written and flushed when
+/// spilled, so this happens only if a temporary file is removed or damaged afterwards or
+/// the disk fails. [`merge_into`] returns such errors instead.
+///
+/// [`merge_into`]: #method.merge_into
+pub struct SpillingFork {
+    snapshot: Box<dyn Snapshot>,
+    options: SpillOptions,
+    buffer: Patch,
+    buffered: usize,
+    // Runs from the oldest to the newest.
+    runs: Vec<Run>,
+}
+
+/// Changes of a column family within a run.
+struct RunFamily {
+    removed: Changes,
+    // First key and offset of every block of entries.
+    blocks: Vec<(Vec<u8>, u64)>,
+    end: u64,
+}
+
+/// Sorted changes written to a temporary file.
+struct Run {
+    path: PathBuf,
+    file: Mutex<File>,
+    families: HashMap<String, RunFamily>,
+}
+
+/// Reader of the entries of a column family within a run.
+struct RunEntries {
+    reader: BufReader<File>,
+    position: u64,
+    end: u64,
+    peeked: Option<RunEntry>,
+}
+
+/// Iterator over the entries of a storage view with the changes of a run applied on top.
+struct RunIter<'a> {
+    lower: Iter<'a>,
+    entries: Option<RunEntries>,
+    removed: Option<&'a Changes>,
+    current: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+enum Step {
+    Stored,
+    Shadowed,
+    Inserted,
+    Replaced,
+    Deleted,
+    MissDeleted,
+    Finished,
+}
+
+fn spill_error(error: io::Error) -> Error {
+    Error::io("spilling fork changes", error)
+}
+
+fn write_entry<W: Write>(writer: &mut W, key: &[u8], value: Option<&[u8]>) -> io::Result<u64> {
+    write_bytes(writer, key)?;
+    match value {
+        Some(value) => {
+            writer.write_u8(TAG_PUT)?;
+            write_bytes(writer, value)?;
+            Ok(4 + key.len() as u64 + 1 + 4 + value.len() as u64)
+        }
+        None => {
+            writer.write_u8(TAG_DELETE)?;
+            Ok(4 + key.len() as u64 + 1)
+        }
+    }
+}
+
+fn read_entry<R: Read>(reader: &mut R) -> io::Result<(Vec<u8>, Option<Vec<u8>>, u64)> {
+    let key = read_bytes(reader)?;
+    let size = 4 + key.len() as u64 + 1;
+    match reader.read_u8()? {
+        TAG_PUT => {
+            let value = read_bytes(reader)?;
+            let size = size + 4 + value.len() as u64;
+            Ok((key, Some(value), size))
+        }
+        TAG_DELETE => Ok((key, None, size)),
+        tag => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unknown change tag {}", tag),
+        )),
+    }
+}
+
+impl RunFamily {
+    /// Returns the offset of the block which may contain the key.
+    fn seek(&self, key: &[u8]) -> u64 {
+        let index = self
+            .blocks
+            .partition_point(|(first, _)| first.as_slice() <= key);
+        self.blocks[index.saturating_sub(1)].1
+    }
+}
+
+impl Run {
+    fn write(options: &SpillOptions, patch: &Patch) -> io::Result<Self> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let path = options.dir.join(format!(
+            "spill-{}-{}.run",
+            process::id(),
+            COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        let mut run = Self {
+            path,
+            file: Mutex::new(file.try_clone()?),
+            families: HashMap::new(),
+        };
+
+        let mut writer = BufWriter::new(file);
+        let mut offset = 0;
+        for (name, changes) in patch.iter() {
+            let mut family = RunFamily {
+                removed: Changes::new(),
+                blocks: Vec::new(),
+                end: 0,
+            };
+            let mut entries = 0;
+            for (key, change) in changes.iter() {
+                let value = match *change {
+                    Change::Put(ref value) => Some(value.as_slice()),
+                    Change::Delete => None,
+                    Change::DeletePrefix => {
+                        family.removed.remove_prefix(key.clone());
+                        continue;
+                    }
+                };
+                if entries % BLOCK_SIZE == 0 {
+                    family.blocks.push((key.clone(), offset));
+                }
+                offset += write_entry(&mut writer, key, value)?;
+                entries += 1;
+            }
+            family.end = offset;
+            run.families.insert(name.clone(), family);
+        }
+        writer.flush()?;
+        Ok(run)
+    }
+
+    /// Returns the change of the key made by the run, in the same form as `Changes::lookup`.
+    fn lookup(&self, name: &str, key: &[u8]) -> io::Result<Option<Option<Vec<u8>>>> {
+        let family = match self.families.get(name) {
+            Some(family) => family,
+            None => return Ok(None),
+        };
+        if !family.blocks.is_empty() {
+            let start = family.seek(key);
+            let mut file = self.file.lock().unwrap();
+            file.seek(SeekFrom::Start(start))?;
+            let mut reader = BufReader::new(&mut *file).take(family.end - start);
+            let mut position = start;
+            while position < family.end {
+                let (stored, value, size) = read_entry(&mut reader)?;
+                match stored.as_slice().cmp(key) {
+                    Less => position += size,
+                    Equal => return Ok(Some(value)),
+                    Greater => break,
+                }
+            }
+        }
+        Ok(if family.removed.is_removed(key) {
+            Some(None)
+        } else {
+            None
+        })
+    }
+
+    fn entries(&self, name: &str, from: &[u8]) -> io::Result<Option<RunEntries>> {
+        let family = match self.families.get(name) {
+            Some(family) if !family.blocks.is_empty() => family,
+            _ => return Ok(None),
+        };
+        let start = family.seek(from);
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(start))?;
+        let mut entries = RunEntries {
+            reader: BufReader::new(file),
+            position: start,
+            end: family.end,
+            peeked: None,
+        };
+        while entries
+            .peek()?
+            .is_some_and(|(key, _)| key.as_slice() < from)
+        {
+            entries.next()?;
+        }
+        Ok(Some(entries))
+    }
+
+    fn into_patch(self) -> io::Result<Patch> {
+        let mut patch = Patch::new();
+        for (name, family) in &self.families {
+            let mut changes = family.removed.clone();
+            if let Some(mut entries) = self.entries(name, &[])? {
+                while let Some((key, value)) = entries.next()? {
+                    changes.insert(key, value.map_or(Change::Delete, Change::Put));
+                }
+            }
+            patch.insert_changes(name.clone(), changes);
+        }
+        Ok(patch)
+    }
+}
+
+impl Drop for Run {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+impl RunEntries {
+    fn peek(&mut self) -> io::Result<Option<&RunEntry>> {
+        if self.peeked.is_none() && self.position < self.end {
+            let (key, value, size) = read_entry(&mut self.reader)?;
+            self.position += size;
+            self.peeked = Some((key, value));
+        }
+        Ok(self.peeked.as_ref())
+    }
+
+    fn next(&mut self) -> io::Result<Option<RunEntry>> {
+        self.peek()?;
+        Ok(self.peeked.take())
+    }
+}
+
+impl SpillingFork {
+    /// Creates a fork over the given snapshot.
+    pub fn new(snapshot: Box<dyn Snapshot>, options: SpillOptions) -> Self {
+        Self {
+            snapshot,
+            options,
+            buffer: Patch::new(),
+            buffered: 0,
+            runs: Vec::new(),
+        }
+    }
+
+    /// Returns the number of runs written to temporary files.
+    pub fn runs(&self) -> usize {
+        self.runs.len()
+    }
+
+    fn write(&mut self, name: &str, key: Vec<u8>, change: Change, size: usize) -> Result<()> {
+        let changes = match self.buffer.changes_entry(name.to_string()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(Changes::new()),
+        };
+        changes.insert(key, change);
+        self.buffered += size;
+        if self.buffered >= self.options.threshold {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the buffered changes to a temporary file.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `Io` error if the file cannot be written, in which case the changes
+    /// stay in the buffer.
+    pub fn spill(&mut self) -> Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let run = Run::write(&self.options, &self.buffer).map_err(spill_error)?;
+        self.runs.push(run);
+        self.buffer = Patch::new();
+        self.buffered = 0;
+        Ok(())
+    }
+
+    /// Inserts a key-value pair into the fork.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `Io` error if the changes exceed the threshold and cannot be spilled.
+    pub fn put(&mut self, name: &str, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let size = key.len() + value.len();
+        self.write(name, key, Change::Put(value), size)
+    }
+
+    /// Removes the key from the fork.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `Io` error if the changes exceed the threshold and cannot be spilled.
+    pub fn remove(&mut self, name: &str, key: Vec<u8>) -> Result<()> {
+        let size = key.len();
+        self.write(name, key, Change::Delete, size)
+    }
+
+    /// Removes all keys starting with the specified prefix from the column family
+    /// with the given `name`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `Io` error if the changes exceed the threshold and cannot be spilled.
+    pub fn remove_by_prefix(&mut self, name: &str, prefix: Option<&Vec<u8>>) -> Result<()> {
+        let prefix = prefix.cloned().unwrap_or_default();
+        let size = prefix.len();
+        self.write(name, prefix, Change::DeletePrefix, size)
+    }
+
+    /// Merges all the changes into the database with [`Database::merge_patches`] and
+    /// removes the temporary files. Either all or none of the changes are merged.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `Io` error if a run cannot be read, in which case nothing is merged, or
+    /// an error of the database.
+    ///
+    /// [`Database::merge_patches`]: ../db/trait.Database.html#method.merge_patches
+    pub fn merge_into<D: Database + ?Sized>(self, db: &D) -> Result<()> {
+        let runs = self
+            .runs
+            .into_iter()
+            .map(|run| run.into_patch().map_err(spill_error));
+        db.merge_patches(&mut runs.chain(iter::once(Ok(self.buffer))))
+    }
+}
+
+impl Snapshot for SpillingFork {
+    fn get(&self, name: &str, key: &[u8]) -> Option<Vec<u8>> {
+        if let Some(value) = self.buffer.changes(name).and_then(|c| c.lookup(key)) {
+            return value.cloned();
+        }
+        for run in self.runs.iter().rev() {
+            match run.lookup(name, key) {
+                Ok(Some(value)) => return value,
+                Ok(None) => {}
+                Err(e) => panic!("{}", spill_error(e)),
+            }
+        }
+        self.snapshot.get(name, key)
+    }
+
+    fn iter<'a>(&'a self, name: &str, from: &[u8]) -> Iter<'a> {
+        let iter = self
+            .runs
+            .iter()
+            .fold(self.snapshot.iter(name, from), |lower, run| {
+                let entries = run
+                    .entries(name, from)
+                    .unwrap_or_else(|e| panic!("{}", spill_error(e)));
+                Box::new(RunIter {
+                    lower,
+                    entries,
+                    removed: run.families.get(name).map(|family| &family.removed),
+                    current: None,
+                })
+            });
+        Box::new(ForkIter::new(iter, self.buffer.changes(name), from))
+    }
+}
+
+fn stored_step(removed: Option<&Changes>, key: &[u8]) -> Step {
+    match removed {
+        Some(removed) if removed.is_removed(key) => Step::Shadowed,
+        _ => Step::Stored,
+    }
+}
+
+impl<'a> RunIter<'a> {
+    fn step(&mut self) -> Step {
+        let removed = self.removed;
+        let upper = match self.entries {
+            Some(ref mut entries) => entries
+                .peek()
+                .unwrap_or_else(|e| panic!("{}", spill_error(e))),
+            None => None,
+        };
+        let lower = self.lower.peek().map(|(key, _)| key);
+        match (upper, lower) {
+            (Some((key, value)), Some(stored)) => match (key.as_slice().cmp(stored), value) {
+                (Less, Some(..)) => Step::Inserted,
+                (Less, None) => Step::MissDeleted,
+                (Equal, Some(..)) => Step::Replaced,
+                (Equal, None) => Step::Deleted,
+                (Greater, _) => stored_step(removed, stored),
+            },
+            (Some((_, Some(..))), None) => Step::Inserted,
+            (Some((_, None)), None) => Step::MissDeleted,
+            (None, Some(stored)) => stored_step(removed, stored),
+            (None, None) => Step::Finished,
+        }
+    }
+
+    // Returns the next entry of the run, which must be a put.
+    fn take_entry(&mut self) -> Option<(&[u8], &[u8])> {
+        self.current = self.next_entry().map(|(key, value)| (key, value.unwrap()));
+        self.current
+            .as_ref()
+            .map(|(k, v)| (k.as_slice(), v.as_slice()))
+    }
+
+    fn next_entry(&mut self) -> Option<RunEntry> {
+        self.entries
+            .as_mut()
+            .unwrap()
+            .next()
+            .unwrap_or_else(|e| panic!("{}", spill_error(e)))
+    }
+}
+
+impl<'a> Iterator for RunIter<'a> {
+    fn next(&mut self) -> Option<(&[u8], &[u8])> {
+        loop {
+            match self.step() {
+                Step::Stored => return self.lower.next(),
+                Step::Replaced => {
+                    self.lower.next();
+                    return self.take_entry();
+                }
+                Step::Inserted => return self.take_entry(),
+                Step::Deleted => {
+                    self.next_entry();
+                    self.lower.next();
+                }
+                Step::MissDeleted => {
+                    self.next_entry();
+                }
+                Step::Shadowed => {
+                    self.lower.next();
+                }
+                Step::Finished => return None,
+            }
+        }
+    }
+
+    fn peek(&mut self) -> Option<(&[u8], &[u8])> {
+        loop {
+            match self.step() {
+                Step::Stored => return self.lower.peek(),
+                Step::Replaced | Step::Inserted => {
+                    return self
+                        .entries
+                        .as_mut()
+                        .unwrap()
+                        .peeked
+                        .as_ref()
+                        .map(|(k, v)| (k.as_slice(), v.as_deref().unwrap()));
+                }
+                Step::Deleted => {
+                    self.next_entry();
+                    self.lower.next();
+                }
+                Step::MissDeleted => {
+                    self.next_entry();
+                }
+                Step::Shadowed => {
+                    self.lower.next();
+                }
+                Step::Finished => return None,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::test_utils::{TempDir, TestDB, entries_from};
+
+    #[test]
+    fn spilled_changes_match_fork() {
+        let dir = TempDir::new("spill");
+        let db = TestDB::new();
+        let mut fork = db.fork();
+        for i in 0..50_u8 {
+            fork.put("a", vec![i], vec![i]);
+        }
+        db.merge(fork.into_patch()).unwrap();
+
+        let options = SpillOptions {
+            threshold: 200,
+            dir: dir.path().to_path_buf(),
+        };
+        let mut spilling = SpillingFork::new(db.snapshot(), options);
+        let mut fork = db.fork();
+        for i in 0..300_u16 {
+            let key = vec![(i % 60) as u8, (i / 60) as u8];
+            match i % 7 {
+                0 => {
+                    spilling.remove("a", vec![key[0]]).unwrap();
+                    fork.remove("a", vec![key[0]]);
+                }
+                3 => {
+                    spilling.remove_by_prefix("a", Some(&vec![key[0]])).unwrap();
+                    fork.remove_by_prefix("a", Some(&vec![key[0]]));
+                }
+                _ => {
+                    spilling
+                        .put("a", key.clone(), i.to_be_bytes().to_vec())
+                        .unwrap();
+                    fork.put("a", key, i.to_be_bytes().to_vec());
+                }
+            }
+        }
+        spilling.put("b", vec![1], vec![1]).unwrap();
+        fork.put("b", vec![1], vec![1]);
+        assert!(spilling.runs() > 1);
+        assert!(fs::read_dir(dir.path()).unwrap().count() > 1);
+
+        for from in &[vec![], vec![7], vec![20, 1], vec![70]] {
+            assert_eq!(
+                entries_from(&spilling, "a", from),
+                entries_from(&fork, "a", from)
+            );
+        }
+        for i in 0..60_u8 {
+            for j in 0..6_u8 {
+                assert_eq!(spilling.get("a", &[i, j]), fork.get("a", &[i, j]));
+            }
+            assert_eq!(spilling.get("a", &[i]), fork.get("a", &[i]));
+        }
+        let mut iter = spilling.iter("a", &[]);
+        let first = iter.peek().map(|(k, v)| (k.to_vec(), v.to_vec()));
+        assert_eq!(first, iter.next().map(|(k, v)| (k.to_vec(), v.to_vec())));
+        drop(iter);
+
+        let expected = entries_from(&fork, "a", &[]);
+        spilling.merge_into(&db).unwrap();
+        assert_eq!(entries_from(&*db.snapshot(), "a", &[]), expected);
+        assert_eq!(db.snapshot().get("b", &[1]), Some(vec![1]));
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn failed_spill_keeps_changes() {
+        let dir = TempDir::new("spill-failed-write");
+        let db = TestDB::new();
+        let options = SpillOptions {
+            threshold: 1,
+            dir: dir.path().join("missing"),
+        };
+        let mut spilling = SpillingFork::new(db.snapshot(), options);
+        assert!(spilling.put("a", vec![1], vec![1]).is_err());
+        assert_eq!(spilling.runs(), 0);
+        assert_eq!(spilling.get("a", &[1]), Some(vec![1]));
+
+        fs::create_dir(dir.path().join("missing")).unwrap();
+        spilling.spill().unwrap();
+        assert_eq!(spilling.runs(), 1);
+        spilling.merge_into(&db).unwrap();
+        assert_eq!(db.snapshot().get("a", &[1]), Some(vec![1]));
+    }
+
+    #[test]
+    fn failed_merge_changes_nothing() {
+        let dir = TempDir::new("spill-failed");
+        let db = TestDB::new();
+        let options = SpillOptions {
+            threshold: 1,
+            dir: dir.path().to_path_buf(),
+        };
+        let mut spilling = SpillingFork::new(db.snapshot(), options);
+        spilling.put("a", vec![1], vec![1]).unwrap();
+        spilling.put("a", vec![2], vec![2]).unwrap();
+        assert_eq!(spilling.runs(), 2);
+
+        // Damage the second run.
+        let path = &spilling.runs[1].path;
+        let len = fs::metadata(path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_len(len - 1)
+            .unwrap();
+        assert!(spilling.merge_into(&db).is_err());
+        assert_eq!(db.snapshot().get("a", &[1]), None);
+    }
+}
@@ -0,0 +1,316 @@
+//! A `Database` implementation on top of [SQLite](https://sqlite.org).
+//!
+//! Available with the `sqlite` cargo feature; SQLite is compiled into the crate, so no system
+//! library is required. The whole database is a single file which can be inspected with
+//! standard SQL tools: each column family is stored in a table named `cf_<name>` with `key`
+//! and `value` blob columns.
+//!
+//! The database is opened in WAL mode, so snapshots (read transactions on separate
+//! connections) do not block the writer and vice versa. A patch is applied in a single
+//! write transaction.
+
+use std::collections::{HashSet, VecDeque};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use rusqlite::{Connection, OptionalExtension, params};
+
+use super::db::{Change, Database, Iter, Iterator, Patch, Snapshot, next_prefix};
+use super::{Error, Result};
+
+const TABLE_PREFIX: &str = "cf_";
+/// Number of rows fetched by an iterator at once.
+const PAGE_SIZE: usize = 256;
+
+/// Database stored in a single SQLite file.
+pub struct SqliteDB {
+    path: PathBuf,
+    writer: Mutex<Connection>,
+    readers: Arc<Mutex<Vec<Connection>>>,
+}
+
+/// Snapshot of the `SqliteDB` database, backed by a read transaction.
+pub struct SqliteSnapshot {
+    conn: Option<Connection>,
+    // Column families existing at the start of the transaction.
+    tables: HashSet<String>,
+    readers: Arc<Mutex<Vec<Connection>>>,
+}
+
+struct SqliteIter<'a> {
+    conn: &'a Connection,
+    table: String,
+    rows: VecDeque<(Vec<u8>, Vec<u8>)>,
+    current: Option<(Vec<u8>, Vec<u8>)>,
+    // The key to continue from, and whether it has been returned already.
+    from: Option<(Vec<u8>, bool)>,
+}
+
+fn sqlite_error(context: &str, error: rusqlite::Error) -> Error {
+    Error::io(context, io::Error::other(error))
+}
+
+/// Returns the quoted name of the table holding the column family.
+fn table(name: &str) -> String {
+    format!("\"{}{}\"", TABLE_PREFIX, name.replace('"', "\"\""))
+}
+
+impl SqliteDB {
+    /// Opens the database stored in the given file, creating the file if it does not exist.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `Io` error if SQLite fails to open the file or to switch it to WAL mode.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let writer = Connection::open(&path)
+            .and_then(|conn| {
+                conn.execute_batch("PRAGMA journal_mode = WAL; PRAGMA synchronous = NORMAL;")?;
+                Ok(conn)
+            })
+            .map_err(|e| sqlite_error("opening SQLite database", e))?;
+        Ok(Self {
+            path,
+            writer: Mutex::new(writer),
+            readers: Arc::new(Mutex::new(Vec::new())),
+        })
+    }
+
+    /// Returns the path to the database file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn sqlite_snapshot(&self) -> Result<SqliteSnapshot> {
+        let context = "starting a read transaction";
+        let conn = match self.readers.lock().unwrap().pop() {
+            Some(conn) => conn,
+            None => Connection::open(&self.path).map_err(|e| sqlite_error(context, e))?,
+        };
+        // The first read of the transaction fixes its view of the database.
+        conn.execute_batch("BEGIN")
+            .map_err(|e| sqlite_error(context, e))?;
+        let tables = conn
+            .prepare_cached("SELECT name FROM sqlite_master WHERE type = 'table'")
+            .and_then(|mut stmt| {
+                stmt.query_map([], |row| row.get::<_, String>(0))?
+                    .collect::<rusqlite::Result<Vec<_>>>()
+            })
+            .map_err(|e| sqlite_error(context, e))?
+            .into_iter()
+            .filter_map(|name| name.strip_prefix(TABLE_PREFIX).map(str::to_string))
+            .collect();
+        Ok(SqliteSnapshot {
+            conn: Some(conn),
+            tables,
+            readers: Arc::clone(&self.readers),
+        })
+    }
+
+    fn write(&self, patch: Patch, sync: bool) -> Result<()> {
+        let mut conn = self.writer.lock().unwrap();
+        let synchronous = if sync { "FULL" } else { "NORMAL" };
+        conn.execute_batch(&format!("PRAGMA synchronous = {}", synchronous))
+            .map_err(|e| sqlite_error("configuring SQLite", e))?;
+
+        let tx = conn
+            .transaction()
+            .map_err(|e| sqlite_error("starting a write transaction", e))?;
+        let apply = || -> rusqlite::Result<()> {
+            for (name, changes) in patch {
+                let table = table(&name);
+                tx.execute_batch(&format!(
+                    "CREATE TABLE IF NOT EXISTS {} \
+                     (key BLOB PRIMARY KEY, value BLOB NOT NULL) WITHOUT ROWID",
+                    table
+                ))?;
+                let put = format!(
+                    "INSERT OR REPLACE INTO {} (key, value) VALUES (?1, ?2)",
+                    table
+                );
+                let delete = format!("DELETE FROM {} WHERE key = ?1", table);
+                for (key, change) in changes {
+                    match change {
+                        Change::Put(value) => tx.prepare_cached(&put)?.execute(params![key, value]),
+                        Change::Delete => tx.prepare_cached(&delete)?.execute(params![key]),
+                        Change::DeletePrefix => match next_prefix(&key) {
+                            Some(end) => tx.execute(
+                                &format!("DELETE FROM {} WHERE key >= ?1 AND key < ?2", table),
+                                params![key, end],
+                            ),
+                            None => tx.execute(
+                                &format!("DELETE FROM {} WHERE key >= ?1", table),
+                                params![key],
+                            ),
+                        },
+                    }?;
+                }
+            }
+            Ok(())
+        };
+        apply()
+            .and_then(|()| tx.commit())
+            .map_err(|e| sqlite_error("writing a patch to SQLite", e))
+    }
+}
+
+impl Database for SqliteDB {
+    fn snapshot(&self) -> Box<dyn Snapshot> {
+        Box::new(self.sqlite_snapshot().unwrap_or_else(|e| panic!("{}", e)))
+    }
+
+    fn merge(&self, patch: Patch) -> Result<()> {
+        self.write(patch, false)
+    }
+
+    fn merge_sync(&self, patch: Patch) -> Result<()> {
+        self.write(patch, true)
+    }
+}
+
+impl SqliteSnapshot {
+    fn conn(&self) -> &Connection {
+        self.conn.as_ref().unwrap()
+    }
+}
+
+impl Snapshot for SqliteSnapshot {
+    fn get(&self, name: &str, key: &[u8]) -> Option<Vec<u8>> {
+        if !self.tables.contains(name) {
+            return None;
+        }
+        let sql = format!("SELECT value FROM {} WHERE key = ?1", table(name));
+        self.conn()
+            .prepare_cached(&sql)
+            .and_then(|mut stmt| stmt.query_row(params![key], |row| row.get(0)).optional())
+            .unwrap_or_else(|e| panic!("{}", sqlite_error("reading from SQLite", e)))
+    }
+
+    fn iter<'a>(&'a self, name: &str, from: &[u8]) -> Iter<'a> {
+        let from = if self.tables.contains(name) {
+            Some((from.to_vec(), false))
+        } else {
+            None
+        };
+        Box::new(SqliteIter {
+            conn: self.conn(),
+            table: table(name),
+            rows: VecDeque::new(),
+            current: None,
+            from,
+        })
+    }
+}
+
+impl Drop for SqliteSnapshot {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            // A connection with a broken transaction is not reused.
+            if conn.execute_batch("COMMIT").is_ok() {
+                self.readers.lock().unwrap().push(conn);
+            }
+        }
+    }
+}
+
+impl<'a> SqliteIter<'a> {
+    fn fill(&mut self) {
+        if !self.rows.is_empty() {
+            return;
+        }
+        let (from, exclusive) = match self.from.take() {
+            Some(from) => from,
+            None => return,
+        };
+        let op = if exclusive { ">" } else { ">=" };
+        let sql = format!(
+            "SELECT key, value FROM {} WHERE key {} ?1 ORDER BY key LIMIT ?2",
+            self.table, op
+        );
+        let rows = self
+            .conn
+            .prepare_cached(&sql)
+            .and_then(|mut stmt| {
+                stmt.query_map(params![from, PAGE_SIZE as i64], |row| {
+                    Ok((row.get(0)?, row.get(1)?))
+                })?
+                .collect::<rusqlite::Result<VecDeque<(Vec<u8>, Vec<u8>)>>>()
+            })
+            .unwrap_or_else(|e| panic!("{}", sqlite_error("reading from SQLite", e)));
+        if rows.len() == PAGE_SIZE {
+            self.from = rows.back().map(|(key, _)| (key.clone(), true));
+        }
+        self.rows = rows;
+    }
+}
+
+impl<'a> Iterator for SqliteIter<'a> {
+    fn next(&mut self) -> Option<(&[u8], &[u8])> {
+        self.fill();
+        self.current = self.rows.pop_front();
+        self.current
+            .as_ref()
+            .map(|(k, v)| (k.as_slice(), v.as_slice()))
+    }
+
+    fn peek(&mut self) -> Option<(&[u8], &[u8])> {
+        self.fill();
+        self.rows.front().map(|(k, v)| (k.as_slice(), v.as_slice()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::test_utils::{TempDir, entries};
+
+    #[test]
+    fn snapshots_and_prefixes() {
+        let dir = TempDir::new("sqlite");
+        let db = SqliteDB::open(dir.path().join("db.sqlite")).unwrap();
+        let mut fork = db.fork();
+        for i in 0..1000_u16 {
+            fork.put("a", i.to_be_bytes().to_vec(), vec![1]);
+        }
+        fork.put("b \"quoted\"", vec![1], vec![2]);
+        db.merge(fork.into_patch()).unwrap();
+        let before = db.snapshot();
+
+        let mut fork = db.fork();
+        fork.remove_by_prefix("a", Some(&vec![0]));
+        fork.remove_by_prefix("a", Some(&vec![3]));
+        fork.put("a", vec![0, 5], vec![3]);
+        fork.remove("b \"quoted\"", vec![1]);
+        db.merge_sync(fork.into_patch()).unwrap();
+
+        assert_eq!(entries(&*before, "a").len(), 1000);
+        assert_eq!(before.get("b \"quoted\"", &[1]), Some(vec![2]));
+        assert_eq!(entries(&*before, "missing"), vec![]);
+
+        let after = db.snapshot();
+        let after_entries = entries(&*after, "a");
+        assert_eq!(after_entries.len(), 1000 - 256 - (1000 - 768) + 1);
+        assert_eq!(after_entries[0], (vec![0, 5], vec![3]));
+        assert!(!after.contains("b \"quoted\"", &[1]));
+
+        let mut iter = after.iter("a", &[1, 255]);
+        assert_eq!(iter.peek(), Some((&[1_u8, 255][..], &[1_u8][..])));
+        assert_eq!(iter.next(), Some((&[1_u8, 255][..], &[1_u8][..])));
+        assert_eq!(iter.next(), Some((&[2_u8, 0][..], &[1_u8][..])));
+    }
+
+    #[test]
+    fn reopen() {
+        let dir = TempDir::new("sqlite_reopen");
+        let path = dir.path().join("db.sqlite");
+        {
+            let db = SqliteDB::open(&path).unwrap();
+            let mut fork = db.fork();
+            fork.put("a", vec![1], vec![1]);
+            db.merge_sync(fork.into_patch()).unwrap();
+        }
+        let db = SqliteDB::open(&path).unwrap();
+        assert_eq!(db.snapshot().get("a", &[1]), Some(vec![1]));
+    }
+}
@@ -0,0 +1,280 @@
+//! Aggregated state root over all authenticated indexes.
+//!
+//! Each authenticated index (e.g. a `ProofMap` or `ProofList`) registers its root hash in
+//! the system column family [`STATE_AGGREGATOR_NAME`] under its name. The state root is
+//! the root of a binary Merkle tree over the registered `(name, root)` pairs in ascending
+//! order of names, so a block committing to the state root commits to every index.
+//!
+//! Leaves and branches are hashed with different tags, so a leaf cannot be passed off as
+//! a branch. A node without a sibling is promoted to the next level unchanged; the number
+//! of leaves is not committed to by the root. The state root of an empty aggregator is
+//! `Hash::zero()`.
+//!
+//! Since roots are stored in a regular column family, the state root computed over a
+//! `Fork` reflects all index updates made through the fork.
+//!
+//! [`STATE_AGGREGATOR_NAME`]: constant.STATE_AGGREGATOR_NAME.html
+
+use crate::crypto::{HASH_SIZE, Hash, HashStream};
+
+use super::db::{Fork, Snapshot};
+use super::{Error, Result};
+
+/// Name of the system column family mapping names of authenticated indexes to their roots.
+pub const STATE_AGGREGATOR_NAME: &str = "__state_aggregator";
+
+const LEAF_TAG: u8 = 0;
+const BRANCH_TAG: u8 = 1;
+
+/// Position of a sibling relative to the hashed node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Side {
+    /// The sibling is on the left.
+    Left,
+    /// The sibling is on the right.
+    Right,
+}
+
+/// Proof that an index with the given root is registered in the state with a certain
+/// state root.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StateProof {
+    /// Name of the index.
+    pub name: String,
+    /// Root hash of the index.
+    pub index_root: Hash,
+    /// Siblings on the path from the leaf of the index to the state root.
+    pub path: Vec<(Side, Hash)>,
+}
+
+impl StateProof {
+    /// Returns the state root the proof leads to.
+    pub fn state_root(&self) -> Hash {
+        self.path.iter().fold(
+            leaf_hash(&self.name, &self.index_root),
+            |node, &(side, ref sibling)| match side {
+                Side::Left => branch_hash(sibling, &node),
+                Side::Right => branch_hash(&node, sibling),
+            },
+        )
+    }
+
+    /// Returns `true` if the proof leads to the given state root.
+    pub fn verify(&self, state_root: &Hash) -> bool {
+        self.state_root() == *state_root
+    }
+}
+
+fn leaf_hash(name: &str, root: &Hash) -> Hash {
+    HashStream::new()
+        .update(&[LEAF_TAG])
+        .update(&(name.len() as u32).to_be_bytes())
+        .update(name.as_bytes())
+        .update(root.as_ref())
+        .hash()
+}
+
+fn branch_hash(left: &Hash, right: &Hash) -> Hash {
+    HashStream::new()
+        .update(&[BRANCH_TAG])
+        .update(left.as_ref())
+        .update(right.as_ref())
+        .hash()
+}
+
+/// Registers the index with the given `name` or updates its root.
+pub fn update_index_root(fork: &mut Fork, name: &str, root: &Hash) {
+    fork.put(
+        STATE_AGGREGATOR_NAME,
+        name.as_bytes().to_vec(),
+        root.as_ref().to_vec(),
+    );
+}
+
+/// Removes the index with the given `name` from the state.
+pub fn remove_index_root(fork: &mut Fork, name: &str) {
+    fork.remove(STATE_AGGREGATOR_NAME, name.as_bytes().to_vec());
+}
+
+fn decode_root(name: &[u8], value: &[u8]) -> Result<Hash> {
+    if value.len() != HASH_SIZE {
+        return Err(Error::Corrupted {
+            name: STATE_AGGREGATOR_NAME.to_string(),
+            key: name.to_vec(),
+            reason: format!(
+                "expected {} bytes of a root hash, got {}",
+                HASH_SIZE,
+                value.len()
+            ),
+        });
+    }
+    Ok(Hash::new(value))
+}
+
+/// Returns the registered root of the index with the given `name`.
+///
+/// # Errors
+///
+/// Returns a `Corrupted` error if the stored root is malformed.
+pub fn index_root(view: &dyn Snapshot, name: &str) -> Result<Option<Hash>> {
+    view.get(STATE_AGGREGATOR_NAME, name.as_bytes())
+        .map(|value| decode_root(name.as_bytes(), &value))
+        .transpose()
+}
+
+/// Returns the registered `(name, root)` pairs in ascending order of names.
+///
+/// # Errors
+///
+/// Returns a `Corrupted` error if a stored entry is malformed.
+pub fn index_roots(view: &dyn Snapshot) -> Result<Vec<(String, Hash)>> {
+    let mut roots = Vec::new();
+    let mut iter = view.iter(STATE_AGGREGATOR_NAME, &[]);
+    while let Some((name, value)) = iter.next() {
+        let root = decode_root(name, value)?;
+        let name = String::from_utf8(name.to_vec()).map_err(|_| Error::Corrupted {
+            name: STATE_AGGREGATOR_NAME.to_string(),
+            key: name.to_vec(),
+            reason: "index name is not UTF-8".to_string(),
+        })?;
+        roots.push((name, root));
+    }
+    Ok(roots)
+}
+
+/// Returns the state root over all registered indexes.
+///
+/// # Errors
+///
+/// Returns a `Corrupted` error if a stored entry is malformed.
+pub fn state_root(view: &dyn Snapshot) -> Result<Hash> {
+    let mut level = index_roots(view)?
+        .iter()
+        .map(|(name, root)| leaf_hash(name, root))
+        .collect::<Vec<_>>();
+    if level.is_empty() {
+        return Ok(Hash::zero());
+    }
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+    Ok(level[0])
+}
+
+/// Returns the proof linking the root of the index with the given `name` to the state
+/// root, or `None` if the index is not registered.
+///
+/// # Errors
+///
+/// Returns a `Corrupted` error if a stored entry is malformed.
+pub fn state_proof(view: &dyn Snapshot, name: &str) -> Result<Option<StateProof>> {
+    let roots = index_roots(view)?;
+    let mut position = match roots.iter().position(|(other, _)| other == name) {
+        Some(position) => position,
+        None => return Ok(None),
+    };
+    let index_root = roots[position].1;
+    let mut level = roots
+        .iter()
+        .map(|(name, root)| leaf_hash(name, root))
+        .collect::<Vec<_>>();
+    let mut path = Vec::new();
+    while level.len() > 1 {
+        if position % 2 == 1 {
+            path.push((Side::Left, level[position - 1]));
+        } else if position + 1 < level.len() {
+            path.push((Side::Right, level[position + 1]));
+        }
+        level = next_level(&level);
+        position /= 2;
+    }
+    Ok(Some(StateProof {
+        name: name.to_string(),
+        index_root,
+        path,
+    }))
+}
+
+fn next_level(level: &[Hash]) -> Vec<Hash> {
+    level
+        .chunks(2)
+        .map(|pair| match *pair {
+            [ref left, ref right] => branch_hash(left, right),
+            [ref single] => *single,
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::hash;
+    use crate::storage::db::Database;
+    use crate::storage::test_utils::TestDB;
+
+    #[test]
+    fn state_root_follows_fork() {
+        let db = TestDB::new();
+        let mut fork = db.fork();
+        assert_eq!(state_root(&fork).unwrap(), Hash::zero());
+
+        update_index_root(&mut fork, "wallets", &hash([1]));
+        let one = state_root(&fork).unwrap();
+        assert_eq!(one, leaf_hash("wallets", &hash([1])));
+        update_index_root(&mut fork, "blocks", &hash([2]));
+        let two = state_root(&fork).unwrap();
+        assert_ne!(one, two);
+        db.merge(fork.into_patch()).unwrap();
+
+        // The order of updates does not matter.
+        let other = TestDB::new();
+        let mut fork = other.fork();
+        update_index_root(&mut fork, "blocks", &hash([2]));
+        update_index_root(&mut fork, "wallets", &hash([1]));
+        assert_eq!(state_root(&fork).unwrap(), two);
+
+        let mut fork = db.fork();
+        update_index_root(&mut fork, "wallets", &hash([3]));
+        assert_ne!(state_root(&fork).unwrap(), two);
+        assert_eq!(index_root(&fork, "wallets").unwrap(), Some(hash([3])));
+        remove_index_root(&mut fork, "wallets");
+        assert_eq!(state_root(&fork).unwrap(), leaf_hash("blocks", &hash([2])));
+        assert_eq!(state_root(&*db.snapshot()).unwrap(), two);
+    }
+
+    #[test]
+    fn proofs() {
+        let db = TestDB::new();
+        for count in 1..8_u8 {
+            let mut fork = db.fork();
+            update_index_root(&mut fork, &format!("index{}", count), &hash([count]));
+            db.merge(fork.into_patch()).unwrap();
+
+            let snapshot = db.snapshot();
+            let root = state_root(&*snapshot).unwrap();
+            for i in 1..=count {
+                let name = format!("index{}", i);
+                let proof = state_proof(&*snapshot, &name).unwrap().unwrap();
+                assert_eq!(proof.index_root, hash([i]));
+                assert!(proof.verify(&root));
+
+                let mut forged = proof.clone();
+                forged.index_root = hash([0]);
+                assert!(!forged.verify(&root));
+            }
+        }
+        assert!(state_proof(&*db.snapshot(), "missing").unwrap().is_none());
+    }
+
+    #[test]
+    fn corrupted_root() {
+        let db = TestDB::new();
+        let mut fork = db.fork();
+        fork.put(STATE_AGGREGATOR_NAME, b"wallets".to_vec(), vec![1, 2, 3]);
+        match state_root(&fork) {
+            Err(Error::Corrupted { ref key, .. }) => assert_eq!(key, b"wallets"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
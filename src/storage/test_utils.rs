@@ -0,0 +1,142 @@
+//! A minimal in-memory `Database` used by the storage unit tests.
+
+use std::collections::btree_map::{BTreeMap, Range};
+use std::collections::Bound::*;
+use std::collections::HashMap;
+use std::iter::Peekable;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::{env, fs, process};
+
+use super::db::{Change, Database, Iter, Iterator, Patch, Snapshot};
+use super::Result;
+
+type Families = HashMap<String, BTreeMap<Vec<u8>, Vec<u8>>>;
+
+/// Database keeping all column families in memory; snapshots are full copies.
+#[derive(Default, Clone)]
+pub struct TestDB {
+    map: Arc<RwLock<Families>>,
+}
+
+struct TestSnapshot {
+    map: Families,
+}
+
+struct TestIter<'a> {
+    iter: Option<Peekable<Range<'a, Vec<u8>, Vec<u8>>>>,
+}
+
+impl TestDB {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Database for TestDB {
+    fn snapshot(&self) -> Box<dyn Snapshot> {
+        Box::new(TestSnapshot {
+            map: self.map.read().unwrap().clone(),
+        })
+    }
+
+    fn merge(&self, patch: Patch) -> Result<()> {
+        let mut map = self.map.write().unwrap();
+        for (name, changes) in patch {
+            let family = map.entry(name).or_default();
+            for (key, change) in changes {
+                match change {
+                    Change::Put(value) => {
+                        family.insert(key, value);
+                    }
+                    Change::Delete => {
+                        family.remove(&key);
+                    }
+                    Change::DeletePrefix => family.retain(|k, _| !k.starts_with(&key)),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn merge_sync(&self, patch: Patch) -> Result<()> {
+        self.merge(patch)
+    }
+}
+
+impl Snapshot for TestSnapshot {
+    fn get(&self, name: &str, key: &[u8]) -> Option<Vec<u8>> {
+        self.map.get(name).and_then(|family| family.get(key).cloned())
+    }
+
+    fn iter<'a>(&'a self, name: &str, from: &[u8]) -> Iter<'a> {
+        let iter = self
+            .map
+            .get(name)
+            .map(|family| family.range::<[u8], _>((Included(from), Unbounded)).peekable());
+        Box::new(TestIter { iter })
+    }
+}
+
+impl<'a> Iterator for TestIter<'a> {
+    fn next(&mut self) -> Option<(&[u8], &[u8])> {
+        self.iter
+            .as_mut()
+            .and_then(|iter| iter.next())
+            .map(|(k, v)| (k.as_slice(), v.as_slice()))
+    }
+
+    fn peek(&mut self) -> Option<(&[u8], &[u8])> {
+        self.iter
+            .as_mut()
+            .and_then(|iter| iter.peek())
+            .map(|&(k, v)| (k.as_slice(), v.as_slice()))
+    }
+}
+
+/// Returns all entries of the column family with the given `name` in the view.
+pub fn entries(view: &dyn Snapshot, name: &str) -> Vec<(Vec<u8>, Vec<u8>)> {
+    entries_from(view, name, &[])
+}
+
+/// Returns the entries of the column family with the given `name` in the view, starting
+/// from the key `from`.
+pub fn entries_from(view: &dyn Snapshot, name: &str, from: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
+    let mut entries = Vec::new();
+    let mut iter = view.iter(name, from);
+    while let Some((k, v)) = iter.next() {
+        entries.push((k.to_vec(), v.to_vec()));
+    }
+    entries
+}
+
+/// Temporary directory removed on drop.
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let path = env::temp_dir().join(format!(
+            "{}_{}_{}",
+            name,
+            process::id(),
+            COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        Self { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
@@ -0,0 +1,273 @@
+//! Map index with entries expiring after a deadline.
+//!
+//! Each entry of a [`TtlMap`] is stored as `expiry ++ value`, where `expiry` is a
+//! `DateTime<Utc>` in its 12-byte `StorageKey` encoding. The map also maintains an
+//! expiry-ordered index in the child column family `<name>.expiry`, with the same layout
+//! as secondary indexes: each entry has the key `expiry ++ key` and the key as a value.
+//! [`purge_expired`] thus walks only the entries which are due instead of the whole map.
+//!
+//! An expired entry is not returned by [`get`] even if it has not been purged yet.
+//!
+//! [`TtlMap`]: struct.TtlMap.html
+//! [`purge_expired`]: struct.TtlMap.html#method.purge_expired
+//! [`get`]: struct.TtlMap.html#method.get
+
+use std::borrow::Cow;
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+
+use super::address::NAME_SEPARATOR;
+use super::db::{Fork, Snapshot};
+use super::keys::StorageKey;
+use super::metadata::{IndexKind, IndexMetadata, TypeTag, ensure_metadata};
+use super::typed::key_bytes;
+use super::values::StorageValue;
+use super::{Error, Result};
+
+/// Size of the encoded expiry time.
+pub(crate) const EXPIRY_SIZE: usize = 12;
+
+/// Map from keys of type `K` to values of type `V` with an expiry time for each entry.
+#[derive(Debug)]
+pub struct TtlMap<K: ?Sized, V> {
+    name: String,
+    expiry_name: String,
+    _marker: PhantomData<(Box<K>, V)>,
+}
+
+impl<K, V> TtlMap<K, V>
+where
+    K: StorageKey + TypeTag + ?Sized,
+    V: StorageValue + TypeTag,
+{
+    /// Creates a map kept in the column family with the given `name`.
+    pub fn new<S: Into<String>>(name: S) -> Self {
+        let name = name.into();
+        let expiry_name = format!("{}{}expiry", name, NAME_SEPARATOR);
+        Self {
+            name,
+            expiry_name,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns the name of the column family of the map.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the name of the column family of the expiry index.
+    pub fn expiry_name(&self) -> &str {
+        &self.expiry_name
+    }
+
+    fn entry(&self, view: &dyn Snapshot, key: &[u8]) -> Result<Option<(DateTime<Utc>, Vec<u8>)>> {
+        match view.get(&self.name, key) {
+            Some(ref value) if value.len() < EXPIRY_SIZE => Err(Error::Corrupted {
+                name: self.name.clone(),
+                key: key.to_vec(),
+                reason: "entry is shorter than its expiry time".to_string(),
+            }),
+            Some(mut value) => {
+                let expiry = decode_expiry(&self.name, key, &value[..EXPIRY_SIZE])?;
+                Ok(Some((expiry, value.split_off(EXPIRY_SIZE))))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the value of the key, or `None` if the entry does not exist or is expired
+    /// at the time `now`.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `Corrupted` error if the stored entry is malformed, and a `Decode` error
+    /// if its value cannot be decoded.
+    pub fn get(&self, view: &dyn Snapshot, key: &K, now: DateTime<Utc>) -> Result<Option<V>> {
+        let key = key_bytes(key);
+        match self.entry(view, &key)? {
+            Some((expiry, value)) if expiry > now => V::try_from_bytes(Cow::Owned(value))
+                .map(Some)
+                .map_err(|cause| Error::Decode {
+                    name: self.name.clone(),
+                    key,
+                    cause: Arc::new(cause),
+                }),
+            _ => Ok(None),
+        }
+    }
+
+    /// Returns the expiry time of the entry, or `None` if the entry does not exist.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `Corrupted` error if the stored entry is malformed.
+    pub fn expiry(&self, view: &dyn Snapshot, key: &K) -> Result<Option<DateTime<Utc>>> {
+        Ok(self.entry(view, &key_bytes(key))?.map(|(expiry, _)| expiry))
+    }
+
+    /// Inserts the entry expiring at the given time, replacing the existing entry.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `IndexTypeMismatch` error if the column family holds another index, and
+    /// a `Corrupted` error if the replaced entry is malformed.
+    pub fn put(&self, fork: &mut Fork, key: &K, value: V, expiry: DateTime<Utc>) -> Result<()> {
+        ensure_metadata(
+            fork,
+            &self.name,
+            &IndexMetadata::new::<K, V>(IndexKind::TtlMap),
+        )?;
+        let key = key_bytes(key);
+        self.remove_bytes(fork, &key)?;
+
+        let expiry = key_bytes(&expiry);
+        let mut entry = expiry.clone();
+        entry.extend_from_slice(&value.into_bytes());
+        fork.put(&self.name, key.clone(), entry);
+        let mut index_key = expiry;
+        index_key.extend_from_slice(&key);
+        fork.put(&self.expiry_name, index_key, key);
+        Ok(())
+    }
+
+    /// Removes the entry of the key, if any.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `Corrupted` error if the stored entry is malformed.
+    pub fn remove(&self, fork: &mut Fork, key: &K) -> Result<()> {
+        self.remove_bytes(fork, &key_bytes(key))
+    }
+
+    fn remove_bytes(&self, fork: &mut Fork, key: &[u8]) -> Result<()> {
+        if let Some((expiry, _)) = self.entry(fork, key)? {
+            let mut index_key = key_bytes(&expiry);
+            index_key.extend_from_slice(key);
+            fork.remove(&self.expiry_name, index_key);
+            fork.remove(&self.name, key.to_vec());
+        }
+        Ok(())
+    }
+
+    /// Removes all entries expired at the time `now` and returns their number.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `Corrupted` error if the expiry index holds a malformed key.
+    pub fn purge_expired(&self, fork: &mut Fork, now: DateTime<Utc>) -> Result<usize> {
+        let mut due = Vec::new();
+        {
+            let mut iter = fork.iter(&self.expiry_name, &[]);
+            while let Some((index_key, key)) = iter.next() {
+                if index_key.len() < EXPIRY_SIZE {
+                    return Err(Error::Corrupted {
+                        name: self.expiry_name.clone(),
+                        key: index_key.to_vec(),
+                        reason: "index key is shorter than the expiry time".to_string(),
+                    });
+                }
+                if decode_expiry(&self.expiry_name, index_key, &index_key[..EXPIRY_SIZE])? > now {
+                    break;
+                }
+                due.push((index_key.to_vec(), key.to_vec()));
+            }
+        }
+        for (index_key, key) in &due {
+            fork.remove(&self.expiry_name, index_key.clone());
+            fork.remove(&self.name, key.clone());
+        }
+        Ok(due.len())
+    }
+}
+
+fn decode_expiry(name: &str, key: &[u8], bytes: &[u8]) -> Result<DateTime<Utc>> {
+    DateTime::<Utc>::try_read(bytes).map_err(|e| Error::Corrupted {
+        name: name.to_string(),
+        key: key.to_vec(),
+        reason: format!("invalid expiry time: {}", e),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::db::Database;
+    use crate::storage::test_utils::TestDB;
+
+    fn at(secs: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(secs, 0).unwrap()
+    }
+
+    #[test]
+    fn expiry_and_purge() {
+        let db = TestDB::new();
+        let bans = TtlMap::<u64, u64>::new("peers.bans");
+        assert_eq!(bans.expiry_name(), "peers.bans.expiry");
+
+        let mut fork = db.fork();
+        bans.put(&mut fork, &1, 10, at(100)).unwrap();
+        bans.put(&mut fork, &2, 20, at(200)).unwrap();
+        bans.put(&mut fork, &3, 30, at(50)).unwrap();
+        // Prolonging the ban moves the entry in the expiry index.
+        bans.put(&mut fork, &3, 31, at(300)).unwrap();
+        db.merge(fork.into_patch()).unwrap();
+
+        let mut fork = db.fork();
+        assert_eq!(bans.get(&fork, &1, at(99)).unwrap(), Some(10));
+        assert_eq!(bans.get(&fork, &1, at(100)).unwrap(), None);
+        assert_eq!(bans.expiry(&fork, &3).unwrap(), Some(at(300)));
+        assert_eq!(bans.purge_expired(&mut fork, at(150)).unwrap(), 1);
+        assert_eq!(bans.expiry(&fork, &1).unwrap(), None);
+        assert_eq!(bans.get(&fork, &2, at(150)).unwrap(), Some(20));
+        assert_eq!(bans.purge_expired(&mut fork, at(150)).unwrap(), 0);
+
+        bans.remove(&mut fork, &2).unwrap();
+        assert_eq!(bans.purge_expired(&mut fork, at(1000)).unwrap(), 1);
+        db.merge(fork.into_patch()).unwrap();
+
+        let snapshot = db.snapshot();
+        assert_eq!(snapshot.iter("peers.bans", &[]).next(), None);
+        assert_eq!(snapshot.iter("peers.bans.expiry", &[]).next(), None);
+    }
+
+    #[test]
+    fn malformed_entries() {
+        let db = TestDB::new();
+        let bans = TtlMap::<u64, u64>::new("bans");
+        let mut fork = db.fork();
+        fork.put("bans", key_bytes(&1_u64), vec![0; 4]);
+        let mut entry = key_bytes(&at(100));
+        entry.push(1);
+        fork.put("bans", key_bytes(&2_u64), entry);
+        fork.put("bans", key_bytes(&3_u64), vec![0xff; EXPIRY_SIZE + 1]);
+
+        match bans.get(&fork, &1, at(0)) {
+            Err(Error::Corrupted { ref name, .. }) => assert_eq!(name, "bans"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(bans.expiry(&fork, &1).is_err());
+        assert!(bans.remove(&mut fork, &1).is_err());
+        match bans.get(&fork, &2, at(0)) {
+            Err(Error::Decode { ref key, .. }) => assert_eq!(*key, key_bytes(&2_u64)),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(bans.expiry(&fork, &2).unwrap(), Some(at(100)));
+        match bans.expiry(&fork, &3) {
+            Err(Error::Corrupted { ref key, .. }) => assert_eq!(*key, key_bytes(&3_u64)),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        fork.put(
+            bans.expiry_name(),
+            vec![0xff; EXPIRY_SIZE + 8],
+            key_bytes(&3_u64),
+        );
+        match bans.purge_expired(&mut fork, at(0)) {
+            Err(Error::Corrupted { ref name, .. }) => assert_eq!(name, bans.expiry_name()),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,267 @@
+//! Typed helpers for reading `StorageKey`/`StorageValue` data from storage views.
+//!
+//! A storage view is anything that can be referenced as a [`Snapshot`], such as
+//! `Box<dyn Snapshot>` or a [`Fork`].
+//!
+//! [`Snapshot`]: ../db/trait.Snapshot.html
+//! [`Fork`]: ../db/struct.Fork.html
+
+use std::borrow::Cow;
+use std::iter::FusedIterator;
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use super::db::{Iter, Snapshot};
+use super::keys::StorageKey;
+use super::values::StorageValue;
+use super::{Error, Result};
+
+/// Serializes the key into a vector of bytes.
+pub fn key_bytes<K: StorageKey + ?Sized>(key: &K) -> Vec<u8> {
+    let mut buffer = vec![0_u8; key.size()];
+    key.write(&mut buffer);
+    buffer
+}
+
+/// Returns a value corresponding to the key, or `None` if it does not exist.
+pub fn get<T, K, V>(view: &T, name: &str, key: &K) -> Option<V>
+where
+    T: AsRef<dyn Snapshot>,
+    K: StorageKey + ?Sized,
+    V: StorageValue,
+{
+    view.as_ref()
+        .get(name, &key_bytes(key))
+        .map(|value| V::from_bytes(Cow::Owned(value)))
+}
+
+/// Returns a value corresponding to the key, or `None` if it does not exist.
+///
+/// Unlike [`get`](fn.get.html), returns a `Decode` error if the stored value is malformed.
+pub fn try_get<T, K, V>(view: &T, name: &str, key: &K) -> Result<Option<V>>
+where
+    T: AsRef<dyn Snapshot>,
+    K: StorageKey + ?Sized,
+    V: StorageValue,
+{
+    let key = key_bytes(key);
+    match view.as_ref().get(name, &key) {
+        Some(value) => V::try_from_bytes(Cow::Owned(value))
+            .map(Some)
+            .map_err(|cause| Error::Decode {
+                name: name.to_string(),
+                key,
+                cause: Arc::new(cause),
+            }),
+        None => Ok(None),
+    }
+}
+
+/// Returns values corresponding to the keys, in the same order as the keys, using a single
+/// batched [`multi_get`] call.
+///
+/// [`multi_get`]: ../db/trait.Snapshot.html#method.multi_get
+pub fn multi_get<T, K, V>(view: &T, name: &str, keys: &[K]) -> Vec<Option<V>>
+where
+    T: AsRef<dyn Snapshot>,
+    K: StorageKey,
+    V: StorageValue,
+{
+    let keys = keys.iter().map(key_bytes).collect::<Vec<_>>();
+    let keys = keys.iter().map(Vec::as_slice).collect::<Vec<_>>();
+    view.as_ref()
+        .multi_get(name, &keys)
+        .into_iter()
+        .map(|value| value.map(|value| V::from_bytes(Cow::Owned(value))))
+        .collect()
+}
+
+/// Iterator decoding the entries of a raw storage iterator into `(K::Owned, V)` pairs.
+///
+/// The iterator is lazy and implements `std::iter::Iterator`, so it stops reading from the
+/// storage as soon as the consumer does (e.g. after `take_while` rejects an entry). If a
+/// prefix is set, the iteration ends at the first key outside of it.
+pub struct TypedIter<'a, K: ?Sized, V> {
+    inner: Iter<'a>,
+    prefix: Vec<u8>,
+    done: bool,
+    _marker: PhantomData<(Box<K>, V)>,
+}
+
+impl<'a, K, V> TypedIter<'a, K, V>
+where
+    K: StorageKey + ?Sized,
+    V: StorageValue,
+{
+    /// Wraps the raw iterator, e.g. the one returned by `IndexAddress::iter`.
+    pub fn new(inner: Iter<'a>) -> Self {
+        Self::with_prefix(inner, Vec::new())
+    }
+
+    /// Wraps the raw iterator, ending the iteration at the first key not starting with
+    /// the `prefix`.
+    pub fn with_prefix(inner: Iter<'a>, prefix: Vec<u8>) -> Self {
+        Self {
+            inner,
+            prefix,
+            done: false,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<K, V> Iterator for TypedIter<'_, K, V>
+where
+    K: StorageKey + ?Sized,
+    V: StorageValue,
+{
+    type Item = (K::Owned, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.inner.next() {
+            Some((key, value)) if key.starts_with(&self.prefix) => {
+                Some((K::read(key), V::from_bytes(Cow::Borrowed(value))))
+            }
+            _ => {
+                self.done = true;
+                None
+            }
+        }
+    }
+}
+
+impl<K, V> FusedIterator for TypedIter<'_, K, V>
+where
+    K: StorageKey + ?Sized,
+    V: StorageValue,
+{
+}
+
+/// Returns an iterator over all entries of the column family in ascending order of keys.
+pub fn iter<'a, T, K, V>(view: &'a T, name: &str) -> TypedIter<'a, K, V>
+where
+    T: AsRef<dyn Snapshot>,
+    K: StorageKey + ?Sized,
+    V: StorageValue,
+{
+    TypedIter::new(view.as_ref().iter(name, &[]))
+}
+
+/// Returns an iterator over the entries of the column family in ascending order of keys,
+/// starting from the key `from`.
+pub fn iter_from<'a, T, K, V>(view: &'a T, name: &str, from: &K) -> TypedIter<'a, K, V>
+where
+    T: AsRef<dyn Snapshot>,
+    K: StorageKey + ?Sized,
+    V: StorageValue,
+{
+    TypedIter::new(view.as_ref().iter(name, &key_bytes(from)))
+}
+
+/// Returns an iterator over the entries of the column family whose serialized keys start
+/// with the serialized `prefix`. Keys are decoded in full, prefix included.
+pub fn iter_prefix<'a, T, P, K, V>(view: &'a T, name: &str, prefix: &P) -> TypedIter<'a, K, V>
+where
+    T: AsRef<dyn Snapshot>,
+    P: StorageKey + ?Sized,
+    K: StorageKey + ?Sized,
+    V: StorageValue,
+{
+    let prefix = key_bytes(prefix);
+    TypedIter::with_prefix(view.as_ref().iter(name, &prefix), prefix)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::db::Database;
+    use crate::storage::test_utils::TestDB;
+
+    #[test]
+    fn typed_multi_get() {
+        let db = TestDB::new();
+        let mut fork = db.fork();
+        fork.put("balances", key_bytes(&1_u64), 10_u64.into_bytes());
+        fork.put("balances", key_bytes(&2_u64), 20_u64.into_bytes());
+        db.merge(fork.into_patch()).unwrap();
+
+        let mut fork = db.fork();
+        fork.put("balances", key_bytes(&3_u64), 30_u64.into_bytes());
+        fork.remove("balances", key_bytes(&1_u64));
+
+        assert_eq!(get::<_, _, u64>(&fork, "balances", &2_u64), Some(20));
+        assert_eq!(
+            multi_get::<_, _, u64>(&fork, "balances", &[1_u64, 2, 3, 4]),
+            vec![None, Some(20), Some(30), None]
+        );
+        assert_eq!(
+            multi_get::<_, _, u64>(&db.snapshot(), "balances", &[1_u64, 3]),
+            vec![Some(10), None]
+        );
+    }
+
+    #[test]
+    fn decode_error() {
+        let db = TestDB::new();
+        let mut fork = db.fork();
+        fork.put("balances", key_bytes(&1_u64), 10_u64.into_bytes());
+        fork.put("balances", key_bytes(&2_u64), b"ten".to_vec());
+
+        assert_eq!(
+            try_get::<_, _, u64>(&fork, "balances", &1_u64).unwrap(),
+            Some(10)
+        );
+        assert_eq!(
+            try_get::<_, _, u64>(&fork, "balances", &3_u64).unwrap(),
+            None
+        );
+        match try_get::<_, _, u64>(&fork, "balances", &2_u64) {
+            Err(Error::Decode {
+                ref name, ref key, ..
+            }) => {
+                assert_eq!(name, "balances");
+                assert_eq!(key, &key_bytes(&2_u64));
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn typed_iterators() {
+        let db = TestDB::new();
+        let mut fork = db.fork();
+        // Assets are keyed by `owner << 32 | id`, so the owner is a 4-byte key prefix.
+        for &(owner, id) in &[(1_u64, 1_u64), (1, 2), (2, 1), (2, 5), (3, 1)] {
+            fork.put(
+                "assets",
+                key_bytes(&(owner << 32 | id)),
+                (id * 10).into_bytes(),
+            );
+        }
+        fork.put("balances", key_bytes(&1_u64), 10_u64.into_bytes());
+        fork.put("balances", key_bytes(&2_u64), 20_u64.into_bytes());
+        fork.put("balances", key_bytes(&3_u64), 30_u64.into_bytes());
+        db.merge(fork.into_patch()).unwrap();
+        let snapshot = db.snapshot();
+
+        let all = iter::<_, u64, u64>(&snapshot, "balances").collect::<Vec<_>>();
+        assert_eq!(all, vec![(1, 10), (2, 20), (3, 30)]);
+        let from = iter_from::<_, u64, u64>(&snapshot, "balances", &2).map(|(k, _)| k);
+        assert_eq!(from.collect::<Vec<_>>(), vec![2, 3]);
+        let small = iter::<_, u64, u64>(&snapshot, "balances")
+            .take_while(|&(_, v)| v < 30)
+            .count();
+        assert_eq!(small, 2);
+
+        let assets = iter_prefix::<_, u32, u64, u64>(&snapshot, "assets", &2_u32)
+            .map(|(k, v)| (k as u32, v))
+            .collect::<Vec<_>>();
+        assert_eq!(assets, vec![(1, 10), (5, 50)]);
+        let mut none = iter_prefix::<_, u32, u64, u64>(&snapshot, "assets", &4_u32);
+        assert_eq!(none.next(), None);
+        assert_eq!(none.next(), None);
+    }
+}
@@ -30,6 +30,15 @@ pub trait StorageValue: UniqueHash + Sized {
 
     /// Deserialize a value from bytes.
     fn from_bytes(value: Cow<[u8]>) -> Self;
+
+    /// Deserialize a value from bytes, returning an error instead of panicking
+    /// if the bytes are malformed.
+    ///
+    /// Default implementation calls `from_bytes`, so it still panics on malformed input
+    /// unless overridden.
+    fn try_from_bytes(value: Cow<[u8]>) -> Result<Self, failure::Error> {
+        Ok(Self::from_bytes(value))
+    }
 }
 
 #[macro_export]
@@ -42,6 +51,9 @@ macro_rules! implement_storagevalue_traits {
             fn from_bytes(value: Cow<[u8]>) -> Self {
                 serde_json::from_slice(&value).unwrap()
             }
+            fn try_from_bytes(value: Cow<[u8]>) -> Result<Self, failure::Error> {
+                Ok(serde_json::from_slice(&value)?)
+            }
         }
     };
 }
@@ -68,6 +80,10 @@ impl StorageValue for () {
     fn from_bytes(value: Cow<[u8]>) -> Self {
         serde_json::from_slice(&value).unwrap()
     }
+
+    fn try_from_bytes(value: Cow<[u8]>) -> Result<Self, failure::Error> {
+        Ok(serde_json::from_slice(&value)?)
+    }
 }
 
 impl StorageValue for Zero {
@@ -78,6 +94,11 @@ impl StorageValue for Zero {
     fn from_bytes(_: Cow<[u8]>) -> Self {
         Zero
     }
+
+    fn try_from_bytes(value: Cow<[u8]>) -> Result<Self, failure::Error> {
+        ensure!(value.is_empty(), "expected 0 bytes, got {}", value.len());
+        Ok(Zero)
+    }
 }
 
 // Hash is very special
@@ -89,6 +110,10 @@ impl StorageValue for Hash {
     fn from_bytes(value: Cow<[u8]>) -> Self {
         serde_json::from_slice(&value).unwrap()
     }
+
+    fn try_from_bytes(value: Cow<[u8]>) -> Result<Self, failure::Error> {
+        Ok(serde_json::from_slice(&value)?)
+    }
 }
 
 impl StorageValue for PublicKey {
@@ -99,6 +124,10 @@ impl StorageValue for PublicKey {
     fn from_bytes(value: Cow<[u8]>) -> Self {
         serde_json::from_slice(&value).unwrap()
     }
+
+    fn try_from_bytes(value: Cow<[u8]>) -> Result<Self, failure::Error> {
+        Ok(serde_json::from_slice(&value)?)
+    }
 }
 
 //impl StorageValue for RawMessage {
@@ -119,6 +148,10 @@ impl StorageValue for Vec<u8> {
     fn from_bytes(value: Cow<[u8]>) -> Self {
         serde_json::from_slice(&value).unwrap()
     }
+
+    fn try_from_bytes(value: Cow<[u8]>) -> Result<Self, failure::Error> {
+        Ok(serde_json::from_slice(&value)?)
+    }
 }
 
 /// Uses little-endian encoding.
@@ -130,6 +163,10 @@ impl StorageValue for DateTime<Utc> {
     fn from_bytes(value: Cow<[u8]>) -> Self {
         serde_json::from_slice(&value).unwrap()
     }
+
+    fn try_from_bytes(value: Cow<[u8]>) -> Result<Self, failure::Error> {
+        Ok(serde_json::from_slice(&value)?)
+    }
 }
 
 #[cfg(test)]
@@ -141,4 +178,12 @@ mod tests {
         let zero1 = Zero::from_bytes(Cow::from(vec![]));
         assert_eq!(0, zero1.into_bytes().len());
     }
+
+    #[test]
+    fn try_from_bytes() {
+        assert_eq!(u64::try_from_bytes(Cow::from(&b"42"[..])).unwrap(), 42);
+        assert!(u64::try_from_bytes(Cow::from(&b"forty two"[..])).is_err());
+        assert!(String::try_from_bytes(Cow::from(&[0xff_u8][..])).is_err());
+        assert!(Zero::try_from_bytes(Cow::from(&[0_u8][..])).is_err());
+    }
 }
@@ -0,0 +1,210 @@
+//! Binary encoding of patches and checksummed records used by durable backends.
+//!
+//! A record is framed as `length (u32) ++ crc32 (u32) ++ payload`, both integers in
+//! big-endian order. A record which is cut short or fails its checksum marks the end of
+//! the valid part of a log: everything after it was never acknowledged as durable.
+//!
+//! A patch is encoded as a sequence of column families, each consisting of the
+//! length-prefixed name, the number of changes and the changes themselves. A change is
+//! a tag byte (`0` put, `1` delete, `2` delete by prefix), the length-prefixed key and,
+//! for puts, the length-prefixed value. Encoded patches may be concatenated: a column
+//! family occurring several times has its later changes applied on top of the earlier ones.
+
+use std::io::{self, Read, Write};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use super::db::{Change, Changes, Patch};
+
+const TAG_PUT: u8 = 0;
+const TAG_DELETE: u8 = 1;
+const TAG_DELETE_PREFIX: u8 = 2;
+
+/// Size of the record header.
+pub const HEADER_SIZE: u64 = 8;
+
+fn invalid_data<T: Into<String>>(message: T) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+/// Writes a length-prefixed byte string.
+pub fn write_bytes<W: Write>(writer: &mut W, bytes: &[u8]) -> io::Result<()> {
+    writer.write_u32::<BigEndian>(bytes.len() as u32)?;
+    writer.write_all(bytes)
+}
+
+/// Reads a length-prefixed byte string.
+pub fn read_bytes<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let len = reader.read_u32::<BigEndian>()? as usize;
+    let mut bytes = Vec::new();
+    reader.take(len as u64).read_to_end(&mut bytes)?;
+    if bytes.len() != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(bytes)
+}
+
+/// Returns the binary representation of the patch.
+pub fn encode_patch(patch: &Patch) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for (name, changes) in patch.iter() {
+        write_bytes(&mut buf, name.as_bytes()).unwrap();
+        buf.write_u32::<BigEndian>(changes.len() as u32).unwrap();
+        for (key, change) in changes.iter() {
+            let tag = match *change {
+                Change::Put(..) => TAG_PUT,
+                Change::Delete => TAG_DELETE,
+                Change::DeletePrefix => TAG_DELETE_PREFIX,
+            };
+            buf.push(tag);
+            write_bytes(&mut buf, key).unwrap();
+            if let Change::Put(ref value) = *change {
+                write_bytes(&mut buf, value).unwrap();
+            }
+        }
+    }
+    buf
+}
+
+/// Restores the patch from its binary representation, combining concatenated patches
+/// into one.
+pub fn decode_patch(mut bytes: &[u8]) -> io::Result<Patch> {
+    let mut patch = Patch::new();
+    while !bytes.is_empty() {
+        let name = String::from_utf8(read_bytes(&mut bytes)?)
+            .map_err(|_| invalid_data("column family name is not UTF-8"))?;
+        let count = bytes.read_u32::<BigEndian>()?;
+        let mut changes = Changes::new();
+        for _ in 0..count {
+            let tag = bytes.read_u8()?;
+            let key = read_bytes(&mut bytes)?;
+            let change = match tag {
+                TAG_PUT => Change::Put(read_bytes(&mut bytes)?),
+                TAG_DELETE => Change::Delete,
+                TAG_DELETE_PREFIX => Change::DeletePrefix,
+                tag => return Err(invalid_data(format!("unknown change tag {}", tag))),
+            };
+            changes.insert(key, change);
+        }
+        match patch.changes_mut(&name) {
+            Some(existing) => {
+                for (key, change) in changes {
+                    existing.insert(key, change);
+                }
+            }
+            None => patch.insert_changes(name, changes),
+        }
+    }
+    Ok(patch)
+}
+
+/// Writes the payload as a checksummed record.
+pub fn write_record<W: Write>(writer: &mut W, payload: &[u8]) -> io::Result<()> {
+    writer.write_u32::<BigEndian>(payload.len() as u32)?;
+    writer.write_u32::<BigEndian>(crc32fast::hash(payload))?;
+    writer.write_all(payload)
+}
+
+/// Reads the next record.
+///
+/// Returns `Ok(None)` at the end of the stream. A record cut short results in an
+/// `UnexpectedEof` error; a checksum mismatch results in an `InvalidData` error.
+pub fn read_record<R: Read>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut header = [0_u8; HEADER_SIZE as usize];
+    let mut read = 0;
+    while read < header.len() {
+        match reader.read(&mut header[read..]) {
+            Ok(0) if read == 0 => return Ok(None),
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => read += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    let mut header = &header[..];
+    let len = header.read_u32::<BigEndian>()?;
+    let checksum = header.read_u32::<BigEndian>()?;
+    let mut payload = Vec::new();
+    reader.take(u64::from(len)).read_to_end(&mut payload)?;
+    if payload.len() != len as usize {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    if crc32fast::hash(&payload) != checksum {
+        return Err(invalid_data("record checksum mismatch"));
+    }
+    Ok(Some(payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::db::Database;
+    use crate::storage::dump::PatchPrinter;
+    use crate::storage::test_utils::TestDB;
+
+    #[test]
+    fn patch_roundtrip() {
+        let db = TestDB::new();
+        let mut fork = db.fork();
+        fork.put("a", vec![1], vec![1, 2, 3]);
+        fork.put("a", vec![2], vec![]);
+        fork.remove("b", vec![1]);
+        fork.remove_by_prefix("c", Some(&vec![5]));
+        fork.put("c", vec![5, 1], vec![4]);
+        let patch = fork.into_patch();
+
+        let bytes = encode_patch(&patch);
+        let decoded = decode_patch(&bytes).unwrap();
+        let printer = PatchPrinter::new();
+        assert_eq!(
+            printer.display(&decoded).to_string(),
+            printer.display(&patch).to_string()
+        );
+        assert_eq!(decoded.len(), 5);
+        assert!(decoded.changes("c").unwrap().is_removed(&[5, 2]));
+        assert!(decode_patch(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn concatenated_patches() {
+        let db = TestDB::new();
+        let mut fork = db.fork();
+        fork.put("a", vec![1], vec![1]);
+        fork.put("a", vec![2, 1], vec![2]);
+        let mut bytes = encode_patch(&fork.into_patch());
+        let mut fork = db.fork();
+        fork.remove_by_prefix("a", Some(&vec![2]));
+        fork.put("b", vec![1], vec![3]);
+        bytes.extend(encode_patch(&fork.into_patch()));
+
+        let decoded = decode_patch(&bytes).unwrap();
+        let changes = decoded.changes("a").unwrap();
+        assert_eq!(changes.lookup(&[1]), Some(Some(&vec![1])));
+        assert_eq!(changes.lookup(&[2, 1]), Some(None));
+        assert!(decoded.changes("b").is_some());
+    }
+
+    #[test]
+    fn torn_records() {
+        let mut log = Vec::new();
+        write_record(&mut log, b"first").unwrap();
+        write_record(&mut log, b"second").unwrap();
+
+        let mut reader = &log[..];
+        assert_eq!(read_record(&mut reader).unwrap(), Some(b"first".to_vec()));
+        assert_eq!(read_record(&mut reader).unwrap(), Some(b"second".to_vec()));
+        assert_eq!(read_record(&mut reader).unwrap(), None);
+
+        let mut reader = &log[..log.len() - 2];
+        read_record(&mut reader).unwrap();
+        let err = read_record(&mut reader).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        let last = log.len() - 1;
+        log[last] ^= 0xff;
+        let mut reader = &log[..];
+        read_record(&mut reader).unwrap();
+        let err = read_record(&mut reader).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}